@@ -0,0 +1,397 @@
+//! Churn statistics obtained by tracking relays by fingerprint across a
+//! series of consensuses
+
+use super::MyConsensus;
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tordoc::Fingerprint;
+
+use torscaler::highlevel::stats::{mean, quantile};
+use torscaler::highlevel::{dynamic_flags, ChurnModel};
+
+/// Quantiles reported for the relay lifetimes
+const LIFETIME_QUANTILES: [f64; 5] = [0.1, 0.25, 0.5, 0.75, 0.9];
+
+/// State of a relay in the most recent consensus
+struct TrackedRelay {
+    /// Start of the current presence spell
+    since: DateTime<Utc>,
+    /// Whether the spell started with the first consensus of the series
+    censored: bool,
+    flags: Vec<&'static str>,
+    bandwidth_weight: u64,
+}
+
+/// Number of events and the average hourly rate they occurred at, over all
+/// steps of the series
+#[derive(Default, Clone, Copy)]
+struct RateCounter {
+    events: u64,
+    /// Sum of the hourly probabilities, weighted by the number of relays
+    weighted_rates: f64,
+    relays: u64,
+}
+
+impl RateCounter {
+    /// Record that `events` of `relays` relays experienced the event during
+    /// a step of `hours`
+    fn add(&mut self, events: u64, relays: u64, hours: f64) {
+        if relays == 0 {
+            return;
+        }
+        let step_prob = events as f64 / relays as f64;
+        self.add_hourly(events, relays, 1.0 - (1.0 - step_prob).powf(1.0 / hours));
+    }
+
+    /// Record `events` relative to `relays` relays that are not a subset of
+    /// them (e.g. joining relays), during a step of `hours`
+    fn add_relative(&mut self, events: u64, relays: u64, hours: f64) {
+        if relays == 0 {
+            return;
+        }
+        self.add_hourly(events, relays, events as f64 / relays as f64 / hours);
+    }
+
+    fn add_hourly(&mut self, events: u64, relays: u64, hourly: f64) {
+        self.events += events;
+        self.weighted_rates += hourly * relays as f64;
+        self.relays += relays;
+    }
+
+    /// Hourly rate of the event
+    fn hourly_rate(&self) -> f64 {
+        if self.relays == 0 {
+            0.0
+        } else {
+            self.weighted_rates / self.relays as f64
+        }
+    }
+}
+
+/// Running sums for correlations and the noise of the bandwidth
+#[derive(Default)]
+struct BandwidthCounter {
+    n: u64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_yy: f64,
+    sum_xy: f64,
+    /// Sum of the squared hourly log changes
+    sum_change_sq: f64,
+}
+
+impl BandwidthCounter {
+    fn add(&mut self, before: u64, after: u64, hours: f64) {
+        if before == 0 || after == 0 {
+            return;
+        }
+        let x = (before as f64).ln();
+        let y = (after as f64).ln();
+        self.n += 1;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xx += x * x;
+        self.sum_yy += y * y;
+        self.sum_xy += x * y;
+        self.sum_change_sq += (y - x).powi(2) / hours;
+    }
+
+    /// Pearson correlation of the logarithms of consecutive bandwidth weights
+    fn autocorrelation(&self) -> Option<f64> {
+        if self.n < 2 {
+            return None;
+        }
+        let n = self.n as f64;
+        let cov = self.sum_xy - self.sum_x * self.sum_y / n;
+        let var_x = self.sum_xx - self.sum_x * self.sum_x / n;
+        let var_y = self.sum_yy - self.sum_y * self.sum_y / n;
+        if var_x <= 0.0 || var_y <= 0.0 {
+            return None;
+        }
+        Some(cov / (var_x * var_y).sqrt())
+    }
+
+    /// Standard deviation of the hourly change of the logarithm of the
+    /// bandwidth weight
+    fn hourly_noise(&self) -> f64 {
+        if self.n == 0 {
+            0.0
+        } else {
+            (self.sum_change_sq / self.n as f64).sqrt()
+        }
+    }
+}
+
+/// Tracks relays across consecutive consensuses
+#[derive(Default)]
+pub(crate) struct ChurnTracker {
+    first_valid_after: Option<DateTime<Utc>>,
+    last_valid_after: Option<DateTime<Utc>>,
+    num_consensuses: usize,
+    relays: HashMap<Fingerprint, TrackedRelay>,
+    joins: RateCounter,
+    leaves: RateCounter,
+    flag_gains: BTreeMap<&'static str, RateCounter>,
+    flag_losses: BTreeMap<&'static str, RateCounter>,
+    bandwidth: BandwidthCounter,
+    /// Lengths (in hours) of presence spells that started and ended within
+    /// the series, from the first to the last consensus listing the relay
+    lifetimes: Vec<f64>,
+    /// Number of spells that started before or ended after the series
+    censored_lifetimes: usize,
+}
+
+impl ChurnTracker {
+    pub(crate) fn new() -> ChurnTracker {
+        Default::default()
+    }
+
+    /// Add the next consensus of the series. Consensuses need to be added in
+    /// chronological order, so a consensus that is not newer than the
+    /// previous one is rejected.
+    pub(crate) fn observe(&mut self, cons: &MyConsensus) -> Result<(), String> {
+        let valid_after = cons.valid_after;
+        let previous = self.last_valid_after;
+        if let Some(previous) = previous {
+            if valid_after <= previous {
+                return Err(format!(
+                    "valid-after time {} is not after the one of the previous consensus ({})",
+                    valid_after, previous
+                ));
+            }
+        }
+        self.first_valid_after.get_or_insert(valid_after);
+        self.last_valid_after = Some(valid_after);
+        self.num_consensuses += 1;
+
+        let dynamic_flags: Vec<&'static str> =
+            dynamic_flags().iter().map(<&'static str>::from).collect();
+
+        let mut current = HashMap::with_capacity(cons.relays.len());
+        let mut joined = 0;
+        // number of changes and of relays that could have changed, by flag
+        let mut gains: BTreeMap<&'static str, (u64, u64)> = BTreeMap::new();
+        let mut losses: BTreeMap<&'static str, (u64, u64)> = BTreeMap::new();
+        for relay in cons.relays.iter() {
+            let flags: Vec<&'static str> = relay
+                .flags
+                .iter()
+                .map(<&'static str>::from)
+                .filter(|f| dynamic_flags.contains(f))
+                .collect();
+
+            let tracked = match self.relays.remove(&relay.fingerprint) {
+                Some(before) => {
+                    for flag in dynamic_flags.iter() {
+                        let had = before.flags.contains(flag);
+                        let has = flags.contains(flag);
+                        let counts = if had {
+                            losses.entry(flag).or_default()
+                        } else {
+                            gains.entry(flag).or_default()
+                        };
+                        counts.0 += (had != has) as u64;
+                        counts.1 += 1;
+                    }
+                    self.bandwidth.add(
+                        before.bandwidth_weight,
+                        relay.bandwidth_weight,
+                        hours_between(previous.unwrap(), valid_after),
+                    );
+
+                    TrackedRelay {
+                        flags,
+                        bandwidth_weight: relay.bandwidth_weight,
+                        ..before
+                    }
+                }
+                None => {
+                    if previous.is_some() {
+                        joined += 1;
+                    }
+                    TrackedRelay {
+                        since: valid_after,
+                        censored: previous.is_none(),
+                        flags,
+                        bandwidth_weight: relay.bandwidth_weight,
+                    }
+                }
+            };
+            current.insert(relay.fingerprint.clone(), tracked);
+        }
+
+        // the remaining relays left the network
+        if let Some(previous) = previous {
+            let hours = hours_between(previous, valid_after);
+            let num_previous = (self.relays.len() + current.len() - joined) as u64;
+            self.leaves
+                .add(self.relays.len() as u64, num_previous, hours);
+            // joins are relative to the size of the network before
+            self.joins.add_relative(joined as u64, num_previous, hours);
+            for (flag, (events, relays)) in gains {
+                self.flag_gains
+                    .entry(flag)
+                    .or_default()
+                    .add(events, relays, hours);
+            }
+            for (flag, (events, relays)) in losses {
+                self.flag_losses
+                    .entry(flag)
+                    .or_default()
+                    .add(events, relays, hours);
+            }
+
+            // the relays were last listed in the previous consensus
+            for (_, relay) in self.relays.drain() {
+                if relay.censored {
+                    self.censored_lifetimes += 1;
+                } else {
+                    self.lifetimes.push(hours_between(relay.since, previous));
+                }
+            }
+        }
+
+        self.relays = current;
+        Ok(())
+    }
+
+    /// Compute the statistics over all consensuses added so far
+    pub(crate) fn finish(mut self) -> ChurnStats {
+        // relays still present at the end of the series have an unknown
+        // lifetime
+        self.censored_lifetimes += self.relays.len();
+        self.lifetimes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let hours = match (self.first_valid_after, self.last_valid_after) {
+            (Some(first), Some(last)) => hours_between(first, last),
+            _ => 0.0,
+        };
+
+        let rates = |counters: &BTreeMap<&'static str, RateCounter>| -> BTreeMap<String, f64> {
+            counters
+                .iter()
+                .map(|(flag, counter)| (flag.to_string(), counter.hourly_rate()))
+                .collect()
+        };
+
+        let model = ChurnModel {
+            join_rate: self.joins.hourly_rate(),
+            leave_rate: self.leaves.hourly_rate(),
+            flag_gain_rates: rates(&self.flag_gains),
+            flag_loss_rates: rates(&self.flag_losses),
+            bandwidth_noise: self.bandwidth.hourly_noise(),
+        };
+
+        ChurnStats {
+            first_valid_after: self.first_valid_after.map(|t| t.timestamp() as u64),
+            last_valid_after: self.last_valid_after.map(|t| t.timestamp() as u64),
+            num_consensuses: self.num_consensuses,
+            hours,
+            joined_relays: self.joins.events,
+            left_relays: self.leaves.events,
+            lifetime_hours: LifetimeStats {
+                completed: self.lifetimes.len(),
+                censored: self.censored_lifetimes,
+                mean: mean(&self.lifetimes),
+                quantiles: LIFETIME_QUANTILES
+                    .iter()
+                    .filter_map(|q| Some((q.to_string(), quantile(&self.lifetimes, *q)?)))
+                    .collect(),
+            },
+            bandwidth_autocorrelation: self.bandwidth.autocorrelation(),
+            model,
+        }
+    }
+}
+
+/// Distribution of the time relays stay in the network
+#[derive(Serialize)]
+pub(crate) struct LifetimeStats {
+    /// Number of presence spells that started and ended within the series
+    completed: usize,
+    /// Number of presence spells that started before the series or did not
+    /// end before its end, and are not part of the distribution
+    censored: usize,
+    mean: Option<f64>,
+    quantiles: BTreeMap<String, f64>,
+}
+
+/// Empirical dynamics of the relays in a series of consensuses
+#[derive(Serialize)]
+pub(crate) struct ChurnStats {
+    /// Unix timestamp of the first consensus
+    first_valid_after: Option<u64>,
+    /// Unix timestamp of the last consensus
+    last_valid_after: Option<u64>,
+    num_consensuses: usize,
+    /// Time covered by the series
+    hours: f64,
+    joined_relays: u64,
+    left_relays: u64,
+    lifetime_hours: LifetimeStats,
+    /// Correlation of the logarithms of a relay's bandwidth weights in
+    /// consecutive consensuses
+    bandwidth_autocorrelation: Option<f64>,
+    /// Hourly rates to generate synthetic series with (see the `series`
+    /// command)
+    model: ChurnModel,
+}
+
+fn hours_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_seconds() as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MyRelay;
+    use super::*;
+
+    use chrono::{Duration, TimeZone};
+
+    fn relay(id: u8) -> MyRelay {
+        MyRelay {
+            fingerprint: Fingerprint::from_u8(&[id; 20]),
+            address: [10, 0, 0, id].into(),
+            flags: Vec::new(),
+            bandwidth_weight: 1000,
+        }
+    }
+
+    fn consensus(hour: i64, ids: &[u8]) -> MyConsensus {
+        MyConsensus {
+            valid_after: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap() + Duration::hours(hour),
+            relays: ids.iter().map(|id| relay(*id)).collect(),
+        }
+    }
+
+    #[test]
+    fn lifetimes() {
+        let mut tracker = ChurnTracker::new();
+        tracker.observe(&consensus(0, &[1, 3])).unwrap();
+        // relay 2 is listed in two consecutive consensuses
+        tracker.observe(&consensus(1, &[1, 2, 3])).unwrap();
+        tracker.observe(&consensus(2, &[1, 2, 3])).unwrap();
+        tracker.observe(&consensus(3, &[3])).unwrap();
+
+        let stats = tracker.finish();
+        assert_eq!(stats.joined_relays, 1);
+        assert_eq!(stats.left_relays, 2);
+        assert_eq!(stats.lifetime_hours.completed, 1);
+        assert_eq!(stats.lifetime_hours.mean, Some(1.0));
+        // relay 1 was present from the start, relay 3 until the end
+        assert_eq!(stats.lifetime_hours.censored, 2);
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut tracker = ChurnTracker::new();
+        tracker.observe(&consensus(0, &[1])).unwrap();
+        assert!(tracker.observe(&consensus(0, &[1])).is_err());
+        tracker.observe(&consensus(1, &[1])).unwrap();
+        assert_eq!(tracker.finish().num_consensuses, 2);
+    }
+}
@@ -0,0 +1,234 @@
+//! Family and AS statistics over time, based on consensuses combined with
+//! their descriptors
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use serde::Serialize;
+use tordoc::{Consensus as ConsensusDocument, Descriptor, Fingerprint};
+
+use torscaler::highlevel::asn::AsnDb;
+use torscaler::highlevel::stats::top_share;
+use torscaler::highlevel::{lookup_descriptors_lenient, Consensus, UnpackedConsensus};
+
+/// A consensus from a CollecTor tree together with its descriptors
+pub(crate) struct WithDescriptors {
+    unpacked: UnpackedConsensus,
+    descriptors: Vec<Descriptor>,
+    /// Number of relays removed from the consensus because their descriptor
+    /// is missing or cannot be parsed
+    missing_descriptors: usize,
+}
+
+impl WithDescriptors {
+    /// Combine the consensus with its descriptors. Returns the combined
+    /// consensus and the number of relays left out for lack of a descriptor.
+    pub(crate) fn combine(
+        self,
+        asn_db: &AsnDb,
+    ) -> Result<(Consensus, usize), Box<dyn std::error::Error + Send + Sync>> {
+        let consensus = Consensus::combine_documents(self.unpacked, self.descriptors, asn_db)?;
+        Ok((consensus, self.missing_descriptors))
+    }
+}
+
+/// Look up the descriptors of an already parsed consensus from a CollecTor
+/// tree. `raw` and `fpath` are the consensus document and its path. Relays
+/// without a usable descriptor are removed from the consensus.
+pub(crate) fn lookup_with_descriptors(
+    document: ConsensusDocument,
+    raw: &str,
+    fpath: &Path,
+) -> Result<WithDescriptors, Box<dyn std::error::Error + Send + Sync>> {
+    let mut unpacked = UnpackedConsensus::from_document(document, raw)?;
+    let descriptors = lookup_descriptors_lenient(&unpacked, fpath)?;
+    let missing_descriptors = unpacked
+        .remove_relays_without_descriptors(&descriptors)
+        .len();
+    Ok(WithDescriptors {
+        unpacked,
+        descriptors,
+        missing_descriptors,
+    })
+}
+
+/// Changes of family membership of relays present in two consecutive
+/// consensuses
+#[derive(Serialize, Default)]
+struct FamilyChanges {
+    /// Relays in both consensuses
+    relays: usize,
+    /// Relays that were not in a family before, but are now
+    joined_family: usize,
+    /// Relays that were in a family before, but are not anymore
+    left_family: usize,
+    /// Relays that are in a family with different members than before
+    changed_family: usize,
+}
+
+/// Family and AS statistics of a single consensus
+#[derive(Serialize)]
+struct FamilyRecord {
+    valid_after: u64,
+    num_relays: usize,
+    /// Relays left out of the statistics because their descriptor is missing
+    /// or cannot be parsed
+    missing_descriptors: usize,
+    num_families: usize,
+    /// Probability that a relay is in a family
+    prob_family: f32,
+    /// Probability that two relays in a family have the same AS
+    prob_family_sameas: f32,
+    /// Sizes of families: (size, frequency) tuples
+    family_sizes: Vec<(usize, usize)>,
+    max_family_size: usize,
+    /// Changes relative to the previous consensus of the series
+    family_changes: Option<FamilyChanges>,
+    num_ases: usize,
+    /// Share of the bandwidth weight of the largest AS
+    top1_as_share: Option<f64>,
+    /// Share of the bandwidth weight of the 5 largest ASes
+    top5_as_share: Option<f64>,
+    /// Share of the bandwidth weight of the 10 largest ASes
+    top10_as_share: Option<f64>,
+    /// Herfindahl-Hirschman index of the bandwidth weight of the ASes
+    as_hhi: Option<f64>,
+}
+
+/// Number of relays and bandwidth weight of an AS in one consensus
+#[derive(Serialize)]
+struct AsGrowthRecord {
+    valid_after: u64,
+    asn: u32,
+    num_relays: usize,
+    bandwidth: u64,
+}
+
+/// Collects family and AS statistics for a series of consensuses
+#[derive(Default)]
+pub(crate) struct FamilyTracker {
+    records: Vec<FamilyRecord>,
+    as_growth: Vec<AsGrowthRecord>,
+    /// Family members of the relays in the previous consensus (excluding
+    /// themselves)
+    previous_families: Option<HashMap<Fingerprint, BTreeSet<String>>>,
+}
+
+impl FamilyTracker {
+    pub(crate) fn new() -> FamilyTracker {
+        Default::default()
+    }
+
+    /// Add the next consensus of the series, from which `missing_descriptors`
+    /// relays were removed for lack of a descriptor
+    pub(crate) fn observe(&mut self, consensus: &Consensus, missing_descriptors: usize) {
+        let valid_after = consensus.valid_after.timestamp() as u64;
+
+        // family membership
+        let families: HashMap<Fingerprint, BTreeSet<String>> = consensus
+            .relays
+            .values()
+            .map(|r| {
+                let members = match r.family {
+                    Some(ref family) => family
+                        .members
+                        .iter()
+                        .filter(|fp| **fp != r.fingerprint)
+                        .map(|fp| fp.to_string_hex())
+                        .collect(),
+                    None => BTreeSet::new(),
+                };
+                (r.fingerprint.clone(), members)
+            })
+            .collect();
+        let family_changes = self.previous_families.as_ref().map(|previous| {
+            let mut changes = FamilyChanges::default();
+            for (fp, members) in families.iter() {
+                let before = match previous.get(fp) {
+                    Some(before) => before,
+                    None => continue,
+                };
+                changes.relays += 1;
+                match (before.is_empty(), members.is_empty()) {
+                    (true, false) => changes.joined_family += 1,
+                    (false, true) => changes.left_family += 1,
+                    (false, false) if before != members => changes.changed_family += 1,
+                    _ => {}
+                }
+            }
+            changes
+        });
+
+        // AS concentration
+        let mut ases: BTreeMap<u32, (usize, u64)> = BTreeMap::new();
+        for relay in consensus.relays.values() {
+            if let Some(ref asn) = relay.asn {
+                let entry = ases.entry(asn.number).or_default();
+                entry.0 += 1;
+                entry.1 += relay.bandwidth_weight;
+            }
+        }
+        let as_bandwidths: Vec<f64> = ases.values().map(|(_, bw)| *bw as f64).collect();
+        let as_total: f64 = as_bandwidths.iter().sum();
+        let as_hhi = if as_total > 0.0 {
+            Some(as_bandwidths.iter().map(|bw| (bw / as_total).powi(2)).sum())
+        } else {
+            None
+        };
+
+        self.records.push(FamilyRecord {
+            valid_after,
+            num_relays: consensus.relays.len(),
+            missing_descriptors,
+            num_families: consensus.families.len(),
+            prob_family: consensus.prob_family,
+            prob_family_sameas: consensus.prob_family_sameas,
+            family_sizes: consensus.family_sizes.clone(),
+            max_family_size: consensus
+                .families
+                .iter()
+                .map(|f| f.members.len())
+                .max()
+                .unwrap_or(0),
+            family_changes,
+            num_ases: ases.len(),
+            top1_as_share: top_share(&as_bandwidths, 1),
+            top5_as_share: top_share(&as_bandwidths, 5),
+            top10_as_share: top_share(&as_bandwidths, 10),
+            as_hhi,
+        });
+        self.as_growth
+            .extend(
+                ases.into_iter()
+                    .map(|(asn, (num_relays, bandwidth))| AsGrowthRecord {
+                        valid_after,
+                        asn,
+                        num_relays,
+                        bandwidth,
+                    }),
+            );
+        self.previous_families = Some(families);
+    }
+
+    /// Save the family and AS statistics as JSON
+    pub(crate) fn save_families(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.records)?)?;
+        Ok(())
+    }
+
+    /// Save the number of relays and bandwidth per AS and consensus as CSV
+    pub(crate) fn save_as_growth(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        for record in self.as_growth.iter() {
+            wtr.serialize(record)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
@@ -1,19 +1,37 @@
+mod churn;
+mod families;
+
 use super::{Cli, Command};
 
-use tordoc::Consensus;
+use tordoc::consensus::Flag;
+use tordoc::{Consensus, Fingerprint};
 
-use std::collections::BTreeMap;
-use std::fs::File;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
 use std::io::Read;
+use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
 
-use chrono::{offset::TimeZone, DateTime, Utc};
+use chrono::{offset::TimeZone, DateTime, Duration, NaiveDate, Utc};
 use clap::Args;
 use csv;
 use fromsuper::FromSuper;
-use glob::glob;
+use rayon::prelude::*;
 use serde::Serialize;
 
+use torscaler::highlevel::asn::AsnDb;
+use torscaler::highlevel::collector::{
+    find_consensuses, sample_consensuses, ConsensusFiles, DEFAULT_CONSENSUS_PATTERN,
+};
+use torscaler::highlevel::stats::{gini, quantile};
+
+/// Number of consensuses to parse in parallel before processing them, which
+/// bounds the memory needed for parsed consensuses
+const PARSE_CHUNK_SIZE: usize = 512;
+/// Like [PARSE_CHUNK_SIZE], but for consensuses that are loaded together
+/// with their descriptors
+const PARSE_CHUNK_SIZE_WITH_DESCRIPTORS: usize = 32;
+
 #[derive(Args)]
 pub(crate) struct HistoryArgs {
     /// Folder structure containing historical consensuses
@@ -21,11 +39,45 @@ pub(crate) struct HistoryArgs {
     /// Output CSV file to store the per-consensus aggregate data
     #[clap(long)]
     csv_out: String,
+    /// Output JSON file to store churn statistics to, obtained by tracking
+    /// relays across all consensuses
+    #[clap(long)]
+    churn_out: Option<String>,
+    /// Only use consensuses valid after the start of this day (YYYY-MM-DD)
+    #[clap(long)]
+    start: Option<NaiveDate>,
+    /// Only use consensuses valid before the start of this day (YYYY-MM-DD)
+    #[clap(long)]
+    end: Option<NaiveDate>,
+    /// Use only one consensus per this many hours, e.g. 24 for one consensus
+    /// per day
+    #[clap(long, default_value_t = 1)]
+    interval_hours: i64,
+    /// Patterns of the consensus files, relative to the consensus folder. The
+    /// file names have to start with the valid-after time as in CollecTor.
+    #[clap(long = "pattern", default_value = DEFAULT_CONSENSUS_PATTERN)]
+    patterns: Vec<String>,
+    /// AS IP ranges database CSV file, for counting the ASes of the relays
+    #[clap(long)]
+    asn_db: Option<String>,
+    /// Output JSON file to store family and AS concentration statistics to.
+    /// This combines each consensus with its descriptors, which need to be
+    /// part of the folder structure. Relays without a descriptor are left out
+    /// and counted per consensus.
+    #[clap(long, requires = "asn-db")]
+    families_out: Option<String>,
+    /// Output CSV file to store the number of relays and bandwidth of each
+    /// AS in each consensus to. Like --families-out, this needs descriptors.
+    #[clap(long, requires = "asn-db")]
+    as_growth_out: Option<String>,
 }
 
 #[derive(Debug, FromSuper)]
 #[fromsuper(from_type = "tordoc::consensus::Relay", unpack = true)]
 struct MyRelay {
+    fingerprint: Fingerprint,
+    address: Ipv4Addr,
+    flags: Vec<Flag>,
     bandwidth_weight: u64,
 }
 
@@ -34,23 +86,53 @@ struct MyConsensus {
     relays: Vec<MyRelay>,
 }
 
-impl TryFrom<Consensus> for MyConsensus {
+impl TryFrom<&Consensus> for MyConsensus {
     type Error = Box<dyn std::error::Error + Send + Sync>;
 
-    fn try_from(value: Consensus) -> Result<Self, Self::Error> {
+    fn try_from(value: &Consensus) -> Result<Self, Self::Error> {
         Ok(MyConsensus {
             valid_after: value
                 .valid_after
                 .ok_or_else(|| "missing valid_after value")?,
             relays: value
                 .relays
-                .into_iter()
-                .map(|r| r.try_into())
+                .iter()
+                .map(|r| r.clone().try_into())
                 .collect::<Result<_, _>>()?,
         })
     }
 }
 
+/// A parsed consensus file
+struct ParsedConsensus {
+    cons: MyConsensus,
+    /// The full consensus and its descriptors, if they were requested
+    with_descriptors:
+        Option<Result<families::WithDescriptors, Box<dyn std::error::Error + Send + Sync>>>,
+}
+
+/// Read and parse a consensus file. If `with_descriptors` is set, also look
+/// up its descriptors, so the consensus can be combined with them.
+fn parse_consensus(
+    fpath: &Path,
+    with_descriptors: bool,
+) -> Result<ParsedConsensus, Box<dyn std::error::Error + Send + Sync>> {
+    let mut raw = String::new();
+    let mut file = File::open(fpath)?;
+    file.read_to_string(&mut raw)?;
+    let document = Consensus::from_str(&raw)?;
+    let cons = MyConsensus::try_from(&document)?;
+    let with_descriptors = if with_descriptors {
+        Some(families::lookup_with_descriptors(document, &raw, fpath))
+    } else {
+        None
+    };
+    Ok(ParsedConsensus {
+        cons,
+        with_descriptors,
+    })
+}
+
 pub(crate) fn command_history(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let cli_history = if let Command::History(x) = cli.command {
         x
@@ -58,80 +140,134 @@ pub(crate) fn command_history(cli: Cli) -> Result<(), Box<dyn std::error::Error
         panic!("wrong command");
     };
 
-    // Find the available consensuses
-    let glob_expr = Path::new(&cli_history.consensus_dir)
-        .join("consensuses-*-*/*/*-consensus")
-        .to_str()
-        .unwrap()
-        .to_owned();
-
-    let files = glob(&glob_expr)?
-        .filter_map(|x| match x {
-            Err(e) => {
-                eprintln!("[Warning] When searching for consensuses: {:?}", e);
-                None
-            }
-            Ok(x) => Some(x),
-        })
-        .collect::<Vec<_>>();
-
-    if files.len() == 0 {
-        panic!("no consensus files found");
+    if cli_history.interval_hours < 1 {
+        return Err("--interval-hours needs to be at least 1".into());
     }
+    let start = cli_history
+        .start
+        .map(|d| Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap()));
+    let end = cli_history
+        .end
+        .map(|d| Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap()));
 
-    // Associate files with their UTC date and time.
-    // Also filter out too old consensuses.
-    let files: BTreeMap<DateTime<Utc>, PathBuf> = files
+    // Find the available consensuses within the requested period, and
+    // sample one consensus per interval
+    let files = find_consensuses(&cli_history.consensus_dir, &cli_history.patterns)?;
+    let files: ConsensusFiles = files
         .into_iter()
-        .filter_map(|f| {
-            let dt = Utc
-                .datetime_from_str(
-                    &f.file_name().unwrap().to_str().unwrap()[..19],
-                    "%Y-%m-%d-%H-%M-%S",
-                )
-                .unwrap();
-
-            // only retain a period of 10 years
-            if (dt >= Utc.ymd(2013, 2, 1).and_hms(0, 0, 0))
-                && (dt < Utc.ymd(2023, 2, 1).and_hms(0, 0, 0))
-            {
-                Some((dt, f))
-            } else {
-                None
-            }
+        .filter(|(dt, _)| {
+            start.map_or(true, |start| *dt >= start) && end.map_or(true, |end| *dt < end)
         })
         .collect();
+    let files: Vec<PathBuf> =
+        sample_consensuses(files, Duration::hours(cli_history.interval_hours))
+            .into_values()
+            .collect();
+    println!("Using {} consensuses", files.len());
+
+    let asn_db = match cli_history.asn_db {
+        Some(ref path) => Some(AsnDb::new(path)?),
+        None => None,
+    };
 
     // open output file
     let mut wtr = csv::Writer::from_path(&cli_history.csv_out)?;
 
+    let mut families = if cli_history.families_out.is_some() || cli_history.as_growth_out.is_some()
+    {
+        Some(families::FamilyTracker::new())
+    } else {
+        None
+    };
+    let mut churn = cli_history
+        .churn_out
+        .as_ref()
+        .map(|_| churn::ChurnTracker::new());
+
     // let pb = indicatif::ProgressBar::new(files.len() as u64);
 
-    // parse and save the consensuses
-    for (i, (_dt, fpath)) in files.into_iter().enumerate() {
-        if i % 24 == 0 {
-            println!("{:7}: {}", i, fpath.display());
-        }
-        let mut raw = String::new();
-        let mut file = File::open(&fpath).unwrap();
-        file.read_to_string(&mut raw).unwrap();
-        let cons = Consensus::from_str(&raw)?;
-        let cons: MyConsensus = cons.try_into()?;
-
-        // create CSV record
-        let record = CsvRecord {
-            valid_after: cons.valid_after.timestamp() as u64,
-            num_relays: cons.relays.len(),
-            avg_bandwidth: cons.relays.iter().map(|r| r.bandwidth_weight).sum::<u64>() as f64
-                / cons.relays.len() as f64,
-        };
+    // parse the consensuses in parallel, one chunk at a time, and save them
+    // in order. Files that cannot be read or parsed are skipped.
+    let load_descriptors = families.is_some();
+    let chunk_size = if load_descriptors {
+        PARSE_CHUNK_SIZE_WITH_DESCRIPTORS
+    } else {
+        PARSE_CHUNK_SIZE
+    };
+    let mut i = 0;
+    for chunk in files.chunks(chunk_size) {
+        let parsed: Vec<_> = chunk
+            .par_iter()
+            .map(|fpath| parse_consensus(fpath, load_descriptors))
+            .collect();
 
-        // write to file
-        wtr.serialize(record)?;
+        for (fpath, parsed) in chunk.iter().zip(parsed) {
+            if i % 24 == 0 {
+                println!("{:7}: {}", i, fpath.display());
+            }
+            i += 1;
+
+            let ParsedConsensus {
+                cons,
+                with_descriptors,
+            } = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("[Warning] Skipping {}: {}", fpath.display(), e);
+                    continue;
+                }
+            };
+
+            // create CSV record
+            let record = CsvRecord::new(&cons, asn_db.as_ref());
+
+            // write to file
+            wtr.serialize(record)?;
+
+            if let Some(ref mut churn) = churn {
+                if let Err(e) = churn.observe(&cons) {
+                    eprintln!(
+                        "[Warning] Skipping {} for churn statistics: {}",
+                        fpath.display(),
+                        e
+                    );
+                }
+            }
+
+            if let Some(ref mut families) = families {
+                // relays without a descriptor are left out, and consensuses
+                // that cannot be combined at all are skipped, so family
+                // changes are relative to the previous usable one
+                let combined = with_descriptors
+                    .expect("descriptors are looked up for the family statistics")
+                    .and_then(|d| d.combine(asn_db.as_ref().unwrap()));
+                match combined {
+                    Ok((combined, missing)) => families.observe(&combined, missing),
+                    Err(e) => eprintln!(
+                        "[Warning] Skipping {} for family statistics: {}",
+                        fpath.display(),
+                        e
+                    ),
+                }
+            }
+        }
     }
 
     drop(wtr);
 
+    if let Some(families) = families {
+        if let Some(ref path) = cli_history.families_out {
+            families.save_families(path)?;
+        }
+        if let Some(ref path) = cli_history.as_growth_out {
+            families.save_as_growth(path)?;
+        }
+    }
+
+    if let (Some(churn), Some(path)) = (churn, cli_history.churn_out) {
+        fs::write(path, serde_json::to_string_pretty(&churn.finish())?)?;
+    }
+
     Ok(())
 }
 
@@ -140,4 +276,98 @@ struct CsvRecord {
     valid_after: u64,
     num_relays: usize,
     avg_bandwidth: f64,
+    total_bandwidth: u64,
+    num_guard: usize,
+    bw_guard: u64,
+    num_exit: usize,
+    bw_exit: u64,
+    num_badexit: usize,
+    bw_badexit: u64,
+    num_fast: usize,
+    bw_fast: u64,
+    num_stable: usize,
+    bw_stable: u64,
+    num_hsdir: usize,
+    bw_hsdir: u64,
+    bw_q10: Option<f64>,
+    bw_q25: Option<f64>,
+    bw_median: Option<f64>,
+    bw_q75: Option<f64>,
+    bw_q90: Option<f64>,
+    bw_q99: Option<f64>,
+    bw_gini: Option<f64>,
+    /// Number of distinct ASes, if an AS database is given
+    num_ases: Option<usize>,
+    /// Share of the total bandwidth of relays with the Guard flag
+    guard_bw_share: f64,
+    /// Share of the total bandwidth of relays with the Exit flag
+    exit_bw_share: f64,
+}
+
+impl CsvRecord {
+    fn new(cons: &MyConsensus, asn_db: Option<&AsnDb>) -> CsvRecord {
+        let flag_stats = |flag: Flag| -> (usize, u64) {
+            let relays = cons.relays.iter().filter(|r| r.flags.contains(&flag));
+            relays.fold((0, 0), |(num, bw), r| (num + 1, bw + r.bandwidth_weight))
+        };
+        let (num_guard, bw_guard) = flag_stats(Flag::Guard);
+        let (num_exit, bw_exit) = flag_stats(Flag::Exit);
+        let (num_badexit, bw_badexit) = flag_stats(Flag::BadExit);
+        let (num_fast, bw_fast) = flag_stats(Flag::Fast);
+        let (num_stable, bw_stable) = flag_stats(Flag::Stable);
+        let (num_hsdir, bw_hsdir) = flag_stats(Flag::HSDir);
+
+        let mut bandwidths: Vec<f64> = cons
+            .relays
+            .iter()
+            .map(|r| r.bandwidth_weight as f64)
+            .collect();
+        bandwidths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let total_bandwidth: u64 = cons.relays.iter().map(|r| r.bandwidth_weight).sum();
+        let share = |bw: u64| {
+            if total_bandwidth == 0 {
+                0.0
+            } else {
+                bw as f64 / total_bandwidth as f64
+            }
+        };
+
+        let num_ases = asn_db.map(|asn_db| {
+            cons.relays
+                .iter()
+                .filter_map(|r| asn_db.lookup(r.address))
+                .map(|asn| asn.number)
+                .collect::<BTreeSet<_>>()
+                .len()
+        });
+
+        CsvRecord {
+            valid_after: cons.valid_after.timestamp() as u64,
+            num_relays: cons.relays.len(),
+            avg_bandwidth: total_bandwidth as f64 / cons.relays.len() as f64,
+            total_bandwidth,
+            num_guard,
+            bw_guard,
+            num_exit,
+            bw_exit,
+            num_badexit,
+            bw_badexit,
+            num_fast,
+            bw_fast,
+            num_stable,
+            bw_stable,
+            num_hsdir,
+            bw_hsdir,
+            bw_q10: quantile(&bandwidths, 0.1),
+            bw_q25: quantile(&bandwidths, 0.25),
+            bw_median: quantile(&bandwidths, 0.5),
+            bw_q75: quantile(&bandwidths, 0.75),
+            bw_q90: quantile(&bandwidths, 0.9),
+            bw_q99: quantile(&bandwidths, 0.99),
+            bw_gini: gini(&bandwidths),
+            num_ases,
+            guard_bw_share: share(bw_guard),
+            exit_bw_share: share(bw_exit),
+        }
+    }
 }
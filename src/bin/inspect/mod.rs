@@ -0,0 +1,368 @@
+//! Summary of a single consensus, without scaling it
+
+use super::{Cli, Command};
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use clap::Args;
+use serde::Serialize;
+
+use torscaler::highlevel::asn::AsnDb;
+use torscaler::highlevel::{
+    lookup_descriptors_lenient, Consensus, Relay, UnpackedConsensus, WeightVerification,
+};
+
+#[derive(Args)]
+pub(crate) struct InspectArgs {
+    /// Consensus to inspect
+    consensus: String,
+    /// Descriptor database for relay descriptors. If not given, try to load
+    /// descriptors from folders relative to the consensus file.
+    #[clap(long)]
+    descriptors: Option<String>,
+    /// AS IP ranges database CSV file
+    #[clap(long)]
+    asn_db: String,
+    /// Number of relays, families and ASes to list
+    #[clap(long, default_value_t = 10)]
+    top: usize,
+    /// Save the summary as JSON to this file
+    #[clap(long)]
+    json: Option<String>,
+}
+
+/// How many relays of the consensus could be combined with a descriptor
+#[derive(Serialize)]
+struct DescriptorCoverage {
+    relays_in_consensus: usize,
+    relays_with_descriptor: usize,
+    /// Fingerprints of the relays without a (parsable) descriptor. They are
+    /// not part of the remaining summary.
+    missing: Vec<String>,
+}
+
+/// Number of relays and bandwidth weight of a group of relays
+#[derive(Serialize, Default)]
+struct GroupStats {
+    relays: usize,
+    bandwidth: u64,
+    bandwidth_share: f64,
+}
+
+impl GroupStats {
+    fn add(&mut self, relay: &Relay) {
+        self.relays += 1;
+        self.bandwidth += relay.bandwidth_weight;
+    }
+}
+
+#[derive(Serialize)]
+struct RelaySummary {
+    fingerprint: String,
+    nickname: String,
+    address: String,
+    bandwidth: u64,
+    bandwidth_share: f64,
+    flags: Vec<&'static str>,
+    asn: Option<u32>,
+}
+
+impl RelaySummary {
+    fn new(relay: &Relay, total_bandwidth: u64) -> RelaySummary {
+        RelaySummary {
+            fingerprint: relay.fingerprint.to_string_hex(),
+            nickname: relay.nickname.clone(),
+            address: relay.address.to_string(),
+            bandwidth: relay.bandwidth_weight,
+            bandwidth_share: share(relay.bandwidth_weight, total_bandwidth),
+            flags: relay.flags.iter().map(<&'static str>::from).collect(),
+            asn: relay.asn.as_ref().map(|asn| asn.number),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FamilySummary {
+    size: usize,
+    bandwidth: u64,
+    bandwidth_share: f64,
+    /// Nicknames of the members
+    members: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AsSummary {
+    asn: u32,
+    name: String,
+    relays: usize,
+    bandwidth: u64,
+    bandwidth_share: f64,
+}
+
+#[derive(Serialize)]
+struct InspectReport {
+    /// Unix timestamp of the consensus
+    valid_after: u64,
+    coverage: DescriptorCoverage,
+    total_bandwidth: u64,
+    flags: BTreeMap<&'static str, GroupStats>,
+    position_classes: BTreeMap<String, GroupStats>,
+    /// Recomputation of the bandwidth weights from all relays of the
+    /// consensus (including those without descriptor), compared to the
+    /// contained ones
+    weights: WeightVerification,
+    num_families: usize,
+    prob_family: f32,
+    prob_family_sameas: f32,
+    family_sizes: Vec<(usize, usize)>,
+    num_ases: usize,
+    top_relays: Vec<RelaySummary>,
+    top_families: Vec<FamilySummary>,
+    top_ases: Vec<AsSummary>,
+    /// Relays whose address is not in the AS database
+    relays_without_as: Vec<RelaySummary>,
+}
+
+fn share(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+/// Load a consensus and combine it with the descriptors that can be found,
+/// leaving out the relays without one. The bandwidth weights are verified
+/// before, using all relays.
+fn load_lenient(
+    args: &InspectArgs,
+    asn_db: &AsnDb,
+) -> Result<
+    (Consensus, DescriptorCoverage, WeightVerification),
+    Box<dyn std::error::Error + Sync + Send>,
+> {
+    let mut unpacked = {
+        let mut raw = String::new();
+        let mut file = File::open(&args.consensus)?;
+        file.read_to_string(&mut raw)?;
+        raw.parse::<UnpackedConsensus>()?
+    };
+    let relays_in_consensus = unpacked.num_relays();
+    let weights = unpacked.verify_weights(0);
+
+    let descriptors = match args.descriptors {
+        Some(ref desc_path) => {
+            let mut raw = String::new();
+            let mut file = File::open(desc_path)?;
+            file.read_to_string(&mut raw)?;
+            tordoc::Descriptor::many_from_str(&raw)?
+        }
+        None => lookup_descriptors_lenient(&unpacked, &args.consensus)?,
+    };
+    let missing = unpacked.remove_relays_without_descriptors(&descriptors);
+
+    let consensus = Consensus::combine_documents(unpacked, descriptors, asn_db)?;
+    let coverage = DescriptorCoverage {
+        relays_in_consensus,
+        relays_with_descriptor: relays_in_consensus - missing.len(),
+        missing: missing.iter().map(|fp| fp.to_string_hex()).collect(),
+    };
+    Ok((consensus, coverage, weights))
+}
+
+fn summarize(
+    consensus: Consensus,
+    coverage: DescriptorCoverage,
+    weights: WeightVerification,
+    top: usize,
+) -> InspectReport {
+    let total_bandwidth: u64 = consensus.relays.values().map(|r| r.bandwidth_weight).sum();
+
+    let mut flags: BTreeMap<&'static str, GroupStats> = BTreeMap::new();
+    let mut position_classes: BTreeMap<String, GroupStats> = BTreeMap::new();
+    let mut ases: BTreeMap<u32, AsSummary> = BTreeMap::new();
+    let mut relays_without_as = Vec::new();
+    for relay in consensus.relays.values() {
+        for flag in relay.flags.iter() {
+            flags.entry(flag.into()).or_default().add(relay);
+        }
+        position_classes
+            .entry(format!("{:?}", relay.position_class()))
+            .or_default()
+            .add(relay);
+
+        match relay.asn {
+            Some(ref asn) => {
+                let entry = ases.entry(asn.number).or_insert_with(|| AsSummary {
+                    asn: asn.number,
+                    name: asn.name().to_string(),
+                    relays: 0,
+                    bandwidth: 0,
+                    bandwidth_share: 0.0,
+                });
+                entry.relays += 1;
+                entry.bandwidth += relay.bandwidth_weight;
+            }
+            None => relays_without_as.push(RelaySummary::new(relay, total_bandwidth)),
+        }
+    }
+    for group in flags.values_mut().chain(position_classes.values_mut()) {
+        group.bandwidth_share = share(group.bandwidth, total_bandwidth);
+    }
+    relays_without_as.sort_by_key(|x| Reverse(x.bandwidth));
+
+    let mut top_relays: Vec<&Relay> = consensus.relays.values().collect();
+    top_relays.sort_by_key(|x| Reverse(x.bandwidth_weight));
+    let top_relays = top_relays
+        .into_iter()
+        .take(top)
+        .map(|relay| RelaySummary::new(relay, total_bandwidth))
+        .collect();
+
+    let mut top_families: Vec<FamilySummary> = consensus
+        .families
+        .iter()
+        .map(|family| {
+            let members: Vec<&Relay> = family
+                .members
+                .iter()
+                .filter_map(|fp| consensus.relays.get(fp))
+                .collect();
+            let bandwidth = members.iter().map(|r| r.bandwidth_weight).sum();
+            FamilySummary {
+                size: family.members.len(),
+                bandwidth,
+                bandwidth_share: share(bandwidth, total_bandwidth),
+                members: members.iter().map(|r| r.nickname.clone()).collect(),
+            }
+        })
+        .collect();
+    top_families.sort_by_key(|x| Reverse(x.bandwidth));
+    top_families.truncate(top);
+
+    let num_ases = ases.len();
+    let mut top_ases: Vec<AsSummary> = ases.into_values().collect();
+    for summary in top_ases.iter_mut() {
+        summary.bandwidth_share = share(summary.bandwidth, total_bandwidth);
+    }
+    top_ases.sort_by_key(|x| Reverse(x.bandwidth));
+    top_ases.truncate(top);
+
+    InspectReport {
+        valid_after: consensus.valid_after.timestamp() as u64,
+        coverage,
+        total_bandwidth,
+        flags,
+        position_classes,
+        weights,
+        num_families: consensus.families.len(),
+        prob_family: consensus.prob_family,
+        prob_family_sameas: consensus.prob_family_sameas,
+        family_sizes: consensus.family_sizes.clone(),
+        num_ases,
+        top_relays,
+        top_families,
+        top_ases,
+        relays_without_as,
+    }
+}
+
+fn print_report(report: &InspectReport) {
+    let coverage = &report.coverage;
+    println!(
+        "Descriptors: {} of {} relays ({:.1}%)",
+        coverage.relays_with_descriptor,
+        coverage.relays_in_consensus,
+        100.0
+            * share(
+                coverage.relays_with_descriptor as u64,
+                coverage.relays_in_consensus as u64
+            )
+    );
+
+    println!("Flags:");
+    for (flag, stats) in report.flags.iter() {
+        println!(
+            "- {:13} {:6} relays, {:5.1}% of bandwidth",
+            flag,
+            stats.relays,
+            100.0 * stats.bandwidth_share
+        );
+    }
+    println!("Position classes:");
+    for (class, stats) in report.position_classes.iter() {
+        println!(
+            "- {:13} {:6} relays, {:5.1}% of bandwidth",
+            class,
+            stats.relays,
+            100.0 * stats.bandwidth_share
+        );
+    }
+
+    print!("{}", report.weights);
+    if !report.weights.is_match() {
+        println!(
+            "[Warning] The bandwidth weights of the consensus differ from the recomputed ones"
+        );
+    }
+
+    println!("Top relays by bandwidth:");
+    for relay in report.top_relays.iter() {
+        println!(
+            "- {:19} {} {:5.2}% AS{}",
+            relay.nickname,
+            relay.fingerprint,
+            100.0 * relay.bandwidth_share,
+            relay.asn.map_or("?".to_string(), |asn| asn.to_string())
+        );
+    }
+    println!("Top families by bandwidth:");
+    for family in report.top_families.iter() {
+        println!(
+            "- {:3} relays, {:5.2}%: {}",
+            family.size,
+            100.0 * family.bandwidth_share,
+            family.members.join(", ")
+        );
+    }
+    println!("Top ASes by bandwidth ({} ASes in total):", report.num_ases);
+    for summary in report.top_ases.iter() {
+        println!(
+            "- AS{:<7} {:5} relays, {:5.2}%: {}",
+            summary.asn,
+            summary.relays,
+            100.0 * summary.bandwidth_share,
+            summary.name
+        );
+    }
+    println!(
+        "Relays without AS: {} ({:.2}% of bandwidth)",
+        report.relays_without_as.len(),
+        100.0
+            * report
+                .relays_without_as
+                .iter()
+                .map(|r| r.bandwidth_share)
+                .sum::<f64>()
+    );
+}
+
+pub(crate) fn command_inspect(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let args = match cli.command {
+        Command::Inspect(args) => args,
+        _ => panic!("wrong command"),
+    };
+
+    let asn_db = AsnDb::new(&args.asn_db)?;
+    let (consensus, coverage, weights) = load_lenient(&args, &asn_db)?;
+    let report = summarize(consensus, coverage, weights, args.top);
+    print_report(&report);
+
+    if let Some(ref path) = args.json {
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use super::{command_scale, Cli, Command, ScaleArgs};
+
+use std::fs;
+use std::path::Path;
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tordoc::consensus::Flag;
+
+use torscaler::highlevel::{descriptor_paths, Consensus, UnpackedConsensus};
+
+/// File name of the manifest within the output directory
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Args)]
+pub(crate) struct ReplayArgs {
+    /// Manifest of the run to repeat
+    manifest: String,
+    /// Directory to save the output to. It is created if it does not exist
+    /// and has to be empty otherwise.
+    #[clap(long, short)]
+    output_dir: String,
+    /// Directory to save the Tor DataDirectory cache files to, if the
+    /// original run saved them. Defaults to "<OUTPUT_DIR>.tor-cache".
+    #[clap(long)]
+    output_tor_cache: Option<String>,
+    /// Directory to save the chutney network to, if the original run saved
+    /// one. Defaults to "<OUTPUT_DIR>.chutney".
+    #[clap(long)]
+    output_chutney: Option<String>,
+    /// Directory to save the relays' Tor data directories to, if the
+    /// original run saved them. Defaults to "<OUTPUT_DIR>.relay-data".
+    #[clap(long)]
+    relay_data_dir: Option<String>,
+    /// Continue even if the input files differ from the ones in the manifest
+    #[clap(long)]
+    ignore_input_changes: bool,
+}
+
+/// Everything needed to reproduce a scaling run
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    torscaler_version: String,
+    seed: u64,
+    args: ScaleArgs,
+    inputs: Vec<InputFile>,
+    steps: Vec<StepStats>,
+}
+
+/// An input file and its SHA-256 hash
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct InputFile {
+    role: String,
+    path: String,
+    sha256: String,
+}
+
+/// Statistics of the consensus after a step of the run
+#[derive(Serialize, Deserialize)]
+struct StepStats {
+    step: String,
+    relays: usize,
+    families: usize,
+    guards: usize,
+    exits: usize,
+    total_weight: u64,
+}
+
+impl Manifest {
+    /// Start a new manifest, hashing the input files given in the arguments
+    /// and the descriptor files looked up relative to the consensus documents.
+    /// Input paths are recorded as absolute paths, so the run can be replayed
+    /// from another working directory.
+    pub(crate) fn new(
+        seed: u64,
+        args: &ScaleArgs,
+    ) -> Result<Manifest, Box<dyn std::error::Error + Sync + Send>> {
+        let mut args = args.clone();
+        args.consensus = absolute(&args.consensus)?;
+        args.asn_db = absolute(&args.asn_db)?;
+        for path in [
+            &mut args.descriptors,
+            &mut args.prob_family_new_from,
+            &mut args.geoip,
+            &mut args.authority_keys,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = absolute(path)?;
+        }
+
+        let mut inputs = vec![
+            InputFile::new("consensus", &args.consensus)?,
+            InputFile::new("asn_db", &args.asn_db)?,
+        ];
+        match args.descriptors {
+            Some(ref descriptors) => inputs.push(InputFile::new("descriptors", descriptors)?),
+            None => inputs.extend(descriptor_inputs("descriptor", &args.consensus)?),
+        }
+        if let Some(ref earlier) = args.prob_family_new_from {
+            inputs.push(InputFile::new("prob_family_new_from", earlier)?);
+            inputs.extend(descriptor_inputs(
+                "prob_family_new_from_descriptor",
+                earlier,
+            )?);
+        }
+        if let Some(ref geoip) = args.geoip {
+            inputs.push(InputFile::new("geoip", geoip)?);
+        }
+
+        Ok(Manifest {
+            torscaler_version: env!("CARGO_PKG_VERSION").to_string(),
+            seed,
+            args,
+            inputs,
+            steps: Vec::new(),
+        })
+    }
+
+    /// Record the state of the consensus after a step
+    pub(crate) fn record_step(&mut self, step: &str, consensus: &Consensus) {
+        self.steps.push(StepStats {
+            step: step.to_string(),
+            relays: consensus.relays.len(),
+            families: consensus.families.len(),
+            guards: consensus
+                .relays
+                .values()
+                .filter(|r| r.has_flag(Flag::Guard))
+                .count(),
+            exits: consensus
+                .relays
+                .values()
+                .filter(|r| r.has_flag(Flag::Exit))
+                .count(),
+            total_weight: consensus.relays.values().map(|r| r.bandwidth_weight).sum(),
+        });
+    }
+
+    pub(crate) fn save(
+        &self,
+        dir: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        fs::write(
+            dir.as_ref().join(MANIFEST_FILE),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    fn load(path: impl AsRef<Path>) -> Result<Manifest, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+impl InputFile {
+    fn new(role: &str, path: &str) -> Result<InputFile, std::io::Error> {
+        Ok(InputFile {
+            role: role.to_string(),
+            path: path.to_string(),
+            sha256: sha256_file(path)?,
+        })
+    }
+}
+
+/// The descriptor files found relative to a consensus document
+fn descriptor_inputs(
+    role: &str,
+    consensus_path: &str,
+) -> Result<Vec<InputFile>, Box<dyn std::error::Error + Sync + Send>> {
+    let consensus: UnpackedConsensus = fs::read_to_string(consensus_path)?.parse()?;
+    descriptor_paths(&consensus, consensus_path)?
+        .iter()
+        .map(|path| Ok(InputFile::new(role, &path.display().to_string())?))
+        .collect()
+}
+
+/// Make a path absolute, without requiring it to exist
+fn absolute(path: &str) -> Result<String, std::io::Error> {
+    Ok(std::path::absolute(path)?.display().to_string())
+}
+
+fn sha256_file(path: impl AsRef<Path>) -> Result<String, std::io::Error> {
+    let hash = Sha256::digest(fs::read(path)?);
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub(crate) fn command_replay(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let cli_replay = if let Command::Replay(x) = cli.command {
+        x
+    } else {
+        panic!("wrong command");
+    };
+
+    let manifest = Manifest::load(&cli_replay.manifest)?;
+    if manifest.torscaler_version != env!("CARGO_PKG_VERSION") {
+        println!(
+            "Warning: the manifest was created by torscaler {}, this is {}",
+            manifest.torscaler_version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    // make sure we work on the same inputs
+    for input in manifest.inputs.iter() {
+        let sha256 = sha256_file(&input.path)?;
+        if sha256 != input.sha256 {
+            if cli_replay.ignore_input_changes {
+                println!("Warning: {} ({}) has changed", input.role, input.path);
+            } else {
+                return Err(format!("{} ({}) has changed", input.role, input.path).into());
+            }
+        }
+    }
+
+    // Outputs that the original run saved outside of its output directory
+    // are placed next to the new output directory, unless given explicitly.
+    let mut args = manifest.args;
+    let output_dir = cli_replay.output_dir;
+    let relocate = |recorded: Option<String>, given: Option<String>, suffix: &str| {
+        recorded.map(|_| {
+            given.unwrap_or_else(|| format!("{}.{}", output_dir.trim_end_matches('/'), suffix))
+        })
+    };
+    args.output_tor_cache = relocate(
+        args.output_tor_cache,
+        cli_replay.output_tor_cache,
+        "tor-cache",
+    );
+    args.output_chutney = relocate(args.output_chutney, cli_replay.output_chutney, "chutney");
+    args.relay_data_dir = relocate(args.relay_data_dir, cli_replay.relay_data_dir, "relay-data");
+    for dir in [
+        Some(&output_dir),
+        args.output_tor_cache.as_ref(),
+        args.output_chutney.as_ref(),
+        args.relay_data_dir.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        fs::create_dir_all(dir)?;
+    }
+    args.output_dir = Some(output_dir);
+
+    seeded_rand::set_seed(manifest.seed);
+    println!("Replaying with seed {}", manifest.seed);
+    command_scale(
+        Cli {
+            seed: manifest.seed,
+            threads: cli.threads,
+            command: Command::Scale(args),
+        },
+        manifest.seed,
+    )
+}
@@ -0,0 +1,127 @@
+//! Simulation of circuit paths and how often adversaries compromise them
+
+use super::{load_consensus, Cli, Command};
+
+use std::fs;
+
+use clap::Args;
+
+use torscaler::highlevel::asn::AsnDb;
+use torscaler::highlevel::paths::{simulate_paths, AdversarySet, PathSimulation};
+use torscaler::highlevel::Consensus;
+
+#[derive(Args)]
+pub(crate) struct PathsArgs {
+    /// Consensus to build the circuits from
+    #[clap(long)]
+    consensus: String,
+    /// Descriptor database for relay descriptors. If not given, try to load
+    /// descriptors from folders relative to the consensus file.
+    #[clap(long)]
+    descriptors: Option<String>,
+    /// AS IP ranges database CSV file
+    #[clap(long)]
+    asn_db: String,
+    /// Number of circuits to simulate
+    #[clap(long, short = 'n', default_value_t = 100_000)]
+    circuits: usize,
+    /// Only use exits that allow connections to this port
+    #[clap(long)]
+    port: Option<u16>,
+    /// Adversary controlling all relays in this AS (can be given multiple
+    /// times)
+    #[clap(long = "adversary-as")]
+    adversary_ases: Vec<u32>,
+    /// Adversary controlling the family of the relay with this fingerprint
+    /// (can be given multiple times)
+    #[clap(long = "adversary-family")]
+    adversary_families: Vec<String>,
+    /// Adversary controlling the relays listed in this file, one fingerprint
+    /// per line (can be given multiple times)
+    #[clap(long = "adversary-relays")]
+    adversary_relays: Vec<String>,
+    /// Save the results as JSON to this file
+    #[clap(long)]
+    json: Option<String>,
+}
+
+/// Build the adversary sets given on the command line
+fn adversaries(
+    args: &PathsArgs,
+    consensus: &Consensus,
+) -> Result<Vec<AdversarySet>, Box<dyn std::error::Error + Sync + Send>> {
+    let mut adversaries = Vec::new();
+    for asn in args.adversary_ases.iter() {
+        let adversary = AdversarySet::from_as(consensus, *asn);
+        if adversary.relays.is_empty() {
+            eprintln!("[Warning] No relays in AS{}", asn);
+        }
+        adversaries.push(adversary);
+    }
+    for fingerprint in args.adversary_families.iter() {
+        let adversary = AdversarySet::from_family(consensus, fingerprint)
+            .ok_or_else(|| format!("relay {} is not in the consensus", fingerprint))?;
+        adversaries.push(adversary);
+    }
+    for path in args.adversary_relays.iter() {
+        let content = fs::read_to_string(path)?;
+        let fingerprints: Vec<&str> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        let (adversary, unknown) = AdversarySet::from_relays(consensus, path, &fingerprints);
+        for fingerprint in unknown {
+            eprintln!(
+                "[Warning] Relay {} from {} is not in the consensus",
+                fingerprint, path
+            );
+        }
+        adversaries.push(adversary);
+    }
+    Ok(adversaries)
+}
+
+fn print_simulation(simulation: &PathSimulation) {
+    println!(
+        "Simulated {} circuits{} ({} could not be built)",
+        simulation.circuits,
+        simulation
+            .port
+            .map_or(String::new(), |port| format!(" to port {}", port)),
+        simulation.failed
+    );
+    for rate in simulation.adversaries.iter() {
+        let ci = rate.end_to_end_ci.map_or(String::new(), |(lower, upper)| {
+            format!(" (95% CI {:.5}..{:.5})", lower, upper)
+        });
+        println!(
+            "- {} ({} relays): guard {:.4}, middle {:.4}, exit {:.4}, end-to-end {:.5}{}, any {:.4}",
+            rate.adversary, rate.relays, rate.guard, rate.middle, rate.exit, rate.end_to_end, ci, rate.any
+        );
+    }
+}
+
+pub(crate) fn command_paths(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let cli_paths = if let Command::Paths(x) = cli.command {
+        x
+    } else {
+        panic!("wrong command");
+    };
+
+    let asn_db = AsnDb::new(&cli_paths.asn_db)?;
+    let consensus = load_consensus(
+        &cli_paths.consensus,
+        cli_paths.descriptors.as_deref(),
+        &asn_db,
+    )?;
+
+    let adversaries = adversaries(&cli_paths, &consensus)?;
+    let simulation = simulate_paths(&consensus, cli_paths.circuits, cli_paths.port, &adversaries)?;
+    print_simulation(&simulation);
+
+    if let Some(ref path) = cli_paths.json {
+        fs::write(path, serde_json::to_string_pretty(&simulation)?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use super::{load_consensus, load_or_generate_authorities, Cli, Command};
+
+use std::fs;
+use std::net::Ipv4Addr;
+
+use chrono::Duration;
+use clap::Args;
+
+use torscaler::highlevel::asn::AsnDb;
+use torscaler::highlevel::output::{
+    save_to_collector_tree, save_to_tordata_dir, OutputOptions, WrittenDocuments,
+};
+use torscaler::highlevel::roundtrip::{validate_written_documents, RoundTripError};
+use torscaler::highlevel::{scale_horizontally, ChurnModel, Consensus, SeriesGenerator};
+
+#[derive(Args)]
+pub(crate) struct SeriesArgs {
+    /// Consensus to start the series with
+    #[clap(long)]
+    consensus: String,
+    /// Descriptor database for relay descriptors. If not given, try to load
+    /// descriptors from folders relative to the consensus file.
+    #[clap(long)]
+    descriptors: Option<String>,
+    /// AS IP ranges database CSV file
+    #[clap(long)]
+    asn_db: String,
+    /// Scale the base consensus horizontally by this factor before generating
+    /// the series
+    #[clap(long, requires = "prob-family-new")]
+    horz: Option<f32>,
+    /// when scaling the base consensus horizontally, favor growing families or
+    /// creating new ones [0...1] (0 = only existing, 1 = only new)
+    #[clap(long, requires = "horz")]
+    prob_family_new: Option<f32>,
+    /// Number of consensuses to generate after the base consensus
+    #[clap(long, short = 'n')]
+    count: usize,
+    /// Time between two consecutive consensuses (in hours)
+    #[clap(long, default_value_t = 1)]
+    interval_hours: i64,
+    /// JSON file with the churn model to use. Rates not given in the file
+    /// and not overridden by the options below keep their defaults.
+    #[clap(long)]
+    churn_model: Option<String>,
+    /// Share of relays joining the network per hour
+    #[clap(long, parse(try_from_str = parse_rate))]
+    join_rate: Option<f64>,
+    /// Probability of a relay to leave the network per hour [0...1]
+    #[clap(long, parse(try_from_str = parse_probability))]
+    leave_rate: Option<f64>,
+    /// Standard deviation of the hourly change of the logarithm of each
+    /// relay's bandwidth
+    #[clap(long)]
+    bandwidth_noise: Option<f64>,
+    /// Directory to save the CollecTor tree with the series to. Needs to be
+    /// empty.
+    #[clap(long, short)]
+    output_dir: String,
+    /// Sign the generated consensuses with directory authorities whose keys
+    /// are stored in this directory. If it does not contain any authorities
+    /// yet, generate new ones and store them there.
+    #[clap(long)]
+    authority_keys: Option<String>,
+    /// Number of directory authorities to generate
+    #[clap(long, default_value_t = 3, requires = "authority-keys")]
+    num_authorities: usize,
+    /// IP address of the generated directory authorities
+    #[clap(long, default_value = "127.0.0.1", requires = "authority-keys")]
+    authority_address: Ipv4Addr,
+    /// Do not parse the written consensuses and descriptors again to check
+    /// that they match the generated consensuses
+    #[clap(long)]
+    no_validate_output: bool,
+}
+
+/// Parse a non-negative rate
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if rate.is_finite() && rate >= 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("{} is not a non-negative rate", s))
+    }
+}
+
+/// Parse a probability between 0 and 1
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let prob: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&prob) {
+        Ok(prob)
+    } else {
+        Err(format!("{} is not between 0 and 1", s))
+    }
+}
+
+impl SeriesArgs {
+    /// The churn model from the given file (or the default one), with the
+    /// rates given on the command line applied on top
+    fn churn_model(&self) -> Result<ChurnModel, Box<dyn std::error::Error + Sync + Send>> {
+        let mut model: ChurnModel = match self.churn_model {
+            Some(ref path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            None => ChurnModel::default(),
+        };
+        if let Some(rate) = self.join_rate {
+            model.join_rate = rate;
+        }
+        if let Some(rate) = self.leave_rate {
+            model.leave_rate = rate;
+        }
+        if let Some(noise) = self.bandwidth_noise {
+            model.bandwidth_noise = noise;
+        }
+        Ok(model)
+    }
+}
+
+pub(crate) fn command_series(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let cli_series = if let Command::Series(x) = cli.command {
+        x
+    } else {
+        panic!("wrong command");
+    };
+
+    if cli_series.interval_hours < 1 {
+        return Err("--interval-hours needs to be at least 1".into());
+    }
+    let model = cli_series.churn_model()?;
+
+    let asn_db = AsnDb::new(&cli_series.asn_db)?;
+    let mut consensus = load_consensus(
+        &cli_series.consensus,
+        cli_series.descriptors.as_deref(),
+        &asn_db,
+    )?;
+
+    if let Some(scale) = cli_series.horz {
+        scale_horizontally(
+            &mut consensus,
+            scale,
+            None,
+            None,
+            &asn_db,
+            cli_series
+                .prob_family_new
+                .expect("--prob-family-new needs to be specified"),
+        )?;
+        consensus.print_stats();
+    }
+
+    let mut options = OutputOptions::default();
+    if let Some(ref key_dir) = cli_series.authority_keys {
+        options.authorities = Some(load_or_generate_authorities(
+            key_dir,
+            cli_series.num_authorities,
+            cli_series.authority_address,
+            &consensus,
+        )?);
+    }
+
+    let mut series = SeriesGenerator::new(
+        consensus,
+        model,
+        Duration::hours(cli_series.interval_hours),
+        &asn_db,
+    )?;
+
+    // the base consensus starts the tree, so only check the output
+    // directory for it
+    let written = save_to_tordata_dir(series.current(), &cli_series.output_dir, &options)?;
+    if !cli_series.no_validate_output {
+        validate(series.current(), &written, &asn_db)?;
+    }
+
+    for i in 0..cli_series.count {
+        let consensus = series.step();
+        let written = save_to_collector_tree(consensus, &cli_series.output_dir, &options)?;
+        if !cli_series.no_validate_output {
+            validate(consensus, &written, &asn_db)?;
+        }
+        println!(
+            "Consensus {}/{}: valid after {}, {} relays",
+            i + 1,
+            cli_series.count,
+            consensus.valid_after,
+            consensus.relays.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Check the written documents, printing all discrepancies
+fn validate(
+    consensus: &Consensus,
+    written: &WrittenDocuments,
+    asn_db: &AsnDb,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    if let Err(e) = validate_written_documents(consensus, written, asn_db) {
+        if let RoundTripError::Discrepancies(ref discrepancies) = e {
+            for discrepancy in discrepancies {
+                eprintln!("{}", discrepancy);
+            }
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
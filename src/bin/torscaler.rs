@@ -1,20 +1,44 @@
 use highlevel::{
-    cutoff_lower_and_redistribute, scale_flag_groups_vertically, scale_horizontally,
-    scale_vertically_by_bandwidth_rank,
+    cutoff_lower_and_redistribute, downsample, scale_capacity, scale_flag_groups_vertically,
+    scale_horizontally, scale_vertically_by_bandwidth_rank, DiversityMetrics, DownsampleOptions,
 };
 use torscaler::highlevel;
 // mod parser;
 
 mod history;
+mod inspect;
+mod manifest;
+mod paths;
+mod series;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::path::Path;
 
+use highlevel::adversary::{as_exposure, compare_exposure, AsExposureComparison};
 use highlevel::asn::AsnDb;
+use highlevel::authority::{AuthorityError, AuthoritySet};
+use highlevel::compare::{compare, ConsensusProfile, SimilarityReport};
+use highlevel::estimate::estimate_prob_family_new;
+use highlevel::geoip::GeoIpDb;
+use highlevel::keys::RelayKeyStore;
+use highlevel::output::{ChutneyOptions, OutputOptions};
+use highlevel::roundtrip::{validate_written_documents, RoundTripError};
+use manifest::Manifest;
+
+use chrono::Duration;
 
 use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 use tordoc;
 
+/// File name of the report on the preserved distributions when downsampling
+const DOWNSAMPLE_REPORT_FILE: &str = "downsample-report.json";
+/// File names of the AS exposure before and after scaling
+const AS_EXPOSURE_JSON_FILE: &str = "as-exposure.json";
+const AS_EXPOSURE_CSV_FILE: &str = "as-exposure.csv";
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
@@ -22,6 +46,10 @@ struct Cli {
     /// a random seed.
     #[clap(long, default_value_t = 0)]
     seed: u64,
+    /// Number of threads to use for parsing documents. If 0 or omitted, use
+    /// one thread per CPU core.
+    #[clap(long, default_value_t = 0)]
+    threads: usize,
     /// Command to execute
     #[clap(subcommand)]
     command: Command,
@@ -31,9 +59,18 @@ struct Cli {
 enum Command {
     Scale(ScaleArgs),
     History(history::HistoryArgs),
+    /// Repeat a scaling run from the manifest in its output directory, saving
+    /// the output to a new directory
+    Replay(manifest::ReplayArgs),
+    /// Generate a series of consecutive consensuses with relay churn
+    Series(series::SeriesArgs),
+    /// Summarize a single consensus
+    Inspect(inspect::InspectArgs),
+    /// Simulate circuit paths and how often adversaries compromise them
+    Paths(paths::PathsArgs),
 }
 
-#[derive(Args)]
+#[derive(Args, Clone, Serialize, Deserialize)]
 struct ScaleArgs {
     /// Input consensus to sample from.
     #[clap(long)]
@@ -48,6 +85,10 @@ struct ScaleArgs {
     /// Verify that the bandwidth weights are correct
     #[clap(long)]
     verify_weights: bool,
+    /// When verifying the bandwidth weights, allow each weight to deviate by
+    /// this value
+    #[clap(long, default_value_t = 0, requires = "verify-weights")]
+    weights_tolerance: u64,
     /// Directory to save the generated consensus to.
     #[clap(long, short)]
     output_dir: Option<String>,
@@ -55,8 +96,59 @@ struct ScaleArgs {
     /// format from CollecTor
     #[clap(long)]
     output_collector: bool,
-    /// Scale the consensus horizontally by this factor
-    #[clap(long, requires = "prob-family-new")]
+    /// Consensus method to state in the generated consensus. Defaults to the
+    /// method of the input consensus.
+    #[clap(long)]
+    consensus_method: Option<u32>,
+    /// Do not parse the written consensus and descriptors again to check
+    /// that they match the generated consensus
+    #[clap(long)]
+    no_validate_output: bool,
+    /// Additionally save a relay info staging file for tornettools to the
+    /// output directory, for generating Shadow networks
+    #[clap(long)]
+    output_tornettools: bool,
+    /// Tor GeoIP file to look up the relays' countries in for the
+    /// tornettools staging file
+    #[clap(long, requires = "output-tornettools")]
+    geoip: Option<String>,
+    /// Save the consensus and descriptors as the cache files of a Tor
+    /// DataDirectory to this directory
+    #[clap(long, requires_all = &["relay-keys", "output-dir"])]
+    output_tor_cache: Option<String>,
+    /// Save a chutney network file and torrc templates resembling the
+    /// generated consensus to this directory
+    #[clap(long)]
+    output_chutney: Option<String>,
+    /// Downscale the chutney network to this number of relays
+    #[clap(long, requires = "output-chutney")]
+    chutney_relays: Option<usize>,
+    /// Total bandwidth (in bytes/s) to distribute among the chutney relays
+    #[clap(long, default_value_t = 100 * 1024 * 1024, requires = "output-chutney")]
+    chutney_bandwidth: u64,
+    /// Sign the generated consensus with directory authorities whose keys are
+    /// stored in this directory. If it does not contain any authorities yet,
+    /// generate new ones and store them there.
+    #[clap(long)]
+    authority_keys: Option<String>,
+    /// Number of directory authorities to generate
+    #[clap(long, default_value_t = 3, requires = "authority-keys")]
+    num_authorities: usize,
+    /// IP address of the generated directory authorities
+    #[clap(long, default_value = "127.0.0.1", requires = "authority-keys")]
+    authority_address: Ipv4Addr,
+    /// Generate key material for all relays and sign their descriptors with
+    /// it. This assigns new fingerprints to all relays.
+    #[clap(long)]
+    relay_keys: bool,
+    /// Save the generated relay keys as Tor data directories (one per
+    /// relay, named by fingerprint) to this directory
+    #[clap(long, requires = "relay-keys")]
+    relay_data_dir: Option<String>,
+    /// Scale the consensus horizontally by this factor. Factors below 1 remove
+    /// relays. Needs either --prob-family-new or
+    /// --prob-family-new-from.
+    #[clap(long)]
     horz: Option<f32>,
     /// when scaling the consensus horizontally, apply this factor to exits
     #[clap(long, requires = "horz")]
@@ -68,6 +160,29 @@ struct ScaleArgs {
     /// creating new ones [0...1] (0 = only existing, 1 = only new)
     #[clap(long, requires = "horz")]
     prob_family_new: Option<f32>,
+    /// when scaling the consensus horizontally, estimate the probability for
+    /// creating new families by comparing the input consensus to this earlier
+    /// consensus (with descriptors relative to it)
+    #[clap(long, requires = "horz", conflicts_with = "prob-family-new")]
+    prob_family_new_from: Option<String>,
+    /// Downsample the consensus to this fraction of its relays (0...1] by
+    /// stratified sampling, preserving the distributions of bandwidth,
+    /// position classes and family sizes
+    #[clap(long, conflicts_with = "horz")]
+    downsample: Option<f32>,
+    /// when downsampling, number of strata by bandwidth rank within each
+    /// position class
+    #[clap(long, default_value_t = 10, requires = "downsample")]
+    downsample_strata: usize,
+    /// when downsampling, trim each family to the given fraction of its
+    /// members instead of keeping or removing families as a whole
+    #[clap(long, requires = "downsample")]
+    downsample_trim_families: bool,
+    /// when downsampling, scale the bandwidth of the remaining relays so that
+    /// the total capacity is this multiple of the original one (e.g. 1 to
+    /// keep the capacity)
+    #[clap(long, requires = "downsample")]
+    downsample_capacity: Option<f32>,
     /// Scale each relay's bandwidth in the network by this factor. This can
     /// also be a comma-separated list of float values. In this case, this
     /// defines different scale factors for relays of different bandwidth rank.
@@ -93,44 +208,33 @@ struct ScaleArgs {
     /// plainly by ignoring the respective descriptors if they are observed.
     #[clap(long)]
     remove_idle_relays: bool,
+    /// Analyze the shares of the guard and exit positions that each AS
+    /// controls, and how they change by scaling. If an output directory is
+    /// given, the results are saved to it.
+    #[clap(long)]
+    as_exposure: bool,
+    /// Largest coalition of ASes to report the shares of
+    #[clap(long, default_value_t = 10, requires = "as-exposure")]
+    as_coalition_size: usize,
 }
 
-fn command_scale(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+fn command_scale(cli: Cli, seed: u64) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let cli_scale = if let Command::Scale(x) = cli.command {
         x
     } else {
         panic!("wrong command");
     };
 
-    let asn_db = AsnDb::new(&cli_scale.asn_db)?;
+    let mut manifest = Manifest::new(seed, &cli_scale)?;
 
-    let consensus: highlevel::UnpackedConsensus = {
-        let mut raw = String::new();
-        let mut file = File::open(&cli_scale.consensus).unwrap();
-        file.read_to_string(&mut raw).unwrap();
-        let raw_consensus = tordoc::Consensus::from_str(&raw)?;
-        raw_consensus.try_into()?
-    };
-
-    let descriptors = match cli_scale.descriptors {
-        Some(ref desc_path) => {
-            // Descriptors are given as a file
-            let mut raw = String::new();
-            let mut file = File::open(desc_path).unwrap();
-            file.read_to_string(&mut raw).unwrap();
-            tordoc::Descriptor::many_from_str(&raw)?
-        }
-        None => {
-            // Load descriptors from files relative to the consensus file
-            highlevel::lookup_descriptors(&consensus, cli_scale.consensus)?
-        }
-    };
-
-    // println!("{:?}", descriptors);
-    let consensus = highlevel::Consensus::combine_documents(consensus, descriptors, &asn_db);
-    // println!("{:?}", consensus);
+    let asn_db = AsnDb::new(&cli_scale.asn_db)?;
 
-    let mut consensus = consensus?;
+    let mut consensus = load_consensus(
+        &cli_scale.consensus,
+        cli_scale.descriptors.as_deref(),
+        &asn_db,
+    )?;
+    manifest.record_step("load", &consensus);
 
     if cli_scale.remove_idle_relays {
         let mut removed = 0;
@@ -142,35 +246,82 @@ fn command_scale(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send
             }
             remove
         });
-        println!("Removed {removed} relays that have an observed bandwidth of zero...")
+        println!("Removed {removed} relays that have an observed bandwidth of zero...");
+        manifest.record_step("remove idle relays", &consensus);
     }
 
+    let original_diversity = consensus.diversity_metrics();
+    let original_exposure = if cli_scale.as_exposure {
+        Some(as_exposure(&consensus, cli_scale.as_coalition_size))
+    } else {
+        None
+    };
+
     if cli_scale.verify_weights {
         println!("verifying bw weights...");
-        match consensus.verify_weights() {
-            Ok(_) => {
-                println!("bw weights match.");
-            }
-            Err(s) => {
-                println!("bw weights do not match:");
-                println!("{}", s);
-            }
-        }
+        let verification = consensus.verify_weights(cli_scale.weights_tolerance);
+        println!("{}", verification);
     }
 
     if let Some(scale) = cli_scale.horz {
+        let prob_family_new = match (cli_scale.prob_family_new, &cli_scale.prob_family_new_from) {
+            (Some(prob), _) => prob,
+            (None, Some(earlier_path)) => {
+                let earlier = load_consensus(earlier_path, None, &asn_db)?;
+                let estimate = estimate_prob_family_new(&earlier, &consensus)
+                    .ok_or("cannot estimate prob_family_new: no new relays in families")?;
+                println!(
+                    "Estimated prob_family_new: {:.3} (95% CI {:.3}..{:.3})",
+                    estimate.prob_family_new, estimate.ci_lower, estimate.ci_upper
+                );
+                estimate.prob_family_new as f32
+            }
+            (None, None) => {
+                return Err(
+                    "--horz needs either --prob-family-new or --prob-family-new-from".into(),
+                )
+            }
+        };
         scale_horizontally(
             &mut consensus,
             scale,
             cli_scale.horz_exit_factor,
             cli_scale.horz_guard_factor,
             &asn_db,
-            cli_scale
-                .prob_family_new
-                .expect("--prob-family-new needs to be specified"),
-        );
+            prob_family_new,
+        )?;
         consensus.print_stats();
+        manifest.record_step("scale horizontally", &consensus);
     }
+    let downsample_report = match cli_scale.downsample {
+        Some(fraction) => {
+            let before = ConsensusProfile::new(&consensus);
+            let total_bandwidth_before: u64 =
+                consensus.relays.values().map(|r| r.bandwidth_weight).sum();
+            let options = DownsampleOptions {
+                bandwidth_strata: cli_scale.downsample_strata,
+                trim_families: cli_scale.downsample_trim_families,
+            };
+            downsample(&mut consensus, fraction, &options);
+
+            // compare before restoring the capacity, so that the report
+            // reflects the sampling only
+            let report = compare(&before, &ConsensusProfile::new(&consensus));
+
+            if let Some(capacity_factor) = cli_scale.downsample_capacity {
+                scale_capacity(
+                    &mut consensus,
+                    (capacity_factor as f64 * total_bandwidth_before as f64) as u64,
+                );
+            }
+            consensus.print_stats();
+            manifest.record_step("downsample", &consensus);
+
+            print_preservation(&report);
+            Some(report)
+        }
+        None => None,
+    };
     if let Some(raw) = cli_scale.scale_vert_by_bw_quantiles {
         if let Some(cutoff) = cli_scale.scale_vert_cutoff_lower {
             // consensus.print_stats();
@@ -181,6 +332,7 @@ fn command_scale(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send
         let scales: Vec<f32> = raw.split(',').map(|x| x.parse().unwrap()).collect();
         scale_vertically_by_bandwidth_rank(&mut consensus, scales);
         consensus.print_stats();
+        manifest.record_step("scale vertically by bandwidth rank", &consensus);
     } else if cli_scale.vert_middle_scale.is_some()
         || cli_scale.vert_exit_scale.is_some()
         || cli_scale.vert_guard_scale.is_some()
@@ -192,23 +344,298 @@ fn command_scale(cli: Cli) -> Result<(), Box<dyn std::error::Error + Sync + Send
             cli_scale.vert_guard_scale.unwrap_or(1.0),
         );
         consensus.print_stats();
+        manifest.record_step("scale flag groups vertically", &consensus);
+    }
+
+    print_diversity(&original_diversity, &consensus.diversity_metrics());
+
+    let exposure = original_exposure.map(|original| {
+        let comparison = compare_exposure(
+            original,
+            as_exposure(&consensus, cli_scale.as_coalition_size),
+        );
+        print_exposure(&comparison);
+        comparison
+    });
+
+    if let Some(method) = cli_scale.consensus_method {
+        consensus.header.consensus_method = method;
+    }
+
+    let relay_keys = if cli_scale.relay_keys {
+        println!("Generating keys for {} relays...", consensus.relays.len());
+        let relay_keys = RelayKeyStore::generate_for(&mut consensus)?;
+        if let Some(ref data_dir) = cli_scale.relay_data_dir {
+            relay_keys.save_to_tor_data_dirs(&consensus, data_dir)?;
+        }
+        manifest.record_step("generate relay keys", &consensus);
+        Some(relay_keys)
+    } else {
+        None
+    };
+
+    if let Some(ref chutney_dir) = cli_scale.output_chutney {
+        let options = ChutneyOptions {
+            num_relays: cli_scale.chutney_relays,
+            total_bandwidth: cli_scale.chutney_bandwidth,
+            ..Default::default()
+        };
+        highlevel::output::save_chutney_network(&consensus, chutney_dir, &options)?;
     }
 
     if let Some(output_dir) = cli_scale.output_dir {
-        if cli_scale.output_collector {
-            highlevel::output::save_to_tordata_dir(&consensus, &output_dir)?;
+        let mut options = OutputOptions::default();
+        options.relay_keys = relay_keys;
+
+        if let Some(ref key_dir) = cli_scale.authority_keys {
+            options.authorities = Some(load_or_generate_authorities(
+                key_dir,
+                cli_scale.num_authorities,
+                cli_scale.authority_address,
+                &consensus,
+            )?);
+        }
+
+        let written = if cli_scale.output_collector {
+            highlevel::output::save_to_tordata_dir(&consensus, &output_dir, &options)?
         } else {
-            highlevel::output::save_to_dir(&consensus, &output_dir)?;
+            highlevel::output::save_to_dir(&consensus, &output_dir, &options)?
+        };
+
+        if !cli_scale.no_validate_output {
+            if let Err(e) = validate_written_documents(&consensus, &written, &asn_db) {
+                if let RoundTripError::Discrepancies(ref discrepancies) = e {
+                    for discrepancy in discrepancies {
+                        eprintln!("{}", discrepancy);
+                    }
+                }
+                return Err(e.into());
+            }
+            println!("Validated the written documents");
+        }
+
+        if let Some(ref cache_dir) = cli_scale.output_tor_cache {
+            highlevel::output::save_to_tor_cache_dir(&consensus, cache_dir, &options)?;
+        }
+
+        if cli_scale.output_tornettools {
+            let geoip = match cli_scale.geoip {
+                Some(ref path) => Some(GeoIpDb::new(path)?),
+                None => None,
+            };
+            let path = highlevel::output::save_tornettools_staging(
+                &consensus,
+                &output_dir,
+                geoip.as_ref(),
+            )?;
+            println!("Saved tornettools staging file to {}", path.display());
+        }
+
+        if let Some(ref exposure) = exposure {
+            save_exposure(exposure, &output_dir)?;
         }
+
+        if let Some(ref report) = downsample_report {
+            fs::write(
+                Path::new(&output_dir).join(DOWNSAMPLE_REPORT_FILE),
+                serde_json::to_string_pretty(report)?,
+            )?;
+        }
+
+        manifest.save(&output_dir)?;
     }
 
     Ok(())
 }
 
+/// Print how well the distributions of a consensus were preserved by
+/// downsampling
+fn print_preservation(report: &SimilarityReport) {
+    let optional = |x: Option<f64>| x.map_or("-".to_string(), |x| format!("{:.4}", x));
+    println!("Preservation of the distributions by downsampling:");
+    println!(
+        "- bandwidth:        KS {}, relative EMD {}",
+        optional(report.bandwidth.ks_statistic),
+        optional(report.bandwidth.relative_earth_movers_distance)
+    );
+    println!(
+        "- position classes: max. difference {:.4} (relays), {:.4} (bandwidth)",
+        report.class_relay_shares.max_difference, report.class_bandwidth_shares.max_difference
+    );
+    println!(
+        "- family sizes:     total variation {}",
+        optional(report.family_sizes.total_variation)
+    );
+    println!(
+        "- ASes:             total variation {}, top 10 share {} -> {}",
+        optional(report.ases.total_variation),
+        optional(report.ases.reference_top10_share),
+        optional(report.ases.candidate_top10_share)
+    );
+}
+
+/// Print the diversity of the positions before and after scaling
+fn print_diversity(original: &DiversityMetrics, scaled: &DiversityMetrics) {
+    let optional = |x: Option<f64>| x.map_or("-".to_string(), |x| format!("{:.3}", x));
+    let count = |x: Option<usize>| x.map_or("-".to_string(), |x| x.to_string());
+    println!("Diversity (original -> scaled):");
+    for (position, original, scaled) in [
+        ("guard", &original.guard, &scaled.guard),
+        ("middle", &original.middle, &scaled.middle),
+        ("exit", &original.exit, &scaled.exit),
+    ] {
+        println!(
+            "- {:6} entropy {} -> {} bits (normalized {} -> {}), \
+             50% of the weight in {} -> {} relays, {} -> {} ASes, {} -> {} families",
+            position,
+            optional(original.entropy),
+            optional(scaled.entropy),
+            optional(original.normalized_entropy),
+            optional(scaled.normalized_entropy),
+            count(original.relays_for_half),
+            count(scaled.relays_for_half),
+            count(original.ases_for_half),
+            count(scaled.ases_for_half),
+            count(original.families_for_half),
+            count(scaled.families_for_half)
+        );
+    }
+    println!(
+        "- Gini coefficient of the bandwidth: {} -> {}",
+        optional(original.bandwidth_gini),
+        optional(scaled.bandwidth_gini)
+    );
+}
+
+/// Print the AS exposure of the largest ASes and coalitions before and after
+/// scaling
+fn print_exposure(comparison: &AsExposureComparison) {
+    println!("AS exposure (original -> scaled):");
+    for change in comparison.changes.iter().take(10) {
+        println!(
+            "- AS{:<7} guard {:.4} -> {:.4}, exit {:.4} -> {:.4}, end-to-end {:.6} -> {:.6} ({})",
+            change.asn,
+            change.guard_original,
+            change.guard_scaled,
+            change.exit_original,
+            change.exit_scaled,
+            change.end_to_end_original,
+            change.end_to_end_scaled,
+            change.name
+        );
+    }
+    println!("Coalitions maximizing the end-to-end share (original -> scaled):");
+    for (original, scaled) in std::iter::zip(
+        comparison.original.coalitions.iter(),
+        comparison.scaled.coalitions.iter(),
+    ) {
+        println!(
+            "- {:3} ASes: guard {:.4} -> {:.4}, exit {:.4} -> {:.4}, end-to-end {:.4} -> {:.4}",
+            original.size,
+            original.guard,
+            scaled.guard,
+            original.exit,
+            scaled.exit,
+            original.end_to_end,
+            scaled.end_to_end
+        );
+    }
+}
+
+/// Save the AS exposure as JSON and the changes per AS as CSV
+fn save_exposure(
+    comparison: &AsExposureComparison,
+    output_dir: &str,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let output_dir = Path::new(output_dir);
+    fs::write(
+        output_dir.join(AS_EXPOSURE_JSON_FILE),
+        serde_json::to_string_pretty(comparison)?,
+    )?;
+
+    let mut wtr = csv::Writer::from_path(output_dir.join(AS_EXPOSURE_CSV_FILE))?;
+    for change in comparison.changes.iter() {
+        wtr.serialize(change)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Load a consensus and combine it with its descriptors, which are either
+/// given as a file or looked up relative to the consensus file
+fn load_consensus(
+    consensus_path: &str,
+    descriptors_path: Option<&str>,
+    asn_db: &AsnDb,
+) -> Result<highlevel::Consensus, Box<dyn std::error::Error + Sync + Send>> {
+    let consensus: highlevel::UnpackedConsensus = {
+        let mut raw = String::new();
+        let mut file = File::open(consensus_path).unwrap();
+        file.read_to_string(&mut raw).unwrap();
+        raw.parse::<highlevel::UnpackedConsensus>()?
+    };
+
+    let descriptors = match descriptors_path {
+        Some(desc_path) => {
+            // Descriptors are given as a file
+            let mut raw = String::new();
+            let mut file = File::open(desc_path).unwrap();
+            file.read_to_string(&mut raw).unwrap();
+            tordoc::Descriptor::many_from_str(&raw)?
+        }
+        None => {
+            // Load descriptors from files relative to the consensus file
+            highlevel::lookup_descriptors(&consensus, consensus_path)?
+        }
+    };
+
+    // println!("{:?}", descriptors);
+    let consensus = highlevel::Consensus::combine_documents(consensus, descriptors, asn_db);
+    // println!("{:?}", consensus);
+
+    consensus
+}
+
+/// Load the directory authorities from a directory, or generate new ones if
+/// there are none yet
+fn load_or_generate_authorities(
+    key_dir: &str,
+    num_authorities: usize,
+    address: Ipv4Addr,
+    consensus: &highlevel::Consensus,
+) -> Result<AuthoritySet, Box<dyn std::error::Error + Sync + Send>> {
+    match AuthoritySet::load(key_dir) {
+        Ok(authorities) => {
+            println!(
+                "Loaded {} directory authorities from {}",
+                authorities.authorities().len(),
+                key_dir
+            );
+            Ok(authorities)
+        }
+        Err(AuthorityError::NoAuthorities(_)) => {
+            println!(
+                "Generating {} directory authorities in {}...",
+                num_authorities, key_dir
+            );
+            let authorities = AuthoritySet::generate(
+                num_authorities,
+                address,
+                5000,
+                7000,
+                consensus.valid_after - Duration::days(1),
+            )?;
+            authorities.save(key_dir)?;
+            Ok(authorities)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let cli = Cli::parse();
 
-    seeded_rand::set_seed(if cli.seed == 0 {
+    let seed = if cli.seed == 0 {
         let new_seed = seeded_rand::generate_random_seed();
         println!(
             "No seed was given. Call with \"--seed {}\" to reproduce this run.",
@@ -217,10 +644,21 @@ fn main() -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
         new_seed
     } else {
         cli.seed
-    });
+    };
+    seeded_rand::set_seed(seed);
+
+    if cli.threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(cli.threads)
+            .build_global()?;
+    }
 
     match cli.command {
-        Command::Scale(_) => command_scale(cli),
+        Command::Scale(_) => command_scale(cli, seed),
         Command::History(_) => history::command_history(cli),
+        Command::Replay(_) => manifest::command_replay(cli),
+        Command::Series(_) => series::command_series(cli),
+        Command::Inspect(_) => inspect::command_inspect(cli),
+        Command::Paths(_) => paths::command_paths(cli),
     }
 }
@@ -1,20 +1,29 @@
 //! Tool to validate the model behavior of torscaler.
 //!
 //! Given two consensuses A and B, scale A to a new consensus A',
-//! and compare A' to B.
+//! and compare A' to B. In backtesting mode, do this for many pairs of
+//! consensuses from a CollecTor tree and aggregate the results.
 
 use std::fs::File;
 use std::io::prelude::*;
 
+use std::collections::BTreeMap;
+
+use torscaler::highlevel::collector::{
+    find_consensuses, sample_consensuses, DEFAULT_CONSENSUS_PATTERN,
+};
+use torscaler::highlevel::compare::{compare, ConsensusProfile, SimilarityReport};
+use torscaler::highlevel::estimate::{estimate_prob_family_new, FamilyNewEstimate};
+use torscaler::highlevel::stats::{mean, quantile};
 use torscaler::highlevel::{self, asn::AsnDb, Consensus};
 
 use anyhow;
 use anyhow::Context;
+use chrono::Duration;
 use clap::Parser;
 use csv;
 use seeded_rand;
 use serde::Serialize;
-use tordoc;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -24,17 +33,34 @@ struct Cli {
     #[clap(long, default_value_t = 0)]
     seed: u64,
     /// First (earlier) consensus (file path).
-    #[clap(long)]
-    first_consensus: String,
+    #[clap(long, required_unless_present = "backtest")]
+    first_consensus: Option<String>,
     /// Second (later) consensus (file path).
-    #[clap(long)]
-    second_consensus: String,
+    #[clap(long, required_unless_present = "backtest")]
+    second_consensus: Option<String>,
+    /// Validate many pairs of consensuses from this CollecTor tree instead of
+    /// a single pair
+    #[clap(long, conflicts_with_all = &["first-consensus", "second-consensus", "output"])]
+    backtest: Option<String>,
+    /// Time between the consensuses of a pair when backtesting (in days). Can
+    /// be given multiple times.
+    #[clap(long = "horizon-days", default_value = "365", requires = "backtest")]
+    horizons_days: Vec<i64>,
+    /// Time between the first consensuses of two pairs when backtesting (in
+    /// days)
+    #[clap(long, default_value_t = 30, requires = "backtest")]
+    sample_days: i64,
     /// AS IP ranges database CSV file
     #[clap(long)]
     asn_db: String,
     /// Output CSV file of the resulting consensuses
     #[clap(long, short)]
     output: Option<String>,
+    /// Output JSON file for the similarity metrics between the scaled and the
+    /// second consensus (or the aggregated metrics when backtesting). If not
+    /// given, print them.
+    #[clap(long)]
+    report: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -54,54 +80,25 @@ fn main() -> anyhow::Result<()> {
     // load AS database
     let asn_db = AsnDb::new(&cli.asn_db)?;
 
-    let mut first_consensus =
-        load_consensus(&cli.first_consensus, &asn_db).context(cli.first_consensus.to_string())?;
-    let second_consensus =
-        load_consensus(&cli.second_consensus, &asn_db).context(cli.second_consensus.to_string())?;
-
-    let growth_h = second_consensus.relays.len() as f64 / first_consensus.relays.len() as f64;
-    let growth_v = (second_consensus
-        .relays
-        .values()
-        .map(|r| r.bandwidth_weight)
-        .sum::<u64>() as f64
-        / second_consensus.relays.len() as f64)
-        / (first_consensus
-            .relays
-            .values()
-            .map(|r| r.bandwidth_weight)
-            .sum::<u64>() as f64
-            / first_consensus.relays.len() as f64);
-
-    dbg!(growth_h);
-    dbg!(growth_v);
+    if let Some(ref tree) = cli.backtest {
+        let report = backtest(tree, &cli.horizons_days, cli.sample_days, &asn_db)?;
+        save_report(&report, cli.report.as_deref())?;
+        println!("Done.");
+        return Ok(());
+    }
 
-    // // avoid double scaling
-    // let growth_v = growth_v / growth_h;
+    let first_path = cli.first_consensus.unwrap();
+    let second_path = cli.second_consensus.unwrap();
+    let mut first_consensus = load_consensus(&first_path, &asn_db).context(first_path)?;
+    let second_consensus = load_consensus(&second_path, &asn_db).context(second_path)?;
 
-    assert!(growth_h >= 1.0);
-    assert!(growth_v >= 1.0);
+    scale_to_match(&mut first_consensus, &second_consensus, &asn_db)?;
 
-    // Scale the first consensus accordingly
-
-    println!("Scaling the consensus vertically by factor {}...", growth_v);
-    highlevel::scale_vertically_by_bandwidth_rank(&mut first_consensus, vec![growth_v as f32]);
-    println!(
-        "Scaling the consensus horizontally by factor {}...",
-        growth_h
-    );
-    highlevel::scale_horizontally(
-        &mut first_consensus,
-        growth_h as f32,
-        None,
-        None,
-        &asn_db,
-        0.5, // TODO P_new_family
+    let report = compare(
+        &ConsensusProfile::new(&second_consensus),
+        &ConsensusProfile::new(&first_consensus),
     );
-
-    // first_consensus is now scaled and ready for comparison with second_consensus
-
-    assert_eq!(first_consensus.relays.len(), second_consensus.relays.len());
+    save_report(&report, cli.report.as_deref())?;
 
     if let Some(out_file) = cli.output {
         println!("Saving result to file...");
@@ -144,26 +141,320 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-// fn determine_p_family(first: &Consensus, second: &Consensus) -> f64 {
-//     // number of relays
-//     let relays_old = first.relays.len();
-//     let relays_new = second.relays.len();
-//     // number of families
-//     let families_old = first.families.len();
-//     let families_new = second.families.len();
+/// Parameters used for scaling the first consensus of a pair
+#[derive(Serialize)]
+struct ScalingParameters {
+    growth_h: f64,
+    growth_v: f64,
+    /// Estimate of `prob_family_new` from the pair. If there is none, 0.5 is
+    /// used.
+    family_new: Option<FamilyNewEstimate>,
+}
+
+/// Probability for new families used if it cannot be estimated
+const DEFAULT_PROB_FAMILY_NEW: f32 = 0.5;
+
+/// Scale the first consensus so that it matches the number of relays and the
+/// average bandwidth of the second one, with the probability for new families
+/// estimated from both. Fails if the scaled consensus does not end up with
+/// the same number of relays as the second one.
+fn scale_to_match(
+    first: &mut Consensus,
+    second: &Consensus,
+    asn_db: &AsnDb,
+) -> anyhow::Result<ScalingParameters> {
+    let growth_h = second.relays.len() as f64 / first.relays.len() as f64;
+    let growth_v = (second
+        .relays
+        .values()
+        .map(|r| r.bandwidth_weight)
+        .sum::<u64>() as f64
+        / second.relays.len() as f64)
+        / (first
+            .relays
+            .values()
+            .map(|r| r.bandwidth_weight)
+            .sum::<u64>() as f64
+            / first.relays.len() as f64);
+
+    let family_new = estimate_prob_family_new(first, second);
+    let prob_family_new = match family_new {
+        Some(ref estimate) => {
+            println!(
+                "Estimated prob_family_new: {:.3} (95% CI {:.3}..{:.3})",
+                estimate.prob_family_new, estimate.ci_lower, estimate.ci_upper
+            );
+            estimate.prob_family_new as f32
+        }
+        None => {
+            println!(
+                "Cannot estimate prob_family_new, using {}",
+                DEFAULT_PROB_FAMILY_NEW
+            );
+            DEFAULT_PROB_FAMILY_NEW
+        }
+    };
+
+    // // avoid double scaling
+    // let growth_v = growth_v / growth_h;
+
+    // Scale the first consensus accordingly
+
+    println!("Scaling the consensus vertically by factor {}...", growth_v);
+    highlevel::scale_vertically_by_bandwidth_rank(first, vec![growth_v as f32]);
+    println!(
+        "Scaling the consensus horizontally by factor {}...",
+        growth_h
+    );
+    highlevel::scale_horizontally(first, growth_h as f32, None, None, asn_db, prob_family_new)?;
+
+    // first is now scaled and ready for comparison with second
+
+    if first.relays.len() != second.relays.len() {
+        anyhow::bail!(
+            "scaled consensus has {} relays instead of {}",
+            first.relays.len(),
+            second.relays.len()
+        );
+    }
+
+    Ok(ScalingParameters {
+        growth_h,
+        growth_v,
+        family_new,
+    })
+}
+
+/// Print the report as JSON or save it to a file
+fn save_report<T: Serialize>(report: &T, path: Option<&str>) -> anyhow::Result<()> {
+    let report = serde_json::to_string_pretty(report)?;
+    match path {
+        Some(path) => std::fs::write(path, report).context(path.to_string())?,
+        None => println!("{}", report),
+    }
+    Ok(())
+}
+
+/// The main metrics of a comparison, for aggregating them
+#[derive(Serialize)]
+struct PairMetrics {
+    ks_statistic: Option<f64>,
+    relative_earth_movers_distance: Option<f64>,
+    median_relative_error: Option<f64>,
+    class_relay_share_difference: Option<f64>,
+    class_bandwidth_share_difference: Option<f64>,
+    family_size_total_variation: Option<f64>,
+    as_total_variation: Option<f64>,
+    max_weight_difference: Option<f64>,
+}
+
+impl PairMetrics {
+    fn new(report: &SimilarityReport) -> PairMetrics {
+        PairMetrics {
+            ks_statistic: report.bandwidth.ks_statistic,
+            relative_earth_movers_distance: report.bandwidth.relative_earth_movers_distance,
+            median_relative_error: report
+                .bandwidth
+                .quantiles
+                .iter()
+                .find(|q| q.quantile == 0.5)
+                .map(|q| q.relative_error),
+            class_relay_share_difference: Some(report.class_relay_shares.max_difference),
+            class_bandwidth_share_difference: Some(report.class_bandwidth_shares.max_difference),
+            family_size_total_variation: report.family_sizes.total_variation,
+            as_total_variation: report.ases.total_variation,
+            max_weight_difference: Some(report.max_weight_difference as f64),
+        }
+    }
+
+    fn values(&self) -> [(&'static str, Option<f64>); 8] {
+        [
+            ("ks_statistic", self.ks_statistic),
+            (
+                "relative_earth_movers_distance",
+                self.relative_earth_movers_distance,
+            ),
+            ("median_relative_error", self.median_relative_error),
+            (
+                "class_relay_share_difference",
+                self.class_relay_share_difference,
+            ),
+            (
+                "class_bandwidth_share_difference",
+                self.class_bandwidth_share_difference,
+            ),
+            (
+                "family_size_total_variation",
+                self.family_size_total_variation,
+            ),
+            ("as_total_variation", self.as_total_variation),
+            ("max_weight_difference", self.max_weight_difference),
+        ]
+    }
+}
+
+/// Result of validating one pair of consensuses
+#[derive(Serialize)]
+struct PairResult {
+    horizon_days: i64,
+    first_consensus: String,
+    second_consensus: String,
+    parameters: ScalingParameters,
+    metrics: PairMetrics,
+}
+
+/// Distribution of a metric over all pairs of a horizon
+#[derive(Serialize)]
+struct MetricSummary {
+    pairs: usize,
+    mean: Option<f64>,
+    median: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct HorizonSummary {
+    horizon_days: i64,
+    pairs: usize,
+    metrics: BTreeMap<&'static str, MetricSummary>,
+}
+
+/// A pair of consensuses that could not be validated
+#[derive(Serialize)]
+struct SkippedPair {
+    horizon_days: i64,
+    first_consensus: String,
+    second_consensus: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct BacktestReport {
+    horizons: Vec<HorizonSummary>,
+    pairs: Vec<PairResult>,
+    skipped_pairs: Vec<SkippedPair>,
+}
+
+/// Validate pairs of consensuses from a CollecTor tree, for each horizon
+fn backtest(
+    tree: &str,
+    horizons_days: &[i64],
+    sample_days: i64,
+    asn_db: &AsnDb,
+) -> anyhow::Result<BacktestReport> {
+    let files = find_consensuses(tree, &[DEFAULT_CONSENSUS_PATTERN])?;
+    let last = *files.keys().next_back().unwrap();
+    let first_consensuses = sample_consensuses(files.clone(), Duration::days(sample_days));
+
+    let mut pairs = Vec::new();
+    let mut skipped_pairs = Vec::new();
+    let mut horizons = Vec::new();
+    for &horizon_days in horizons_days {
+        let horizon = Duration::days(horizon_days);
+        let mut metrics: BTreeMap<&'static str, Vec<f64>> = BTreeMap::new();
+        let mut num_pairs = 0;
+
+        for (valid_after, first_path) in first_consensuses.iter() {
+            let target = *valid_after + horizon;
+            if target > last {
+                break;
+            }
+            // use the first consensus at or after the target time, unless
+            // there is a gap in the data
+            let (second_valid_after, second_path) = files.range(target..).next().unwrap();
+            if *second_valid_after - target > Duration::days(1) {
+                eprintln!("[Warning] No consensus close to {}, skipping", target);
+                continue;
+            }
+
+            println!(
+                "Validating {} against {}...",
+                first_path.display(),
+                second_path.display()
+            );
+            let loaded = load_consensus(&first_path.to_string_lossy(), asn_db).and_then(|first| {
+                Ok((
+                    first,
+                    load_consensus(&second_path.to_string_lossy(), asn_db)?,
+                ))
+            });
+            let mut skip = |reason: String| {
+                eprintln!("[Warning] Skipping pair: {}", reason);
+                skipped_pairs.push(SkippedPair {
+                    horizon_days,
+                    first_consensus: first_path.display().to_string(),
+                    second_consensus: second_path.display().to_string(),
+                    reason,
+                });
+            };
+            let (mut first, second) = match loaded {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    skip(format!("{:?}", e));
+                    continue;
+                }
+            };
 
-//     (families_new - families_old) as f64 / (relays_old - relays_new) as f64
-// }
+            let parameters = match scale_to_match(&mut first, &second, asn_db) {
+                Ok(parameters) => parameters,
+                Err(e) => {
+                    skip(e.to_string());
+                    continue;
+                }
+            };
+            let report = compare(
+                &ConsensusProfile::new(&second),
+                &ConsensusProfile::new(&first),
+            );
+            let pair_metrics = PairMetrics::new(&report);
+            for (name, value) in pair_metrics.values() {
+                let values = metrics.entry(name).or_default();
+                values.extend(value);
+            }
+            num_pairs += 1;
+
+            pairs.push(PairResult {
+                horizon_days,
+                first_consensus: first_path.display().to_string(),
+                second_consensus: second_path.display().to_string(),
+                parameters,
+                metrics: pair_metrics,
+            });
+        }
+
+        horizons.push(HorizonSummary {
+            horizon_days,
+            pairs: num_pairs,
+            metrics: metrics
+                .into_iter()
+                .map(|(name, mut values)| {
+                    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    let summary = MetricSummary {
+                        pairs: values.len(),
+                        mean: mean(&values),
+                        median: quantile(&values, 0.5),
+                        max: values.last().copied(),
+                    };
+                    (name, summary)
+                })
+                .collect(),
+        });
+    }
+
+    Ok(BacktestReport {
+        horizons,
+        pairs,
+        skipped_pairs,
+    })
+}
 
 fn load_consensus(path: &str, asn_db: &AsnDb) -> anyhow::Result<Consensus> {
     let consensus: highlevel::UnpackedConsensus = {
         let mut raw = String::new();
         let mut file = File::open(path)?;
         file.read_to_string(&mut raw).unwrap();
-        let raw_consensus = tordoc::Consensus::from_str(&raw)?;
-        raw_consensus
-            .try_into()
-            .map_err(|e: Box<dyn std::error::Error + Send + Sync>| anyhow::anyhow!(e))?
+        raw.parse::<highlevel::UnpackedConsensus>()
+            .map_err(|e| anyhow::anyhow!(e))?
     };
 
     // Load descriptors from files relative to the consensus file
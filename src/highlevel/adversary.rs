@@ -0,0 +1,264 @@
+//! Analysis of how much of the guard and exit positions are controlled by
+//! single ASes or coalitions of ASes.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::{Consensus, PositionProbabilities};
+
+/// Selection probabilities of the relays in an AS
+#[derive(Debug, Clone, Serialize)]
+pub struct AsExposure {
+    pub asn: u32,
+    pub name: String,
+    pub relays: usize,
+    pub guard: f64,
+    pub middle: f64,
+    pub exit: f64,
+    /// Probability that both the guard and the exit of a circuit are in the
+    /// AS, assuming that they are chosen independently
+    pub end_to_end: f64,
+}
+
+/// Share of the positions controlled by a coalition of `size` ASes. The
+/// coalition is chosen greedily to maximize the end-to-end probability, and
+/// all probabilities refer to this coalition.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoalitionShare {
+    pub size: usize,
+    /// Numbers of the ASes in the coalition, in the order they were added
+    pub asns: Vec<u32>,
+    pub guard: f64,
+    pub exit: f64,
+    pub end_to_end: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AsExposureReport {
+    /// All ASes, sorted by descending sum of guard and exit probability
+    pub ases: Vec<AsExposure>,
+    /// Probabilities of the relays without a known AS
+    pub unknown: PositionProbabilities,
+    /// Shares of coalitions of 1 to the maximum number of ASes
+    pub coalitions: Vec<CoalitionShare>,
+}
+
+/// Change of the probabilities of an AS between two consensuses. ASes that
+/// are missing in one of them have a probability of zero there.
+#[derive(Debug, Clone, Serialize)]
+pub struct AsExposureChange {
+    pub asn: u32,
+    pub name: String,
+    pub guard_original: f64,
+    pub guard_scaled: f64,
+    pub exit_original: f64,
+    pub exit_scaled: f64,
+    pub end_to_end_original: f64,
+    pub end_to_end_scaled: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AsExposureComparison {
+    pub original: AsExposureReport,
+    pub scaled: AsExposureReport,
+    /// Changes per AS, sorted by descending absolute change of the sum of
+    /// guard and exit probability
+    pub changes: Vec<AsExposureChange>,
+}
+
+/// Compute the selection probabilities of each AS, and of coalitions of up
+/// to `max_coalition` ASes
+pub fn as_exposure(consensus: &Consensus, max_coalition: usize) -> AsExposureReport {
+    let probabilities = consensus.selection_probabilities();
+
+    let mut ases: BTreeMap<u32, AsExposure> = BTreeMap::new();
+    let mut unknown = PositionProbabilities::default();
+    for (fp, prob) in probabilities.iter() {
+        let relay = &consensus.relays[fp];
+        match relay.asn {
+            Some(ref asn) => {
+                let entry = ases.entry(asn.number).or_insert_with(|| AsExposure {
+                    asn: asn.number,
+                    name: asn.name().to_string(),
+                    relays: 0,
+                    guard: 0.0,
+                    middle: 0.0,
+                    exit: 0.0,
+                    end_to_end: 0.0,
+                });
+                entry.relays += 1;
+                entry.guard += prob.guard;
+                entry.middle += prob.middle;
+                entry.exit += prob.exit;
+            }
+            None => {
+                unknown.guard += prob.guard;
+                unknown.middle += prob.middle;
+                unknown.exit += prob.exit;
+            }
+        }
+    }
+
+    let mut ases: Vec<AsExposure> = ases.into_values().collect();
+    for exposure in ases.iter_mut() {
+        exposure.end_to_end = exposure.guard * exposure.exit;
+    }
+    ases.sort_by(|a, b| (b.guard + b.exit).partial_cmp(&(a.guard + a.exit)).unwrap());
+
+    let coalitions = coalition_shares(&ases, max_coalition);
+    AsExposureReport {
+        ases,
+        unknown,
+        coalitions,
+    }
+}
+
+/// Shares of the guard, exit and end-to-end positions of the strongest
+/// coalitions of 1 to `max_coalition` ASes. Each coalition extends the
+/// previous one by the AS that increases the end-to-end probability the most.
+fn coalition_shares(ases: &[AsExposure], max_coalition: usize) -> Vec<CoalitionShare> {
+    let mut shares = Vec::new();
+    let mut chosen: Vec<usize> = Vec::new();
+    let (mut guard, mut exit) = (0.0, 0.0);
+    for size in 1..=max_coalition.min(ases.len()) {
+        // add the AS that increases the end-to-end probability the most
+        let next = (0..ases.len())
+            .filter(|i| !chosen.contains(i))
+            .max_by(|&i, &j| {
+                let with = |k: usize| (guard + ases[k].guard) * (exit + ases[k].exit);
+                with(i).partial_cmp(&with(j)).unwrap()
+            })
+            .unwrap();
+        chosen.push(next);
+        guard += ases[next].guard;
+        exit += ases[next].exit;
+
+        shares.push(CoalitionShare {
+            size,
+            asns: chosen.iter().map(|&i| ases[i].asn).collect(),
+            guard,
+            exit,
+            end_to_end: guard * exit,
+        });
+    }
+    shares
+}
+
+/// Compare the AS exposure of an original and a scaled consensus
+pub fn compare_exposure(
+    original: AsExposureReport,
+    scaled: AsExposureReport,
+) -> AsExposureComparison {
+    let mut changes: BTreeMap<u32, AsExposureChange> = BTreeMap::new();
+    let empty = |exposure: &AsExposure| AsExposureChange {
+        asn: exposure.asn,
+        name: exposure.name.clone(),
+        guard_original: 0.0,
+        guard_scaled: 0.0,
+        exit_original: 0.0,
+        exit_scaled: 0.0,
+        end_to_end_original: 0.0,
+        end_to_end_scaled: 0.0,
+    };
+    for exposure in original.ases.iter() {
+        let change = changes
+            .entry(exposure.asn)
+            .or_insert_with(|| empty(exposure));
+        change.guard_original = exposure.guard;
+        change.exit_original = exposure.exit;
+        change.end_to_end_original = exposure.end_to_end;
+    }
+    for exposure in scaled.ases.iter() {
+        let change = changes
+            .entry(exposure.asn)
+            .or_insert_with(|| empty(exposure));
+        change.guard_scaled = exposure.guard;
+        change.exit_scaled = exposure.exit;
+        change.end_to_end_scaled = exposure.end_to_end;
+    }
+
+    let mut changes: Vec<AsExposureChange> = changes.into_values().collect();
+    let difference = |c: &AsExposureChange| {
+        ((c.guard_scaled + c.exit_scaled) - (c.guard_original + c.exit_original)).abs()
+    };
+    changes.sort_by(|a, b| difference(b).partial_cmp(&difference(a)).unwrap());
+
+    AsExposureComparison {
+        original,
+        scaled,
+        changes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exposure(asn: u32, guard: f64, exit: f64) -> AsExposure {
+        AsExposure {
+            asn,
+            name: format!("AS {}", asn),
+            relays: 1,
+            guard,
+            middle: 0.0,
+            exit,
+            end_to_end: guard * exit,
+        }
+    }
+
+    fn report(ases: Vec<AsExposure>) -> AsExposureReport {
+        AsExposureReport {
+            coalitions: coalition_shares(&ases, 3),
+            ases,
+            unknown: PositionProbabilities::default(),
+        }
+    }
+
+    #[test]
+    fn coalitions() {
+        let ases = vec![
+            exposure(1, 0.5, 0.0),
+            exposure(2, 0.0, 0.4),
+            exposure(3, 0.2, 0.2),
+        ];
+        let shares = coalition_shares(&ases, 5);
+        assert_eq!(shares.len(), 3);
+
+        // a single AS can only control both ends if it has guards and exits
+        assert_eq!(shares[0].asns, vec![3]);
+        assert_eq!(shares[0].guard, 0.2);
+        assert_eq!(shares[0].exit, 0.2);
+
+        // all numbers of a row refer to the same coalition
+        assert_eq!(shares[1].asns, vec![3, 1]);
+        assert!((shares[1].guard - 0.7).abs() < 1e-9);
+        assert!((shares[1].exit - 0.2).abs() < 1e-9);
+        assert!((shares[1].end_to_end - 0.14).abs() < 1e-9);
+
+        assert_eq!(shares[2].asns, vec![3, 1, 2]);
+        assert!((shares[2].end_to_end - 0.7 * 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compare() {
+        let original = report(vec![exposure(1, 0.5, 0.1), exposure(2, 0.1, 0.1)]);
+        let scaled = report(vec![exposure(2, 0.1, 0.2), exposure(3, 0.3, 0.2)]);
+        let comparison = compare_exposure(original, scaled);
+
+        // sorted by descending absolute change of guard + exit
+        let asns: Vec<u32> = comparison.changes.iter().map(|c| c.asn).collect();
+        assert_eq!(asns, vec![1, 3, 2]);
+
+        // ASes missing in one of the consensuses have zero probability there
+        let vanished = &comparison.changes[0];
+        assert_eq!(vanished.guard_scaled, 0.0);
+        assert_eq!(vanished.exit_original, 0.1);
+        let appeared = &comparison.changes[1];
+        assert_eq!(appeared.guard_original, 0.0);
+        assert!((appeared.end_to_end_scaled - 0.06).abs() < 1e-9);
+        let both = &comparison.changes[2];
+        assert_eq!(both.exit_original, 0.1);
+        assert_eq!(both.exit_scaled, 0.2);
+    }
+}
@@ -44,6 +44,11 @@ pub struct Asn {
 }
 
 impl Asn {
+    /// Name of the AS as given in the database
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn sample_ip(&self) -> Ipv4Addr {
         use rand::distributions::WeightedIndex;
         use rand::prelude::*;
@@ -137,9 +142,13 @@ impl IpRange {
 
 impl AsnDb {
     pub fn new<P: AsRef<Path>>(geolite_file: P) -> Result<AsnDb, AsnDbError> {
-        let file = File::open(geolite_file.as_ref())?;
+        AsnDb::from_reader(File::open(geolite_file.as_ref())?)
+    }
 
-        let mut rdr = csv::Reader::from_reader(file);
+    /// Read the database from CSV data with a header line and the columns
+    /// network (e.g. `1.0.0.0/24`), AS number and AS name
+    pub fn from_reader<R: std::io::Read>(reader: R) -> Result<AsnDb, AsnDbError> {
+        let mut rdr = csv::Reader::from_reader(reader);
 
         let mut as_lookup = IpLookupTable::new();
         let mut as_objects: RHashMap<u32, Rc<Asn>> = RHashMap::default();
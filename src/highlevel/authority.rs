@@ -0,0 +1,399 @@
+//! Directory authorities with generated keys, used to sign consensuses so
+//! that they can be consumed by actual Tor clients (e.g. in a private network).
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, LineEnding};
+use rsa::RsaPrivateKey;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use thiserror;
+
+use super::keys::{self, KeyError};
+use tordoc::Fingerprint;
+
+/// Size of the authority identity keys in bits
+const IDENTITY_KEY_BITS: usize = 3072;
+/// Size of the authority signing keys in bits
+const SIGNING_KEY_BITS: usize = 2048;
+/// Size of the authorities' relay identity keys in bits
+const RELAY_IDENTITY_KEY_BITS: usize = 1024;
+/// Lifetime of generated authority key certificates
+const CERTIFICATE_LIFETIME_DAYS: i64 = 365;
+
+const IDENTITY_KEY_FILE: &str = "authority_identity_key";
+const SIGNING_KEY_FILE: &str = "authority_signing_key";
+const CERTIFICATE_FILE: &str = "authority_certificate";
+const RELAY_IDENTITY_KEY_FILE: &str = "secret_id_key";
+const META_FILE: &str = "authority.json";
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthorityError {
+    #[error("Key error")]
+    KeyError(#[from] KeyError),
+    #[error("PKCS#1 encoding error")]
+    Pkcs1Error(#[from] rsa::pkcs1::Error),
+    #[error("General I/O error")]
+    IoError(#[from] io::Error),
+    #[error("Formatting error")]
+    FmtError(#[from] fmt::Error),
+    #[error("JSON serialization error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("No directory authorities found in {0}")]
+    NoAuthorities(String),
+    #[error("The document to sign does not contain a network-status-version line")]
+    UnsignableDocument,
+}
+
+/// The digest algorithm used for signing a consensus
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+/// Information about an authority that is not contained in its keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuthorityMeta {
+    nickname: String,
+    address: Ipv4Addr,
+    dir_port: u16,
+    or_port: u16,
+    contact: String,
+}
+
+/// A directory authority, including its keys and key certificate
+pub struct DirAuthority {
+    meta: AuthorityMeta,
+    identity_key: RsaPrivateKey,
+    signing_key: RsaPrivateKey,
+    certificate: String,
+    /// The identity key the authority uses as a relay
+    relay_identity_key: RsaPrivateKey,
+}
+
+impl DirAuthority {
+    fn generate(
+        meta: AuthorityMeta,
+        published: DateTime<Utc>,
+        rng: &mut rand_chacha::ChaCha20Rng,
+    ) -> Result<DirAuthority, AuthorityError> {
+        let identity_key = keys::generate_rsa_key(rng, IDENTITY_KEY_BITS)?;
+        let signing_key = keys::generate_rsa_key(rng, SIGNING_KEY_BITS)?;
+        let certificate = make_certificate(&identity_key, &signing_key, published)?;
+        let relay_identity_key = keys::generate_rsa_key(rng, RELAY_IDENTITY_KEY_BITS)?;
+
+        Ok(DirAuthority {
+            meta,
+            identity_key,
+            signing_key,
+            certificate,
+            relay_identity_key,
+        })
+    }
+
+    fn load(dir: &Path) -> Result<DirAuthority, AuthorityError> {
+        let meta = serde_json::from_str(&fs::read_to_string(dir.join(META_FILE))?)?;
+        let identity_key =
+            RsaPrivateKey::from_pkcs1_pem(&fs::read_to_string(dir.join(IDENTITY_KEY_FILE))?)?;
+        let signing_key =
+            RsaPrivateKey::from_pkcs1_pem(&fs::read_to_string(dir.join(SIGNING_KEY_FILE))?)?;
+        let certificate = fs::read_to_string(dir.join(CERTIFICATE_FILE))?;
+        let relay_identity_key =
+            RsaPrivateKey::from_pkcs1_pem(&fs::read_to_string(dir.join(RELAY_IDENTITY_KEY_FILE))?)?;
+
+        Ok(DirAuthority {
+            meta,
+            identity_key,
+            signing_key,
+            certificate,
+            relay_identity_key,
+        })
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), AuthorityError> {
+        fs::create_dir_all(dir)?;
+        fs::write(
+            dir.join(META_FILE),
+            serde_json::to_string_pretty(&self.meta)?,
+        )?;
+        fs::write(
+            dir.join(IDENTITY_KEY_FILE),
+            self.identity_key.to_pkcs1_pem(LineEnding::LF)?.as_bytes(),
+        )?;
+        fs::write(
+            dir.join(SIGNING_KEY_FILE),
+            self.signing_key.to_pkcs1_pem(LineEnding::LF)?.as_bytes(),
+        )?;
+        fs::write(dir.join(CERTIFICATE_FILE), &self.certificate)?;
+        fs::write(
+            dir.join(RELAY_IDENTITY_KEY_FILE),
+            self.relay_identity_key
+                .to_pkcs1_pem(LineEnding::LF)?
+                .as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    pub fn nickname(&self) -> &str {
+        &self.meta.nickname
+    }
+
+    /// The fingerprint of the authority's identity key (v3ident)
+    pub fn identity_fingerprint(&self) -> Result<Fingerprint, AuthorityError> {
+        Ok(keys::rsa_fingerprint(&self.identity_key)?)
+    }
+
+    /// The fingerprint of the authority's relay identity key
+    pub fn relay_fingerprint(&self) -> Result<Fingerprint, AuthorityError> {
+        Ok(keys::rsa_fingerprint(&self.relay_identity_key)?)
+    }
+
+    /// The key certificate of this authority
+    pub fn certificate(&self) -> &str {
+        &self.certificate
+    }
+}
+
+/// Create a key certificate (see dir-spec.txt, section 3.1)
+fn make_certificate(
+    identity_key: &RsaPrivateKey,
+    signing_key: &RsaPrivateKey,
+    published: DateTime<Utc>,
+) -> Result<String, AuthorityError> {
+    use std::fmt::Write;
+
+    let identity_digest = Sha1::digest(keys::rsa_public_der(identity_key)?);
+
+    let mut cert = String::new();
+    writeln!(&mut cert, "dir-key-certificate-version 3")?;
+    writeln!(
+        &mut cert,
+        "fingerprint {}",
+        keys::rsa_fingerprint(identity_key)?.to_string_hex()
+    )?;
+    writeln!(
+        &mut cert,
+        "dir-key-published {}",
+        published.format("%Y-%m-%d %H:%M:%S")
+    )?;
+    writeln!(
+        &mut cert,
+        "dir-key-expires {}",
+        (published + Duration::days(CERTIFICATE_LIFETIME_DAYS)).format("%Y-%m-%d %H:%M:%S")
+    )?;
+    writeln!(&mut cert, "dir-identity-key")?;
+    write!(&mut cert, "{}", keys::rsa_public_pem(identity_key)?)?;
+    writeln!(&mut cert, "dir-signing-key")?;
+    write!(&mut cert, "{}", keys::rsa_public_pem(signing_key)?)?;
+    writeln!(&mut cert, "dir-key-crosscert")?;
+    write!(
+        &mut cert,
+        "{}",
+        keys::pem_object(
+            "ID SIGNATURE",
+            &keys::rsa_sign(signing_key, &identity_digest)?
+        )
+    )?;
+    writeln!(&mut cert, "dir-key-certification")?;
+
+    let cert_digest = Sha1::digest(cert.as_bytes());
+    write!(
+        &mut cert,
+        "{}",
+        keys::pem_object("SIGNATURE", &keys::rsa_sign(identity_key, &cert_digest)?)
+    )?;
+
+    Ok(cert)
+}
+
+/// A set of directory authorities that jointly sign a consensus
+pub struct AuthoritySet {
+    authorities: Vec<DirAuthority>,
+}
+
+impl AuthoritySet {
+    /// Generate `num` new authorities. They listen on consecutive ports,
+    /// starting at `first_or_port` and `first_dir_port`, respectively.
+    /// Their certificates are published at `published`.
+    pub fn generate(
+        num: usize,
+        address: Ipv4Addr,
+        first_or_port: u16,
+        first_dir_port: u16,
+        published: DateTime<Utc>,
+    ) -> Result<AuthoritySet, AuthorityError> {
+        let mut rng = keys::key_rng();
+        let authorities = (0..num)
+            .map(|i| {
+                let meta = AuthorityMeta {
+                    nickname: format!("SynthAuth{}", i),
+                    address,
+                    dir_port: first_dir_port + i as u16,
+                    or_port: first_or_port + i as u16,
+                    contact: format!("synthetic authority {}", i),
+                };
+                DirAuthority::generate(meta, published, &mut rng)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(AuthoritySet { authorities })
+    }
+
+    /// Load authorities from a directory, containing one subdirectory per
+    /// authority. Returns [`AuthorityError::NoAuthorities`] if the directory
+    /// does not exist or does not contain any authorities.
+    pub fn load(dir: impl AsRef<Path>) -> Result<AuthoritySet, AuthorityError> {
+        let dir = dir.as_ref();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(AuthorityError::NoAuthorities(dir.display().to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut subdirs = entries
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        subdirs.retain(|p| p.join(META_FILE).exists());
+        subdirs.sort();
+
+        if subdirs.is_empty() {
+            return Err(AuthorityError::NoAuthorities(dir.display().to_string()));
+        }
+
+        let authorities = subdirs
+            .iter()
+            .map(|p| DirAuthority::load(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AuthoritySet { authorities })
+    }
+
+    /// Save the authorities' keys and certificates to a directory, using one
+    /// subdirectory per authority
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<(), AuthorityError> {
+        let dir = dir.as_ref();
+        for authority in self.authorities.iter() {
+            authority.save(&dir.join(&authority.meta.nickname))?;
+        }
+        Ok(())
+    }
+
+    pub fn authorities(&self) -> &[DirAuthority] {
+        &self.authorities
+    }
+
+    /// The authorities, sorted by their identity fingerprint as required in
+    /// the consensus
+    fn sorted(&self) -> Result<Vec<(String, &DirAuthority)>, AuthorityError> {
+        let mut result = self
+            .authorities
+            .iter()
+            .map(|a| Ok((a.identity_fingerprint()?.to_string_hex(), a)))
+            .collect::<Result<Vec<_>, AuthorityError>>()?;
+        result.sort_by(|(x, _), (y, _)| x.cmp(y));
+        Ok(result)
+    }
+
+    /// Lines to configure Tor to use these authorities (`DirAuthority` option)
+    pub fn torrc_lines(&self) -> Result<Vec<String>, AuthorityError> {
+        self.authorities
+            .iter()
+            .map(|a| {
+                Ok(format!(
+                    "DirAuthority {} orport={} no-v2 v3ident={} {}:{} {}",
+                    a.meta.nickname,
+                    a.meta.or_port,
+                    a.identity_fingerprint()?.to_string_hex(),
+                    a.meta.address,
+                    a.meta.dir_port,
+                    a.relay_fingerprint()?.to_string_hex(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The authority section of a consensus (dir-source, contact and
+    /// vote-digest entries).
+    ///
+    /// As there are no actual votes, the vote digest is derived from the
+    /// authority and the consensus' valid-after time.
+    pub fn authority_section(&self, valid_after: DateTime<Utc>) -> Result<String, AuthorityError> {
+        use std::fmt::Write;
+
+        let mut section = String::new();
+        for (identity, authority) in self.sorted()? {
+            let meta = &authority.meta;
+            writeln!(
+                &mut section,
+                "dir-source {} {} {} {} {} {}",
+                meta.nickname, identity, meta.address, meta.address, meta.dir_port, meta.or_port
+            )?;
+            writeln!(&mut section, "contact {}", meta.contact)?;
+            let vote_digest = Sha1::digest(
+                format!("{} {}", identity, valid_after.format("%Y-%m-%d %H:%M:%S")).as_bytes(),
+            );
+            writeln!(
+                &mut section,
+                "vote-digest {}",
+                Fingerprint::from_u8(&vote_digest).to_string_hex()
+            )?;
+        }
+        Ok(section)
+    }
+
+    /// Compute the directory signatures for a consensus document. `document`
+    /// has to contain the whole consensus up to (and excluding) the first
+    /// `directory-signature` line. The returned signature lines are meant
+    /// to be appended to it.
+    pub fn sign_consensus(
+        &self,
+        document: &str,
+        algorithm: DigestAlgorithm,
+    ) -> Result<String, AuthorityError> {
+        use std::fmt::Write;
+
+        // The signature covers the document from "network-status-version"
+        // up to and including the space after "directory-signature".
+        let start = document
+            .find("network-status-version")
+            .ok_or(AuthorityError::UnsignableDocument)?;
+        let mut signed_part = document[start..].to_string();
+        signed_part.push_str("directory-signature ");
+
+        let digest = match algorithm {
+            DigestAlgorithm::Sha1 => Sha1::digest(signed_part.as_bytes()).to_vec(),
+            DigestAlgorithm::Sha256 => Sha256::digest(signed_part.as_bytes()).to_vec(),
+        };
+
+        let mut signatures = String::new();
+        for (identity, authority) in self.sorted()? {
+            let signing_key_digest = keys::rsa_fingerprint(&authority.signing_key)?;
+            writeln!(
+                &mut signatures,
+                "directory-signature {}{} {}",
+                match algorithm {
+                    DigestAlgorithm::Sha1 => "",
+                    DigestAlgorithm::Sha256 => "sha256 ",
+                },
+                identity,
+                signing_key_digest.to_string_hex()
+            )?;
+            write!(
+                &mut signatures,
+                "{}",
+                keys::pem_object(
+                    "SIGNATURE",
+                    &keys::rsa_sign(&authority.signing_key, &digest)?
+                )
+            )?;
+        }
+        Ok(signatures)
+    }
+}
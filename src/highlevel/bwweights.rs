@@ -1,42 +1,293 @@
+//! Computation of the bandwidth weights according to dir-spec.txt
+
 use std::cmp::{max, min};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::highlevel::{Consensus, PositionClass};
+
+use serde::Serialize;
+use thiserror;
+
+/// The case of dir-spec's bandwidth weight computation that was applied
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+pub enum WeightCase {
+    /// Neither guards nor exits are scarce
+    Case1,
+    /// Both are scarce, and D cannot balance them out
+    Case2a,
+    /// Both are scarce, Wgg=weightscale, Wmd=Wgd
+    Case2b1,
+    /// Both are scarce, Wgg=Wee=weightscale
+    Case2b2,
+    /// Both are scarce, Wgg=Wee=weightscale, but too much middle bandwidth (Wmd=0)
+    Case2b3,
+    /// Guards or exits are scarce, and S+D < T/3
+    Case3a,
+    /// Guards are scarce, S+D >= T/3
+    Case3bg,
+    /// Exits are scarce, S+D >= T/3
+    Case3be,
+}
+
+impl WeightCase {
+    /// The case identifier as used in dir-spec.txt (e.g. "2b1")
+    pub fn id(&self) -> &'static str {
+        match self {
+            WeightCase::Case1 => "1",
+            WeightCase::Case2a => "2a",
+            WeightCase::Case2b1 => "2b1",
+            WeightCase::Case2b2 => "2b2",
+            WeightCase::Case2b3 => "2b3",
+            WeightCase::Case3a => "3a",
+            WeightCase::Case3bg => "3bg",
+            WeightCase::Case3be => "3be",
+        }
+    }
+
+    /// Whether this case aims at balancing the positions. If not, the balance
+    /// checks are expected to fail and are therefore not performed.
+    fn is_balanced(&self) -> bool {
+        !matches!(self, WeightCase::Case2a | WeightCase::Case3a)
+    }
+
+    /// Whether an imbalance of the middle position is tolerated. As in Tor,
+    /// this is the case for all variants of subcase 2b.
+    fn tolerates_mid_imbalance(&self) -> bool {
+        matches!(
+            self,
+            WeightCase::Case2b1 | WeightCase::Case2b2 | WeightCase::Case2b3
+        )
+    }
+}
+
+impl fmt::Display for WeightCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            WeightCase::Case1 => "Wgd=Wmd=Wed",
+            WeightCase::Case2a => "E and G scarce, R+D < S",
+            WeightCase::Case2b1 => "Wgg=weightscale, Wmd=Wgd",
+            WeightCase::Case2b2 => "Wgg=weightscale, Wee=weightscale",
+            WeightCase::Case2b3 => "Wmd=0",
+            WeightCase::Case3a => "S+D < T/3",
+            WeightCase::Case3bg => "G scarce, Wgg=weightscale, Wmd == Wed",
+            WeightCase::Case3be => "E scarce, Wee=weightscale, Wmd == Wgd",
+        };
+        write!(f, "Case {} ({})", self.id(), description)
+    }
+}
+
+/// Total bandwidth of the relay classes the weights are computed from
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BandwidthTotals {
+    /// Exits (without guard flag)
+    pub e: i64,
+    /// Guards (without exit flag)
+    pub g: i64,
+    /// Relays that are both guard and exit
+    pub d: i64,
+    /// Middle relays (neither guard nor exit)
+    pub m: i64,
+    /// Total bandwidth
+    pub t: i64,
+}
+
+/// The result of computing the bandwidth weights
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightComputation {
+    pub case: WeightCase,
+    pub totals: BandwidthTotals,
+    /// Failed checks of the final weights
+    pub violations: Vec<BwwError>,
+    pub weights: BTreeMap<String, u64>,
+}
+
+/// Difference between a contained and a recomputed bandwidth weight
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightDifference {
+    pub key: String,
+    /// Value contained in the consensus
+    pub old: Option<u64>,
+    /// Recomputed value
+    pub new: Option<u64>,
+    /// Whether old and new value differ by at most the tolerance
+    pub within_tolerance: bool,
+}
+
+impl WeightDifference {
+    fn new(key: String, old: Option<u64>, new: Option<u64>, tolerance: u64) -> WeightDifference {
+        let within_tolerance = match (old, new) {
+            (Some(old), Some(new)) => old.abs_diff(new) <= tolerance,
+            _ => false,
+        };
+        WeightDifference {
+            key,
+            old,
+            new,
+            within_tolerance,
+        }
+    }
+}
+
+/// The result of verifying the bandwidth weights contained in a consensus
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightVerification {
+    pub computation: WeightComputation,
+    pub tolerance: u64,
+    /// Per-key comparison of the contained and the recomputed weights
+    pub differences: Vec<WeightDifference>,
+}
+
+impl WeightVerification {
+    /// Compare old weights to a new computation, allowing each weight to
+    /// differ by `tolerance`
+    pub fn new(
+        old_weights: &BTreeMap<String, u64>,
+        computation: WeightComputation,
+        tolerance: u64,
+    ) -> WeightVerification {
+        let keys: BTreeSet<&String> = old_weights
+            .keys()
+            .chain(computation.weights.keys())
+            .collect();
+        let differences = keys
+            .into_iter()
+            .map(|key| {
+                WeightDifference::new(
+                    key.clone(),
+                    old_weights.get(key).copied(),
+                    computation.weights.get(key).copied(),
+                    tolerance,
+                )
+            })
+            .collect();
+
+        WeightVerification {
+            computation,
+            tolerance,
+            differences,
+        }
+    }
+
+    /// Whether all weights match within the tolerance
+    pub fn is_match(&self) -> bool {
+        self.differences.iter().all(|d| d.within_tolerance)
+    }
+}
 
-use crate::highlevel::Consensus;
+impl fmt::Display for WeightVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = &self.computation.totals;
+        writeln!(f, "Weight case: {}", self.computation.case)?;
+        writeln!(
+            f,
+            "Totals:      E={} G={} D={} M={} (T={})",
+            totals.e, totals.g, totals.d, totals.m, totals.t
+        )?;
+        if self.computation.violations.is_empty() {
+            writeln!(f, "Violations:  none")?;
+        } else {
+            writeln!(f, "Violations:")?;
+            for violation in self.computation.violations.iter() {
+                writeln!(f, "- {}", violation)?;
+            }
+        }
+        writeln!(f, "Weights (tolerance {}):", self.tolerance)?;
+        let fmt_value = |x: Option<u64>| x.map(|v| v.to_string()).unwrap_or("-".to_string());
+        for diff in self.differences.iter() {
+            writeln!(
+                f,
+                "  {} {:>8} {:>8} {}",
+                diff.key,
+                fmt_value(diff.old),
+                fmt_value(diff.new),
+                if diff.within_tolerance {
+                    "ok"
+                } else {
+                    "MISMATCH"
+                }
+            )?;
+        }
+        let mismatches = self
+            .differences
+            .iter()
+            .filter(|d| !d.within_tolerance)
+            .count();
+        if mismatches == 0 {
+            write!(f, "bw weights match.")
+        } else {
+            write!(f, "{} bw weights do not match.", mismatches)
+        }
+    }
+}
+
+/// Compute the bandwidth weights and store them in the consensus
+pub fn recompute_bw_weights(consensus: &mut Consensus) -> WeightComputation {
+    let computation = compute_bw_weights(consensus);
+    consensus.weights = computation.weights.clone();
+    computation
+}
 
-use tordoc::consensus::Flag;
+/// Compute the bandwidth weights for the relays in the consensus
+pub fn compute_bw_weights(consensus: &Consensus) -> WeightComputation {
+    compute_bw_weights_for(
+        consensus
+            .relays
+            .values()
+            .map(|relay| (relay.position_class(), relay.bandwidth_weight)),
+    )
+}
 
+/// Compute the bandwidth weights for relays given as (class, bandwidth) pairs
 #[allow(non_snake_case)]
-pub fn recompute_bw_weights(consensus: &mut Consensus) {
-    let mut Wmd: i64;
-    let mut Wed: i64;
-    let mut Wgd: i64;
-    let mut Wme: i64;
-    let mut Wee: i64;
-    let mut Wmg: i64;
-    let mut Wgg: i64;
+pub(crate) fn compute_bw_weights_for<I: IntoIterator<Item = (PositionClass, u64)>>(
+    relays: I,
+) -> WeightComputation {
     // First, collect the total bandwidth values
     let mut E = 1i64;
     let mut G = 1i64;
     let mut D = 1i64;
     let mut M = 1i64;
-    for (_, relay) in consensus.relays.iter() {
-        let is_exit = relay.has_flag(Flag::Exit) && !relay.has_flag(Flag::BadExit);
-        if is_exit && relay.has_flag(Flag::Guard) {
-            D += relay.bandwidth_weight as i64;
-        } else if is_exit {
-            E += relay.bandwidth_weight as i64;
-        } else if relay.has_flag(Flag::Guard) {
-            G += relay.bandwidth_weight as i64;
-        } else {
-            M += relay.bandwidth_weight as i64;
+    for (class, bandwidth) in relays {
+        match class {
+            PositionClass::GuardExit => D += bandwidth as i64,
+            PositionClass::Exit => E += bandwidth as i64,
+            PositionClass::Guard => G += bandwidth as i64,
+            PositionClass::Middle => M += bandwidth as i64,
         }
     }
-    let T = E + G + D + M;
+    compute_bw_weights_from_totals(BandwidthTotals {
+        e: E,
+        g: G,
+        d: D,
+        m: M,
+        t: E + G + D + M,
+    })
+}
+
+/// Compute the bandwidth weights from the total bandwidth of each relay class
+#[allow(non_snake_case)]
+fn compute_bw_weights_from_totals(totals: BandwidthTotals) -> WeightComputation {
+    let mut Wmd: i64;
+    let mut Wed: i64;
+    let mut Wgd: i64;
+    let mut Wme: i64;
+    let mut Wee: i64;
+    let mut Wmg: i64;
+    let mut Wgg: i64;
+    let BandwidthTotals {
+        e: E,
+        g: G,
+        d: D,
+        m: M,
+        t: T,
+    } = totals;
     let weightscale = 10000;
+    let mut case;
 
     if 3 * E >= T && 3 * G >= T {
         // Case 1: Neither are scarce
-        // casename = "Case 1 (Wgd=Wmd=Wed)"
+        case = WeightCase::Case1;
         Wmd = weightscale / 3;
         Wed = weightscale / 3;
         Wgd = weightscale / 3;
@@ -57,18 +308,19 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
             Wmd = 0;
             Wme = 0;
             Wmg = 0;
+            case = WeightCase::Case2a;
             if E < G {
-                // casename = "Case 2a (E scarce)"
+                // E scarce
                 Wed = weightscale;
                 Wgd = 0;
             } else {
-                // casename = "Case 2a (G scarce)"
+                // G scarce
                 Wed = 0;
                 Wgd = weightscale;
             }
         } else {
             // subcase b R+D >= S
-            // casename = "Case 2b1 (Wgg=weightscale, Wmd=Wgd)"
+            case = WeightCase::Case2b1;
             Wee = (weightscale * (E - G + M)) / E;
             Wed = (weightscale * (D - 2 * E + 4 * G - 2 * M)) / (3 * D);
             Wme = (weightscale * (G - M)) / E;
@@ -77,7 +329,7 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
             Wgd = (weightscale - Wed) / 2;
             Wmd = (weightscale - Wed) / 2;
 
-            if let Some(_) = check_weights_errors(
+            if check_weights_errors(
                 Wgg,
                 Wgd,
                 Wmg,
@@ -93,8 +345,10 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
                 T,
                 10,
                 true,
-            ) {
-                // casename = 'Case 2b2 (Wgg=weightscale, Wee=weightscale)'
+            )
+            .is_some()
+            {
+                case = WeightCase::Case2b2;
                 Wee = weightscale;
                 Wgg = weightscale;
                 Wed = (weightscale * (D - 2 * E + G + M)) / (3 * D);
@@ -103,34 +357,11 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
                 Wme = 0;
                 if Wmd < 0 {
                     // Too much bandwidth at middle position
-                    // casename = 'case 2b3 (Wmd=0)'
+                    case = WeightCase::Case2b3;
                     Wmd = 0;
                 }
                 Wgd = weightscale - Wed - Wmd;
             }
-
-            match check_weights_errors(
-                Wgg,
-                Wgd,
-                Wmg,
-                Wme,
-                Wmd,
-                Wee,
-                Wed,
-                weightscale,
-                G,
-                M,
-                E,
-                D,
-                T,
-                10,
-                true,
-            ) {
-                None | Some(BwwError::BalanceMid) => {}
-                _ => {
-                    panic!("bw weight error");
-                }
-            }
         }
     } else {
         // if (E < T/3 or G < T/3)
@@ -140,7 +371,8 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
         if 3 * (S + D) < T {
             // subcase a: S+D < T/3
             if G < E {
-                // casename = 'Case 3a (G scarce)'
+                // G scarce
+                case = WeightCase::Case3a;
                 Wgd = weightscale;
                 Wgg = weightscale;
                 Wmg = 0;
@@ -155,7 +387,8 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
                 Wee = weightscale - Wme;
             } else {
                 // G >= E
-                // casename = "Case 3a (E scarce)"
+                // E scarce
+                case = WeightCase::Case3a;
                 Wed = weightscale;
                 Wee = weightscale;
                 Wme = 0;
@@ -171,7 +404,7 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
         } else {
             // subcase S+D >= T/3
             if G < E {
-                // casename = 'Case 3bg (G scarce, Wgg=weightscale, Wmd == Wed'
+                case = WeightCase::Case3bg;
                 Wgg = weightscale;
                 Wgd = (weightscale * (D - 2 * G + E + M)) / (3 * D);
                 Wmg = 0;
@@ -181,7 +414,7 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
                 Wmd = (weightscale - Wgd) / 2;
             } else {
                 // G >= E
-                // casename = 'Case 3be (E scarce, Wee=weightscale, Wmd == Wgd'
+                case = WeightCase::Case3be;
                 Wee = weightscale;
                 Wed = (weightscale * (D - 2 * E + G + M)) / (3 * D);
                 Wme = 0;
@@ -193,7 +426,30 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
         }
     }
 
-    consensus.weights = BTreeMap::from_iter(
+    // Check the final weights. The balance checks are only expected to hold
+    // for cases that actually try to balance the positions.
+    let mut violations = weights_errors(
+        Wgg,
+        Wgd,
+        Wmg,
+        Wme,
+        Wmd,
+        Wee,
+        Wed,
+        weightscale,
+        G,
+        M,
+        E,
+        D,
+        T,
+        10,
+        case.is_balanced(),
+    );
+    if case.tolerates_mid_imbalance() {
+        violations.retain(|v| *v != BwwError::BalanceMid);
+    }
+
+    let weights = BTreeMap::from_iter(
         [
             ("Wbd", Wmd),
             ("Wbe", Wme),
@@ -237,6 +493,13 @@ pub fn recompute_bw_weights(consensus: &mut Consensus) {
     //     (int)weight_scale, (int)Wed, (int)Wee, (int)Wed, (int)Wee,
     //     (int)weight_scale, (int)Wgd, (int)Wgg, (int)Wgg,
     //     (int)weight_scale, (int)Wmd, (int)Wme, (int)Wmg, (int)weight_scale);
+
+    WeightComputation {
+        case,
+        totals,
+        violations,
+        weights,
+    }
 }
 
 fn check_eq(a: i64, b: i64, margin: i64) -> bool {
@@ -263,17 +526,25 @@ fn check_range(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, mx: i64)
         && g <= mx
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-enum BwwError {
+/// A violated constraint of the bandwidth weights
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+pub enum BwwError {
+    #[error("Wed + Wmd + Wgd != weightscale")]
     SumD,
+    #[error("Wmg + Wgg != weightscale")]
     SumG,
+    #[error("Wme + Wee != weightscale")]
     SumE,
+    #[error("weights not within range 0 -> weightscale")]
     Range,
+    #[error("Wgg*G + Wgd*D != Wee*E + Wed*D (guard/exit balance)")]
     BalanceEg,
+    #[error("Wgg*G + Wgd*D != M*weightscale + Wmd*D + Wme*E + Wmg*G (middle balance)")]
     BalanceMid,
 }
 
-/// Verify that our weights satify the formulas from dir-spec.txt
+/// Verify that our weights satify the formulas from dir-spec.txt, returning
+/// the first violated constraint
 #[allow(non_snake_case)]
 fn check_weights_errors(
     Wgg: i64,
@@ -292,26 +563,69 @@ fn check_weights_errors(
     margin: i64,
     do_balance: bool,
 ) -> Option<BwwError> {
+    weights_errors(
+        Wgg,
+        Wgd,
+        Wmg,
+        Wme,
+        Wmd,
+        Wee,
+        Wed,
+        weightscale,
+        G,
+        M,
+        E,
+        D,
+        T,
+        margin,
+        do_balance,
+    )
+    .into_iter()
+    .next()
+}
+
+/// Verify that our weights satify the formulas from dir-spec.txt, returning
+/// all violated constraints
+#[allow(non_snake_case)]
+fn weights_errors(
+    Wgg: i64,
+    Wgd: i64,
+    Wmg: i64,
+    Wme: i64,
+    Wmd: i64,
+    Wee: i64,
+    Wed: i64,
+    weightscale: i64,
+    G: i64,
+    M: i64,
+    E: i64,
+    D: i64,
+    T: i64,
+    margin: i64,
+    do_balance: bool,
+) -> Vec<BwwError> {
+    let mut errors = Vec::new();
+
     // # Wed + Wmd + Wgd == weightscale
     if !check_eq(Wed + Wmd + Wgd, weightscale, margin) {
-        return Some(BwwError::SumD);
+        errors.push(BwwError::SumD);
     }
     // # Wmg + Wgg == weightscale
     if !check_eq(Wmg + Wgg, weightscale, margin) {
-        return Some(BwwError::SumG);
+        errors.push(BwwError::SumG);
     }
     // # Wme + Wee == weightscale
     if !check_eq(Wme + Wee, weightscale, margin) {
-        return Some(BwwError::SumE);
+        errors.push(BwwError::SumE);
     }
     // # Verify weights within range 0 -> weightscale
     if !check_range(Wgg, Wgd, Wmg, Wme, Wmd, Wed, Wee, weightscale) {
-        return Some(BwwError::Range);
+        errors.push(BwwError::Range);
     }
     if do_balance {
         // #Wgg*G + Wgd*D == Wee*E + Wed*D
         if !check_eq(Wgg * G + Wgd * D, Wee * E + Wed * D, (margin * T) / 3) {
-            return Some(BwwError::BalanceEg);
+            errors.push(BwwError::BalanceEg);
         }
         // #Wgg*G+Wgd*D == M*weightscale + Wmd*D + Wme * E + Wmg*G
         if !check_eq(
@@ -319,9 +633,105 @@ fn check_weights_errors(
             M * weightscale + Wmd * D + Wme * E + Wmg * G,
             (margin * T) / 3,
         ) {
-            return Some(BwwError::BalanceMid);
+            errors.push(BwwError::BalanceMid);
         }
     }
 
-    None
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compute(e: i64, g: i64, d: i64, m: i64) -> WeightComputation {
+        compute_bw_weights_from_totals(BandwidthTotals {
+            e,
+            g,
+            d,
+            m,
+            t: e + g + d + m,
+        })
+    }
+
+    #[test]
+    fn case_1() {
+        let computation = compute(2000, 2000, 1000, 1000);
+        assert_eq!(computation.case, WeightCase::Case1);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wgd"], 3333);
+        assert_eq!(computation.weights["Wmd"], 3333);
+        assert_eq!(computation.weights["Wed"], 3333);
+    }
+
+    #[test]
+    fn case_2a() {
+        let computation = compute(100, 300, 100, 500);
+        assert_eq!(computation.case, WeightCase::Case2a);
+        assert!(computation.violations.is_empty());
+        // exits are scarce, so D is used for exits only
+        assert_eq!(computation.weights["Wed"], 10000);
+        assert_eq!(computation.weights["Wgd"], 0);
+    }
+
+    #[test]
+    fn case_2b1() {
+        let computation = compute(100, 100, 100, 100);
+        assert_eq!(computation.case, WeightCase::Case2b1);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wgg"], 10000);
+        assert_eq!(computation.weights["Wmd"], computation.weights["Wgd"]);
+    }
+
+    #[test]
+    fn case_2b2() {
+        let computation = compute(100, 100, 200, 200);
+        assert_eq!(computation.case, WeightCase::Case2b2);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wgg"], 10000);
+        assert_eq!(computation.weights["Wee"], 10000);
+    }
+
+    #[test]
+    fn case_2b3_tolerates_mid_imbalance() {
+        let computation = compute(100, 100, 100, 200);
+        assert_eq!(computation.case, WeightCase::Case2b3);
+        assert_eq!(computation.weights["Wmd"], 0);
+        assert_eq!(computation.violations, vec![BwwError::BalanceEg]);
+    }
+
+    #[test]
+    fn case_3a() {
+        let computation = compute(100, 300, 100, 200);
+        assert_eq!(computation.case, WeightCase::Case3a);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wed"], 10000);
+        assert_eq!(computation.weights["Wee"], 10000);
+    }
+
+    #[test]
+    fn case_3bg() {
+        let computation = compute(200, 100, 100, 100);
+        assert_eq!(computation.case, WeightCase::Case3bg);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wgg"], 10000);
+    }
+
+    #[test]
+    fn case_3be() {
+        let computation = compute(100, 200, 100, 100);
+        assert_eq!(computation.case, WeightCase::Case3be);
+        assert!(computation.violations.is_empty());
+        assert_eq!(computation.weights["Wee"], 10000);
+    }
+
+    #[test]
+    fn verification_tolerance() {
+        let computation = compute(2000, 2000, 1000, 1000);
+        let mut old_weights = computation.weights.clone();
+        *old_weights.get_mut("Wgd").unwrap() += 2;
+
+        assert!(!WeightVerification::new(&old_weights, computation.clone(), 1).is_match());
+        assert!(WeightVerification::new(&old_weights, computation, 2).is_match());
+    }
 }
@@ -0,0 +1,89 @@
+//! Discovery of documents in folder hierarchies in the format from CollecTor.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{offset::TimeZone, DateTime, Duration, NaiveDateTime, Utc};
+use glob::glob;
+use thiserror;
+
+/// Pattern of the consensus files in a CollecTor tree, relative to its root
+pub const DEFAULT_CONSENSUS_PATTERN: &str = "consensuses-*-*/*/*-consensus";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CollectorError {
+    #[error("Invalid file pattern")]
+    PatternError(#[from] glob::PatternError),
+    #[error("No consensus files found")]
+    NoConsensuses,
+}
+
+/// Consensus files, by their valid-after time
+pub type ConsensusFiles = BTreeMap<DateTime<Utc>, PathBuf>;
+
+/// Find the consensus files below `dir` that match any of the `patterns`
+/// (relative to `dir`). The file names have to start with the valid-after
+/// time as in CollecTor; other files are skipped with a warning.
+pub fn find_consensuses<P: AsRef<Path>, S: AsRef<str>>(
+    dir: P,
+    patterns: &[S],
+) -> Result<ConsensusFiles, CollectorError> {
+    let mut files = BTreeMap::new();
+    for pattern in patterns {
+        let glob_expr = dir
+            .as_ref()
+            .join(pattern.as_ref())
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        for entry in glob(&glob_expr)? {
+            let path = match entry {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!("[Warning] When searching for consensuses: {:?}", e);
+                    continue;
+                }
+            };
+            match valid_after_from_file_name(&path) {
+                Some(valid_after) => {
+                    files.insert(valid_after, path);
+                }
+                None => {
+                    eprintln!(
+                        "[Warning] No valid-after time in file name: {}",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Err(CollectorError::NoConsensuses);
+    }
+    Ok(files)
+}
+
+/// Parse the valid-after time at the start of a consensus file name
+fn valid_after_from_file_name(path: &Path) -> Option<DateTime<Utc>> {
+    let name = path.file_name()?.to_str()?;
+    let time = NaiveDateTime::parse_from_str(name.get(..19)?, "%Y-%m-%d-%H-%M-%S").ok()?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+/// Keep only one consensus per `interval`, starting with the first one
+pub fn sample_consensuses(files: ConsensusFiles, interval: Duration) -> ConsensusFiles {
+    let mut next = None;
+    files
+        .into_iter()
+        .filter(|(valid_after, _)| {
+            if next.map_or(true, |next| *valid_after >= next) {
+                next = Some(*valid_after + interval);
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
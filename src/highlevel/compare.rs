@@ -0,0 +1,301 @@
+//! Similarity metrics between two consensuses, e.g. a scaled consensus and
+//! the real consensus it is supposed to resemble.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use super::selection::PositionClass;
+use super::stats::{earth_movers_distance, ks_statistic, quantile, top_share};
+use super::Consensus;
+
+/// Quantiles of the bandwidth distribution that are compared
+const BANDWIDTH_QUANTILES: [f64; 7] = [0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0];
+
+/// Values per class of relays
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClassShares {
+    pub exit: f64,
+    pub guard: f64,
+    pub guard_exit: f64,
+    pub middle: f64,
+}
+
+impl ClassShares {
+    fn get_mut(&mut self, class: PositionClass) -> &mut f64 {
+        match class {
+            PositionClass::Exit => &mut self.exit,
+            PositionClass::Guard => &mut self.guard,
+            PositionClass::GuardExit => &mut self.guard_exit,
+            PositionClass::Middle => &mut self.middle,
+        }
+    }
+
+    fn values(&self) -> [f64; 4] {
+        [self.exit, self.guard, self.guard_exit, self.middle]
+    }
+
+    /// Divide all values by their sum
+    fn normalize(&mut self) {
+        let total: f64 = self.values().iter().sum();
+        if total > 0.0 {
+            self.exit /= total;
+            self.guard /= total;
+            self.guard_exit /= total;
+            self.middle /= total;
+        }
+    }
+}
+
+/// The distributions of a consensus that are relevant for comparing it to
+/// another one
+#[derive(Debug, Clone)]
+pub struct ConsensusProfile {
+    /// Bandwidth weights of all relays, sorted ascendingly
+    pub bandwidths: Vec<f64>,
+    /// Share of relays in each class
+    pub class_relays: ClassShares,
+    /// Share of bandwidth weight in each class
+    pub class_bandwidth: ClassShares,
+    /// Number of families of each size
+    pub family_sizes: BTreeMap<usize, usize>,
+    /// Bandwidth weight of each AS
+    pub as_bandwidth: BTreeMap<u32, f64>,
+    /// Bandwidth weights (Wgg etc.) of the consensus
+    pub weights: BTreeMap<String, u64>,
+}
+
+impl ConsensusProfile {
+    pub fn new(consensus: &Consensus) -> ConsensusProfile {
+        let mut bandwidths: Vec<f64> = consensus
+            .relays
+            .values()
+            .map(|r| r.bandwidth_weight as f64)
+            .collect();
+        bandwidths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let mut class_relays = ClassShares::default();
+        let mut class_bandwidth = ClassShares::default();
+        let mut as_bandwidth = BTreeMap::new();
+        for relay in consensus.relays.values() {
+            let class = relay.position_class();
+            *class_relays.get_mut(class) += 1.0;
+            *class_bandwidth.get_mut(class) += relay.bandwidth_weight as f64;
+            if let Some(ref asn) = relay.asn {
+                *as_bandwidth.entry(asn.number).or_default() += relay.bandwidth_weight as f64;
+            }
+        }
+        class_relays.normalize();
+        class_bandwidth.normalize();
+
+        ConsensusProfile {
+            bandwidths,
+            class_relays,
+            class_bandwidth,
+            family_sizes: consensus.family_sizes.iter().copied().collect(),
+            as_bandwidth,
+            weights: consensus.weights.clone(),
+        }
+    }
+}
+
+/// Value of a quantile of the bandwidth distribution in both consensuses
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantileError {
+    pub quantile: f64,
+    pub reference: f64,
+    pub candidate: f64,
+    /// Deviation of the candidate, relative to the reference value
+    pub relative_error: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthComparison {
+    pub reference_total: f64,
+    pub candidate_total: f64,
+    pub ks_statistic: Option<f64>,
+    pub earth_movers_distance: Option<f64>,
+    /// Earth mover's distance relative to the mean bandwidth of the
+    /// reference
+    pub relative_earth_movers_distance: Option<f64>,
+    pub quantiles: Vec<QuantileError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassComparison {
+    pub reference: ClassShares,
+    pub candidate: ClassShares,
+    /// Largest absolute difference of the shares of a class
+    pub max_difference: f64,
+}
+
+impl ClassComparison {
+    fn new(reference: &ClassShares, candidate: &ClassShares) -> ClassComparison {
+        let max_difference = std::iter::zip(reference.values(), candidate.values())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        ClassComparison {
+            reference: reference.clone(),
+            candidate: candidate.clone(),
+            max_difference,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FamilySizeComparison {
+    pub reference: BTreeMap<usize, usize>,
+    pub candidate: BTreeMap<usize, usize>,
+    /// Total variation distance of the family size distributions
+    pub total_variation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AsComparison {
+    pub reference_ases: usize,
+    pub candidate_ases: usize,
+    /// Total variation distance of the distributions of bandwidth among ASes
+    pub total_variation: Option<f64>,
+    /// Share of bandwidth of the 10 largest ASes of the reference
+    pub reference_top10_share: Option<f64>,
+    /// Share of bandwidth of the 10 largest ASes of the candidate
+    pub candidate_top10_share: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightComparison {
+    pub key: String,
+    pub reference: Option<u64>,
+    pub candidate: Option<u64>,
+}
+
+/// Similarity of a candidate consensus to a reference consensus
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarityReport {
+    pub reference_relays: usize,
+    pub candidate_relays: usize,
+    pub bandwidth: BandwidthComparison,
+    pub class_relay_shares: ClassComparison,
+    pub class_bandwidth_shares: ClassComparison,
+    pub family_sizes: FamilySizeComparison,
+    pub ases: AsComparison,
+    pub weights: Vec<WeightComparison>,
+    /// Largest absolute difference of a bandwidth weight
+    pub max_weight_difference: u64,
+}
+
+/// Total variation distance between two distributions given as (not
+/// necessarily normalized) weights per key
+fn total_variation<K: Ord>(a: &BTreeMap<K, f64>, b: &BTreeMap<K, f64>) -> Option<f64> {
+    let total_a: f64 = a.values().sum();
+    let total_b: f64 = b.values().sum();
+    if total_a <= 0.0 || total_b <= 0.0 {
+        return None;
+    }
+    let keys: BTreeSet<&K> = a.keys().chain(b.keys()).collect();
+    let sum: f64 = keys
+        .into_iter()
+        .map(|k| {
+            let p = a.get(k).copied().unwrap_or(0.0) / total_a;
+            let q = b.get(k).copied().unwrap_or(0.0) / total_b;
+            (p - q).abs()
+        })
+        .sum();
+    Some(sum / 2.0)
+}
+
+/// Compare the candidate consensus to the reference
+pub fn compare(reference: &ConsensusProfile, candidate: &ConsensusProfile) -> SimilarityReport {
+    // bandwidth distribution
+    let emd = earth_movers_distance(&reference.bandwidths, &candidate.bandwidths);
+    let reference_total: f64 = reference.bandwidths.iter().sum();
+    let reference_mean = reference_total / reference.bandwidths.len() as f64;
+    let quantiles = BANDWIDTH_QUANTILES
+        .iter()
+        .filter_map(|&q| {
+            let reference = quantile(&reference.bandwidths, q)?;
+            let candidate = quantile(&candidate.bandwidths, q)?;
+            Some(QuantileError {
+                quantile: q,
+                reference,
+                candidate,
+                relative_error: (candidate - reference) / reference,
+            })
+        })
+        .collect();
+    let bandwidth = BandwidthComparison {
+        reference_total,
+        candidate_total: candidate.bandwidths.iter().sum(),
+        ks_statistic: ks_statistic(&reference.bandwidths, &candidate.bandwidths),
+        earth_movers_distance: emd,
+        relative_earth_movers_distance: emd.map(|emd| emd / reference_mean),
+        quantiles,
+    };
+
+    // families
+    let as_weights = |sizes: &BTreeMap<usize, usize>| -> BTreeMap<usize, f64> {
+        sizes.iter().map(|(k, v)| (*k, *v as f64)).collect()
+    };
+    let family_sizes = FamilySizeComparison {
+        reference: reference.family_sizes.clone(),
+        candidate: candidate.family_sizes.clone(),
+        total_variation: total_variation(
+            &as_weights(&reference.family_sizes),
+            &as_weights(&candidate.family_sizes),
+        ),
+    };
+
+    // ASes
+    let top10 = |profile: &ConsensusProfile| {
+        top_share(
+            &profile.as_bandwidth.values().copied().collect::<Vec<_>>(),
+            10,
+        )
+    };
+    let ases = AsComparison {
+        reference_ases: reference.as_bandwidth.len(),
+        candidate_ases: candidate.as_bandwidth.len(),
+        total_variation: total_variation(&reference.as_bandwidth, &candidate.as_bandwidth),
+        reference_top10_share: top10(reference),
+        candidate_top10_share: top10(candidate),
+    };
+
+    // bandwidth weights
+    let keys: BTreeSet<&String> = reference
+        .weights
+        .keys()
+        .chain(candidate.weights.keys())
+        .collect();
+    let weights: Vec<WeightComparison> = keys
+        .into_iter()
+        .map(|key| WeightComparison {
+            key: key.clone(),
+            reference: reference.weights.get(key).copied(),
+            candidate: candidate.weights.get(key).copied(),
+        })
+        .collect();
+    let max_weight_difference = weights
+        .iter()
+        .map(|w| match (w.reference, w.candidate) {
+            (Some(a), Some(b)) => a.abs_diff(b),
+            (Some(x), None) | (None, Some(x)) => x,
+            (None, None) => 0,
+        })
+        .max()
+        .unwrap_or(0);
+
+    SimilarityReport {
+        reference_relays: reference.bandwidths.len(),
+        candidate_relays: candidate.bandwidths.len(),
+        bandwidth,
+        class_relay_shares: ClassComparison::new(&reference.class_relays, &candidate.class_relays),
+        class_bandwidth_shares: ClassComparison::new(
+            &reference.class_bandwidth,
+            &candidate.class_bandwidth,
+        ),
+        family_sizes,
+        ases,
+        weights,
+        max_weight_difference,
+    }
+}
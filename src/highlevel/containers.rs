@@ -1,13 +1,15 @@
 //! Helpers to work with parsed Tor data on a high level.
 
 // std
+use std::cmp::min;
 use std::collections::hash_map::Entry;
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::net::Ipv4Addr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
 
 // external dependencies
 use anyhow;
@@ -15,13 +17,18 @@ use anyhow::Context;
 use chrono::{DateTime, Utc};
 use fromsuper::FromSuper;
 use itertools;
+use rayon::prelude::*;
 use regex::Regex;
 
 // local modules
 use super::asn::{Asn, AsnDb};
-use super::bwweights;
+use super::bwweights::{self, WeightComputation, WeightVerification};
+use super::diversity::{self, DiversityMetrics};
 use super::families;
 use super::families::Family;
+use super::header::ConsensusHeader;
+use super::scale::Lineage;
+use super::selection::{self, PositionClass, PositionProbabilities};
 // use crate::parser;
 // use crate::parser::consensus::ConsensusDocument;
 // use crate::parser::consensus::{
@@ -40,11 +47,16 @@ use tordoc::{Consensus as ConsensusDocument, Descriptor, Fingerprint};
 
 use seeded_rand::{RHashMap, RHashSet};
 
+/// Maximum bandwidth value that Tor accepts in descriptors
+const MAX_DESCRIPTOR_BANDWIDTH: u64 = 2147483500;
+
 /// A container for the result of merging a consensus document and the
 /// respective relay server descriptors.
 #[derive(Debug)]
 pub struct Consensus {
     pub valid_after: DateTime<Utc>,
+    /// Header items to use when writing the consensus
+    pub header: ConsensusHeader,
     pub weights: BTreeMap<String, u64>,
     pub relays: RHashMap<Fingerprint, Relay>,
     pub families: Vec<Rc<Family>>,
@@ -83,6 +95,75 @@ pub struct UnpackedConsensus {
     valid_after: DateTime<Utc>,
     relays: Vec<UnpackedRelay>,
     weights: Option<BTreeMap<String, u64>>,
+    header: ConsensusHeader,
+}
+
+impl FromStr for UnpackedConsensus {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Parse a raw consensus document, including the header items not
+    /// covered by tordoc
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        UnpackedConsensus::from_document(ConsensusDocument::from_str(raw)?, raw)
+    }
+}
+
+impl UnpackedConsensus {
+    /// Unpack an already parsed consensus document. `raw` is the document it
+    /// was parsed from, to obtain the header items not covered by tordoc.
+    pub fn from_document(
+        document: ConsensusDocument,
+        raw: &str,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut consensus = UnpackedConsensus::try_from(document)?;
+        consensus.header = ConsensusHeader::from_raw(raw);
+        Ok(consensus)
+    }
+
+    /// Number of relays in the consensus
+    pub fn num_relays(&self) -> usize {
+        self.relays.len()
+    }
+
+    /// Recompute the bandwidth weights from all relays of the consensus and
+    /// compare them to the contained ones, allowing each weight to deviate by
+    /// `tolerance`. Unlike [Consensus::verify_weights], this also covers
+    /// relays whose descriptor is missing.
+    pub fn verify_weights(&self, tolerance: u64) -> WeightVerification {
+        let computation = bwweights::compute_bw_weights_for(self.relays.iter().map(|relay| {
+            (
+                PositionClass::from_flags(&relay.flags),
+                relay.bandwidth_weight,
+            )
+        }));
+        WeightVerification::new(
+            &self.weights.clone().unwrap_or_default(),
+            computation,
+            tolerance,
+        )
+    }
+
+    /// Remove the relays whose descriptor is not among `descriptors`, so the
+    /// consensus can be combined with them. Returns the fingerprints of the
+    /// removed relays.
+    pub fn remove_relays_without_descriptors(
+        &mut self,
+        descriptors: &[Descriptor],
+    ) -> Vec<Fingerprint> {
+        let digests: RHashSet<&Fingerprint> = descriptors
+            .iter()
+            .filter_map(|d| d.digest.as_ref())
+            .collect();
+        let mut removed = Vec::new();
+        self.relays.retain(|relay| {
+            let keep = digests.contains(&relay.digest);
+            if !keep {
+                removed.push(relay.fingerprint.clone());
+            }
+            keep
+        });
+        removed
+    }
 }
 
 impl TryFrom<ConsensusDocument> for UnpackedConsensus {
@@ -99,6 +180,7 @@ impl TryFrom<ConsensusDocument> for UnpackedConsensus {
                 .map(UnpackedRelay::try_from)
                 .collect::<Result<Vec<_>, _>>()?,
             weights: value.weights,
+            header: ConsensusHeader::default(),
         })
     }
 }
@@ -138,6 +220,13 @@ pub struct Relay {
     pub bw_ratio_burst: f32,
     pub bw_ratio_observed: f32,
     pub bw_observed_was_zero: bool,
+    // from scaling
+    /// Origin of the relay if it was created synthetically, `None` for
+    /// relays of the original consensus
+    pub lineage: Option<Lineage>,
+    /// Fingerprint of the relay before it was assigned a new one (e.g. for
+    /// generated keys), `None` if it was not changed
+    pub original_fingerprint: Option<Fingerprint>,
 }
 
 impl Relay {
@@ -178,12 +267,161 @@ impl Relay {
             bw_ratio_observed: descriptor.bandwidth_observed as f32
                 / cons_relay.bandwidth_weight as f32,
             bw_observed_was_zero: descriptor.bandwidth_observed == 0,
+            lineage: None,
+            original_fingerprint: None,
         }
     }
 
     pub fn has_flag(&self, flag: Flag) -> bool {
         self.flags.contains(&flag)
     }
+
+    /// Whether the relay was created by scaling
+    pub fn is_synthetic(&self) -> bool {
+        self.lineage.is_some()
+    }
+
+    /// Average bandwidth (in bytes/s) to announce in the descriptor
+    pub fn bandwidth_avg(&self) -> u64 {
+        self.descriptor_bandwidth(self.bw_ratio_avg)
+    }
+
+    /// Burst bandwidth (in bytes/s) to announce in the descriptor
+    pub fn bandwidth_burst(&self) -> u64 {
+        self.descriptor_bandwidth(self.bw_ratio_burst)
+    }
+
+    /// Observed bandwidth (in bytes/s) to announce in the descriptor
+    pub fn bandwidth_observed(&self) -> u64 {
+        self.descriptor_bandwidth(self.bw_ratio_observed)
+    }
+
+    fn descriptor_bandwidth(&self, ratio: f32) -> u64 {
+        min(
+            (self.bandwidth_weight as f32 * ratio) as u64,
+            MAX_DESCRIPTOR_BANDWIDTH,
+        )
+    }
+
+    /// The class of this relay regarding the bandwidth weights. Exits with the
+    /// BadExit flag are not considered to be exits.
+    pub fn position_class(&self) -> PositionClass {
+        PositionClass::from_flags(&self.flags)
+    }
+}
+
+#[cfg(test)]
+impl Relay {
+    /// A relay for unit tests with the fingerprint `[id; 20]`, parsed from a
+    /// minimal consensus entry. `flags` and `exit_policy` are given as in the
+    /// consensus, e.g. `"Fast Guard Running Valid"` and `"accept 80,443"`.
+    pub(crate) fn for_test(
+        id: u8,
+        address: &str,
+        flags: &str,
+        bandwidth: u64,
+        exit_policy: &str,
+    ) -> Relay {
+        let fingerprint = Fingerprint::from_u8(&[id; 20]).to_string_b64();
+        let raw = format!(
+            "network-status-version 3
+vote-status consensus
+consensus-method 32
+valid-after 2023-03-01 12:00:00
+fresh-until 2023-03-01 13:00:00
+valid-until 2023-03-01 15:00:00
+voting-delay 300 300
+known-flags {}
+r relay{} {} {} 2023-03-01 11:00:00 {} 9001 0
+s {}
+w Bandwidth={}
+p {}
+directory-footer
+",
+            Flag::known_flags_string(),
+            id,
+            fingerprint,
+            fingerprint,
+            address,
+            flags,
+            bandwidth,
+            exit_policy
+        );
+        let mut consensus: UnpackedConsensus = raw.parse().unwrap();
+        let relay = consensus.relays.pop().unwrap();
+
+        Relay {
+            nickname: relay.nickname,
+            fingerprint: relay.fingerprint,
+            digest: relay.digest,
+            published: relay.published,
+            address: relay.address,
+            asn: None,
+            or_port: relay.or_port,
+            dir_port: relay.dir_port,
+            flags: relay.flags,
+            version_line: relay.version_line,
+            protocols: relay.protocols,
+            exit_policy: relay.exit_policy,
+            bandwidth_weight: relay.bandwidth_weight,
+            family: None,
+            bw_ratio_avg: 1.0,
+            bw_ratio_burst: 1.0,
+            bw_ratio_observed: 1.0,
+            bw_observed_was_zero: false,
+            lineage: None,
+            original_fingerprint: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Consensus {
+    /// A consensus for unit tests. `families` lists the ids of the members of
+    /// each family (see [Relay::for_test]). If an AS database is given, the
+    /// ASes of the relays are looked up in it. The bandwidth weights are
+    /// computed from the relays.
+    pub(crate) fn for_test(
+        relays: Vec<Relay>,
+        families: &[&[u8]],
+        asn_db: Option<&AsnDb>,
+    ) -> Consensus {
+        let mut relays: RHashMap<Fingerprint, Relay> = relays
+            .into_iter()
+            .map(|mut relay| {
+                relay.asn = asn_db.and_then(|db| db.lookup(relay.address));
+                (relay.fingerprint.clone(), relay)
+            })
+            .collect();
+        for ids in families {
+            let family = Rc::new(Family {
+                members: ids
+                    .iter()
+                    .map(|id| Fingerprint::from_u8(&[*id; 20]))
+                    .collect(),
+            });
+            for fingerprint in family.members.iter() {
+                relays.get_mut(fingerprint).unwrap().family = Some(family.clone());
+            }
+        }
+        let families = families::recompute_families(&mut relays);
+
+        let mut consensus = Consensus {
+            valid_after: DateTime::parse_from_rfc3339("2023-03-01T12:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            header: ConsensusHeader::default(),
+            weights: BTreeMap::new(),
+            relays,
+            families,
+            prob_family: 0.0,
+            prob_family_sameas: 0.0,
+            family_sizes: Vec::new(),
+        };
+        consensus.recompute_bw_weights();
+        consensus.recompute_stats();
+        consensus
+    }
 }
 
 impl Consensus {
@@ -317,6 +555,7 @@ impl Consensus {
 
         let mut res = Consensus {
             valid_after: consensus.valid_after,
+            header: consensus.header,
             weights: weights,
             relays: relays,
             families: family_objects,
@@ -334,10 +573,22 @@ impl Consensus {
         Ok(res)
     }
 
-    pub fn recompute_bw_weights(&mut self) {
+    pub fn recompute_bw_weights(&mut self) -> WeightComputation {
         bwweights::recompute_bw_weights(self)
     }
 
+    /// Compute each relay's probability of being chosen as guard, middle
+    /// and exit
+    pub fn selection_probabilities(&self) -> RHashMap<Fingerprint, PositionProbabilities> {
+        selection::selection_probabilities(self)
+    }
+
+    /// Compute the diversity of the relays for the guard, middle and exit
+    /// positions
+    pub fn diversity_metrics(&self) -> DiversityMetrics {
+        diversity::diversity_metrics(self)
+    }
+
     pub fn print_stats(&self) {
         let with_asn = {
             let mut res = 0;
@@ -400,20 +651,12 @@ impl Consensus {
         self.family_sizes = family_sizes(&self.families);
     }
 
-    /// Recompute and verify the contained bandwidth weights
-    pub fn verify_weights(&mut self) -> Result<(), String> {
-        let old_weights = self.weights.clone();
-        self.recompute_bw_weights();
-        let new_weights = &self.weights;
-
-        if old_weights == *new_weights {
-            return Ok(());
-        } else {
-            return Err(format!(
-                "old weights: {:?}\nnew weights: {:?}",
-                old_weights, new_weights
-            ));
-        }
+    /// Recompute and verify the contained bandwidth weights, allowing each
+    /// weight to deviate by `tolerance`. The contained weights are left
+    /// unchanged.
+    pub fn verify_weights(&self, tolerance: u64) -> WeightVerification {
+        let computation = bwweights::compute_bw_weights(self);
+        WeightVerification::new(&self.weights, computation, tolerance)
     }
 
     /// Remove all relays from the consensus that meet a certain condition
@@ -428,6 +671,27 @@ impl Consensus {
         self.recompute_bw_weights();
         self.recompute_stats();
     }
+
+    /// Assign new fingerprints to relays, given a mapping from old to new
+    /// fingerprints. Relays not contained in the mapping keep their fingerprint.
+    /// The first fingerprint of a changed relay is kept as its
+    /// `original_fingerprint`. Lineages keep referring to the old fingerprints.
+    pub fn change_fingerprints(&mut self, mapping: &RHashMap<Fingerprint, Fingerprint>) {
+        let old_relays = std::mem::take(&mut self.relays);
+        for (old_fingerprint, mut relay) in old_relays.into_iter() {
+            let fingerprint = mapping.get(&old_fingerprint).unwrap_or(&old_fingerprint);
+            if *fingerprint != old_fingerprint {
+                relay
+                    .original_fingerprint
+                    .get_or_insert_with(|| old_fingerprint.clone());
+            }
+            relay.fingerprint = fingerprint.clone();
+            self.relays.insert(fingerprint.clone(), relay);
+        }
+
+        // family objects list their members by fingerprint
+        self.families = families::recompute_families(&mut self.relays);
+    }
 }
 
 fn prob_family(relays: &RHashMap<Fingerprint, Relay>) -> f32 {
@@ -464,12 +728,9 @@ fn family_sizes(family_objects: &Vec<Rc<Family>>) -> Vec<(usize, usize)> {
     families::size_histogram(&family_objects)
 }
 
-/// Load descriptors from files relative to the consensus document
-pub fn lookup_descriptors<P: AsRef<Path>>(
-    consensus: &UnpackedConsensus,
-    consensus_path: P,
-) -> anyhow::Result<Vec<Descriptor>> {
-    let consensus_path = consensus_path.as_ref();
+/// Find the descriptor folders for a consensus document (of the current month
+/// and the one before)
+fn descriptor_dirs(consensus_path: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
     // get year and month
     let fname_regex = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})-").unwrap();
     let fname_match = fname_regex
@@ -512,53 +773,124 @@ pub fn lookup_descriptors<P: AsRef<Path>>(
             previous_year, previous_month
         ));
 
-    // Lookup the descriptors
-    let mut descriptors = Vec::new();
-    for relay in consensus.relays.iter() {
-        let digest = format!("{}", relay.digest);
-        let first_char = digest.chars().next().unwrap();
-        let second_char = digest.chars().skip(1).next().unwrap();
-
-        let subpath = format!("{}/{}/{}", first_char, second_char, digest);
-        let current_path = current_desc.join(&subpath);
-        let previous_path = previous_desc.join(&subpath);
-
-        let desc_path = if current_path.exists() {
-            current_path
-        } else if previous_path.exists() {
-            previous_path
-        } else {
-            return Err(anyhow::anyhow!(DocumentCombiningError::MissingDescriptor {
-                digest: relay.digest.to_string_hex(),
-            })
-            .context(format!(
-                "looking up {} or {}",
-                current_desc.display(),
-                previous_desc.display()
-            )));
-        };
+    Ok((current_desc, previous_desc))
+}
 
-        let descriptor = {
-            use std::str;
-
-            let mut raw = Vec::new();
-            let mut file = File::open(&desc_path).unwrap();
-            file.read_to_end(&mut raw).unwrap();
-
-            match str::from_utf8(&raw) {
-                Ok(text) => Descriptor::from_str(text).context(format!(
-                    "parsing descriptor {}",
-                    desc_path.canonicalize().unwrap().display()
-                ))?,
-                Err(_) => {
-                    // invalid UTF-8
-                    Descriptor::from_bytes_lossy(&raw)?
-                }
-            }
-        };
+/// Path of the descriptor with the given digest, if it exists in one of the
+/// descriptor folders
+fn find_descriptor(
+    current_desc: &Path,
+    previous_desc: &Path,
+    digest: &Fingerprint,
+) -> Option<PathBuf> {
+    let digest = format!("{}", digest);
+    let first_char = digest.chars().next().unwrap();
+    let second_char = digest.chars().skip(1).next().unwrap();
+
+    let subpath = format!("{}/{}/{}", first_char, second_char, digest);
+    let current_path = current_desc.join(&subpath);
+    let previous_path = previous_desc.join(&subpath);
+
+    if current_path.exists() {
+        Some(current_path)
+    } else if previous_path.exists() {
+        Some(previous_path)
+    } else {
+        None
+    }
+}
+
+/// Read and parse a descriptor file
+fn read_descriptor(desc_path: &Path) -> anyhow::Result<Descriptor> {
+    use std::str;
+
+    let mut raw = Vec::new();
+    let mut file =
+        File::open(desc_path).context(format!("opening descriptor {}", desc_path.display()))?;
+    file.read_to_end(&mut raw)
+        .context(format!("reading descriptor {}", desc_path.display()))?;
+
+    Ok(match str::from_utf8(&raw) {
+        Ok(text) => Descriptor::from_str(text)
+            .context(format!("parsing descriptor {}", desc_path.display()))?,
+        Err(_) => {
+            // invalid UTF-8
+            Descriptor::from_bytes_lossy(&raw)?
+        }
+    })
+}
+
+/// Paths of the descriptor files relative to the consensus document, in the
+/// order of the relays. Relays without a descriptor file are left out.
+pub fn descriptor_paths<P: AsRef<Path>>(
+    consensus: &UnpackedConsensus,
+    consensus_path: P,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let (current_desc, previous_desc) = descriptor_dirs(consensus_path.as_ref())?;
+
+    Ok(consensus
+        .relays
+        .iter()
+        .filter_map(|relay| find_descriptor(&current_desc, &previous_desc, &relay.digest))
+        .collect())
+}
+
+/// Load descriptors from files relative to the consensus document
+pub fn lookup_descriptors<P: AsRef<Path>>(
+    consensus: &UnpackedConsensus,
+    consensus_path: P,
+) -> anyhow::Result<Vec<Descriptor>> {
+    let (current_desc, previous_desc) = descriptor_dirs(consensus_path.as_ref())?;
 
-        descriptors.push(descriptor);
+    // Lookup the descriptors
+    let mut desc_paths = Vec::with_capacity(consensus.relays.len());
+    for relay in consensus.relays.iter() {
+        let desc_path =
+            find_descriptor(&current_desc, &previous_desc, &relay.digest).ok_or_else(|| {
+                anyhow::anyhow!(DocumentCombiningError::MissingDescriptor {
+                    digest: relay.digest.to_string_hex(),
+                })
+                .context(format!(
+                    "looking up {} or {}",
+                    current_desc.display(),
+                    previous_desc.display()
+                ))
+            })?;
+        desc_paths.push(desc_path);
     }
 
+    // Parse the descriptors in parallel, keeping the order of the relays
+    let descriptors = desc_paths
+        .par_iter()
+        .map(|desc_path| read_descriptor(desc_path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
     Ok(descriptors)
 }
+
+/// Like [lookup_descriptors], but skip descriptors that are missing or cannot
+/// be parsed instead of failing. Use
+/// [UnpackedConsensus::remove_relays_without_descriptors] to be able to
+/// combine the consensus with the descriptors found.
+pub fn lookup_descriptors_lenient<P: AsRef<Path>>(
+    consensus: &UnpackedConsensus,
+    consensus_path: P,
+) -> anyhow::Result<Vec<Descriptor>> {
+    let desc_paths = descriptor_paths(consensus, consensus_path)?;
+
+    let descriptors = desc_paths
+        .par_iter()
+        .map(|desc_path| read_descriptor(desc_path))
+        .collect::<Vec<_>>();
+
+    Ok(descriptors
+        .into_iter()
+        .filter_map(|descriptor| match descriptor {
+            Ok(descriptor) => Some(descriptor),
+            Err(e) => {
+                eprintln!("[Warning] Skipping descriptor: {:?}", e);
+                None
+            }
+        })
+        .collect())
+}
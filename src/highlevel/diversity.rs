@@ -0,0 +1,214 @@
+//! Diversity metrics of the relays available for the positions of a
+//! circuit, as a measure of the anonymity a network provides.
+
+use std::rc::Rc;
+
+use seeded_rand::RHashMap;
+use serde::Serialize;
+
+use super::stats::{count_to_reach, entropy, gini, normalized_entropy};
+use super::Consensus;
+
+/// Share of the selection probability that the counts in
+/// [PositionDiversity] refer to
+const MAJORITY_SHARE: f64 = 0.5;
+
+/// Diversity of the relays for a single position of a circuit
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionDiversity {
+    /// Number of relays with a non-zero selection probability
+    pub relays: usize,
+    /// Shannon entropy (in bits) of the selection probabilities
+    pub entropy: Option<f64>,
+    /// Entropy relative to choosing uniformly among the relays
+    pub normalized_entropy: Option<f64>,
+    /// Number of relays that make up half of the selection probability
+    pub relays_for_half: Option<usize>,
+    /// Number of ASes that make up half of the selection probability.
+    /// Relays without a known AS count as an AS of their own.
+    pub ases_for_half: Option<usize>,
+    /// Number of families that make up half of the selection probability.
+    /// Relays without a family count as a family of their own.
+    pub families_for_half: Option<usize>,
+}
+
+/// Diversity metrics of a consensus
+#[derive(Debug, Clone, Serialize)]
+pub struct DiversityMetrics {
+    pub guard: PositionDiversity,
+    pub middle: PositionDiversity,
+    pub exit: PositionDiversity,
+    /// Gini coefficient of the bandwidth weights of all relays
+    pub bandwidth_gini: Option<f64>,
+}
+
+/// Group of relays that a relay belongs to, for counting ASes and families
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum Group {
+    As(u32),
+    Family(usize),
+    Relay(usize),
+}
+
+fn position_diversity(probabilities: &[(Group, Group, f64)]) -> PositionDiversity {
+    let values: Vec<f64> = probabilities.iter().map(|(_, _, p)| *p).collect();
+    let group_values = |group_of: fn(&(Group, Group, f64)) -> &Group| -> Vec<f64> {
+        let mut groups: RHashMap<&Group, f64> = RHashMap::default();
+        for entry in probabilities.iter() {
+            *groups.entry(group_of(entry)).or_default() += entry.2;
+        }
+        groups.into_values().collect()
+    };
+
+    PositionDiversity {
+        relays: values.iter().filter(|p| **p > 0.0).count(),
+        entropy: entropy(&values),
+        normalized_entropy: normalized_entropy(&values),
+        relays_for_half: count_to_reach(&values, MAJORITY_SHARE),
+        ases_for_half: count_to_reach(&group_values(|(asn, _, _)| asn), MAJORITY_SHARE),
+        families_for_half: count_to_reach(&group_values(|(_, family, _)| family), MAJORITY_SHARE),
+    }
+}
+
+/// Compute the diversity metrics of the guard, middle and exit positions
+pub fn diversity_metrics(consensus: &Consensus) -> DiversityMetrics {
+    let probabilities = consensus.selection_probabilities();
+
+    let mut guard = Vec::with_capacity(probabilities.len());
+    let mut middle = Vec::with_capacity(probabilities.len());
+    let mut exit = Vec::with_capacity(probabilities.len());
+    for (i, (fp, prob)) in probabilities.iter().enumerate() {
+        let relay = &consensus.relays[fp];
+        let asn = match relay.asn {
+            Some(ref asn) => Group::As(asn.number),
+            None => Group::Relay(i),
+        };
+        let family = match relay.family {
+            Some(ref family) => Group::Family(Rc::as_ptr(family) as usize),
+            None => Group::Relay(i),
+        };
+        guard.push((asn, family, prob.guard));
+        middle.push((asn, family, prob.middle));
+        exit.push((asn, family, prob.exit));
+    }
+
+    let bandwidths: Vec<f64> = consensus
+        .relays
+        .values()
+        .map(|r| r.bandwidth_weight as f64)
+        .collect();
+
+    DiversityMetrics {
+        guard: position_diversity(&guard),
+        middle: position_diversity(&middle),
+        exit: position_diversity(&exit),
+        bandwidth_gini: gini(&bandwidths),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::highlevel::asn::AsnDb;
+    use crate::highlevel::Relay;
+
+    const ASN_DB: &str = "network,autonomous_system_number,autonomous_system_organization
+10.1.0.0/16,1,AS One
+10.2.0.0/16,2,AS Two
+10.3.0.0/16,2,AS Two
+10.5.0.0/16,5,AS Five
+10.6.0.0/16,3,AS Three
+10.7.0.0/16,3,AS Three
+10.8.0.0/16,4,AS Four
+";
+
+    /// Four guards (one without a known AS), a middle relay and three exits,
+    /// with all bandwidth weights at full weight
+    fn consensus(asn_db: &AsnDb) -> Consensus {
+        let relay = |id: u8, address: &str, flags: &str, bandwidth| {
+            Relay::for_test(id, address, flags, bandwidth, "accept 80,443")
+        };
+        let guard = "Fast Guard Running Valid";
+        let exit = "Exit Fast Running Valid";
+        let mut consensus = Consensus::for_test(
+            vec![
+                relay(1, "10.1.0.1", guard, 100),
+                relay(2, "10.2.0.1", guard, 100),
+                relay(3, "10.3.0.1", guard, 200),
+                relay(4, "192.168.0.1", guard, 100),
+                relay(5, "10.5.0.1", "Fast Running Valid", 100),
+                relay(6, "10.6.0.1", exit, 200),
+                relay(7, "10.7.0.1", exit, 200),
+                relay(8, "10.8.0.1", exit, 100),
+            ],
+            &[&[1, 2], &[6, 8]],
+            Some(asn_db),
+        );
+        consensus.weights = ["Wgg", "Wgd", "Wmg", "Wmm", "Wme", "Wmd", "Wee", "Wed"]
+            .into_iter()
+            .map(|k| (k.to_string(), 10000))
+            .collect();
+        consensus
+    }
+
+    fn assert_close(a: Option<f64>, b: f64) {
+        let a = a.unwrap();
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn positions() {
+        let asn_db = AsnDb::from_reader(ASN_DB.as_bytes()).unwrap();
+        let metrics = diversity_metrics(&consensus(&asn_db));
+
+        // guard: 0.2, 0.2, 0.4 (AS 2 together with relay 2) and 0.2 (no AS),
+        // relays 1 and 2 are a family
+        let guard = &metrics.guard;
+        assert_eq!(guard.relays, 4);
+        assert_close(guard.entropy, 0.6 * 5f64.log2() + 0.4 * 2.5f64.log2());
+        assert_close(
+            guard.normalized_entropy,
+            (0.6 * 5f64.log2() + 0.4 * 2.5f64.log2()) / 2.0,
+        );
+        assert_eq!(guard.relays_for_half, Some(2));
+        assert_eq!(guard.ases_for_half, Some(1));
+        assert_eq!(guard.families_for_half, Some(2));
+
+        // middle: all relays, three with 2/11 and five with 1/11; ASes 3
+        // (4/11) and 2 (3/11), families {6, 8} (3/11) and {1, 2} (2/11)
+        // followed by relays with 2/11
+        let middle = &metrics.middle;
+        assert_eq!(middle.relays, 8);
+        let entropy = 6.0 / 11.0 * 5.5f64.log2() + 5.0 / 11.0 * 11f64.log2();
+        assert_close(middle.entropy, entropy);
+        assert_close(middle.normalized_entropy, entropy / 3.0);
+        assert_eq!(middle.relays_for_half, Some(3));
+        assert_eq!(middle.ases_for_half, Some(2));
+        assert_eq!(middle.families_for_half, Some(3));
+
+        // exit: 0.4, 0.4 (both AS 3) and 0.2, relays 6 and 8 are a family
+        let exit = &metrics.exit;
+        assert_eq!(exit.relays, 3);
+        assert_close(exit.entropy, 0.8 * 2.5f64.log2() + 0.2 * 5f64.log2());
+        assert_eq!(exit.relays_for_half, Some(2));
+        assert_eq!(exit.ases_for_half, Some(1));
+        assert_eq!(exit.families_for_half, Some(1));
+    }
+
+    #[test]
+    fn without_ases_and_families() {
+        let mut consensus = consensus(&AsnDb::from_reader(ASN_DB.as_bytes()).unwrap());
+        for relay in consensus.relays.values_mut() {
+            relay.asn = None;
+            relay.family = None;
+        }
+        let metrics = diversity_metrics(&consensus);
+
+        // every relay counts as an AS and a family of its own
+        for position in [&metrics.guard, &metrics.middle, &metrics.exit] {
+            assert_eq!(position.ases_for_half, position.relays_for_half);
+            assert_eq!(position.families_for_half, position.relays_for_half);
+        }
+    }
+}
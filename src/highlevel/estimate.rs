@@ -0,0 +1,162 @@
+//! Estimation of scaling parameters from pairs of consensuses.
+
+use serde::Serialize;
+
+use seeded_rand::RHashSet;
+use tordoc::Fingerprint;
+
+use super::stats::wilson_interval;
+use super::Consensus;
+
+/// Standard normal quantile for 95% confidence intervals
+const Z_95: f64 = 1.96;
+
+/// Estimate of the probability that a new relay in a family is part of a
+/// newly formed family rather than joining an existing one
+#[derive(Debug, Clone, Serialize)]
+pub struct FamilyNewEstimate {
+    pub prob_family_new: f64,
+    /// Lower bound of the 95% confidence interval
+    pub ci_lower: f64,
+    /// Upper bound of the 95% confidence interval
+    pub ci_upper: f64,
+    /// Number of relays in the later consensus that are not in the earlier one
+    pub new_relays: usize,
+    /// Number of new relays that joined a family of the earlier consensus
+    pub joined_existing: usize,
+    /// Number of new relays in families that do not correspond to a family
+    /// of the earlier consensus
+    pub formed_new: usize,
+}
+
+/// Estimate `prob_family_new` from an earlier and a later consensus.
+///
+/// New relays are those only contained in the later consensus. A family of
+/// the later consensus that contains new relays is matched to the families
+/// of the earlier consensus by member overlap. If it shares members with
+/// one of them, its new relays joined an existing family, otherwise they
+/// formed a new one. New relays without a family are not considered.
+/// Returns `None` if no new relay is in a family.
+///
+/// Only earlier *family* members count as overlap. If new relays form a
+/// family with a relay that already existed but was not in a family before,
+/// they are counted as forming a new family. This is deliberate: when scaling,
+/// joining always means joining a family that exists already, so a family
+/// without any previous family is new from the model's point of view.
+///
+/// The confidence interval treats all new relays as independent, although
+/// relays joining the same family are not, so it tends to be too narrow.
+pub fn estimate_prob_family_new(
+    earlier: &Consensus,
+    later: &Consensus,
+) -> Option<FamilyNewEstimate> {
+    let new_relays = later
+        .relays
+        .keys()
+        .filter(|fp| !earlier.relays.contains_key(*fp))
+        .count();
+
+    let (joined_existing, formed_new) = count_new_family_members(
+        |fp| earlier.relays.contains_key(fp),
+        earlier.families.iter().map(|family| &family.members[..]),
+        later.families.iter().map(|family| &family.members[..]),
+    );
+
+    estimate_from_counts(new_relays, joined_existing, formed_new)
+}
+
+/// Count the new relays in families of the later consensus that joined an
+/// existing family and that formed a new one, respectively
+fn count_new_family_members<'a>(
+    in_earlier: impl Fn(&Fingerprint) -> bool,
+    earlier_families: impl Iterator<Item = &'a [Fingerprint]>,
+    later_families: impl Iterator<Item = &'a [Fingerprint]>,
+) -> (usize, usize) {
+    // relays that were in a family in the earlier consensus
+    let in_earlier_family: RHashSet<&Fingerprint> = earlier_families.flatten().collect();
+
+    let mut joined_existing = 0;
+    let mut formed_new = 0;
+    for members in later_families {
+        let num_new = members.iter().filter(|fp| !in_earlier(fp)).count();
+        if num_new == 0 {
+            continue;
+        }
+
+        if members.iter().any(|fp| in_earlier_family.contains(fp)) {
+            joined_existing += num_new;
+        } else {
+            formed_new += num_new;
+        }
+    }
+    (joined_existing, formed_new)
+}
+
+fn estimate_from_counts(
+    new_relays: usize,
+    joined_existing: usize,
+    formed_new: usize,
+) -> Option<FamilyNewEstimate> {
+    let trials = joined_existing + formed_new;
+    let (ci_lower, ci_upper) = wilson_interval(formed_new, trials, Z_95)?;
+    Some(FamilyNewEstimate {
+        prob_family_new: formed_new as f64 / trials as f64,
+        ci_lower,
+        ci_upper,
+        new_relays,
+        joined_existing,
+        formed_new,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fp(id: u8) -> Fingerprint {
+        Fingerprint::from_u8(&[id; 20])
+    }
+
+    fn count(earlier: &[Vec<Fingerprint>], later: &[Vec<Fingerprint>]) -> (usize, usize) {
+        let earlier_relays: RHashSet<Fingerprint> = (1..=10).map(fp).collect();
+        count_new_family_members(
+            |fp| earlier_relays.contains(fp),
+            earlier.iter().map(|f| &f[..]),
+            later.iter().map(|f| &f[..]),
+        )
+    }
+
+    #[test]
+    fn joined_and_formed_families() {
+        // relays 1-10 are old, 11 and up are new
+        let earlier = vec![vec![fp(1), fp(2)]];
+        let later = vec![
+            // two new relays joined the family of 1 and 2
+            vec![fp(1), fp(2), fp(11), fp(12)],
+            // three new relays formed a new family
+            vec![fp(13), fp(14), fp(15)],
+            // an old family without new relays does not count
+            vec![fp(3), fp(4)],
+        ];
+        assert_eq!(count(&earlier, &later), (2, 3));
+    }
+
+    #[test]
+    fn old_relay_without_family_forms_new_family() {
+        // relay 3 existed but was not in a family
+        let earlier = vec![vec![fp(1), fp(2)]];
+        let later = vec![vec![fp(3), fp(11), fp(12)]];
+        assert_eq!(count(&earlier, &later), (0, 2));
+    }
+
+    #[test]
+    fn estimate() {
+        let estimate = estimate_from_counts(10, 2, 6).unwrap();
+        assert_eq!(estimate.prob_family_new, 0.75);
+        assert!(estimate.ci_lower < 0.75 && estimate.ci_upper > 0.75);
+        assert_eq!(estimate.new_relays, 10);
+
+        // no new relay in a family
+        assert!(estimate_from_counts(10, 0, 0).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+//! IP -> country lookup using Tor's GeoIP file format.
+
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use thiserror;
+
+#[derive(thiserror::Error, Debug)]
+pub enum GeoIpError {
+    #[error("I/O error when reading the GeoIP file")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid GeoIP line {0}")]
+    InvalidLine(String),
+}
+
+/// A country database as shipped with Tor (e.g. `/usr/share/tor/geoip`).
+/// Each line has the form `INTIPLOW,INTIPHIGH,CC`.
+pub struct GeoIpDb {
+    /// Non-overlapping (first IP, last IP, country code) ranges, sorted by
+    /// their first IP
+    ranges: Vec<(u32, u32, String)>,
+}
+
+impl GeoIpDb {
+    pub fn new<P: AsRef<Path>>(geoip_file: P) -> Result<GeoIpDb, GeoIpError> {
+        GeoIpDb::parse(&fs::read_to_string(geoip_file)?)
+    }
+
+    /// Parse the contents of a GeoIP file
+    pub fn parse(raw: &str) -> Result<GeoIpDb, GeoIpError> {
+        let mut ranges = Vec::new();
+        for line in raw.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || GeoIpError::InvalidLine(line.to_string());
+
+            let mut parts = line.split(',');
+            let mut next_ip = || -> Result<u32, GeoIpError> {
+                parts
+                    .next()
+                    .ok_or_else(invalid)?
+                    .parse()
+                    .map_err(|_| invalid())
+            };
+            let low = next_ip()?;
+            let high = next_ip()?;
+            let country = parts.next().ok_or_else(invalid)?.to_string();
+            if low > high {
+                return Err(invalid());
+            }
+
+            // "??" marks ranges without a known country
+            if country != "??" {
+                ranges.push((low, high, country));
+            }
+        }
+        ranges.sort_by_key(|(low, _, _)| *low);
+
+        Ok(GeoIpDb { ranges })
+    }
+
+    /// The country code (e.g. "DE") of an IP address, if known
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<String> {
+        let ip = u32::from_be_bytes(ip.octets());
+        // the last range starting at or before the IP
+        let index = self.ranges.partition_point(|(low, _, _)| *low <= ip);
+        let (_, high, country) = self.ranges.get(index.checked_sub(1)?)?;
+        if ip <= *high {
+            Some(country.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ranges() {
+        let db = GeoIpDb::parse(
+            "# comment\n16777216,16777471,AU\n16777472,16778239,CN\n16778240,16779263,??\n",
+        )
+        .unwrap();
+        assert_eq!(
+            db.lookup("1.0.0.0".parse().unwrap()),
+            Some("AU".to_string())
+        );
+        assert_eq!(
+            db.lookup("1.0.0.255".parse().unwrap()),
+            Some("AU".to_string())
+        );
+        assert_eq!(
+            db.lookup("1.0.1.0".parse().unwrap()),
+            Some("CN".to_string())
+        );
+        assert_eq!(db.lookup("1.0.8.0".parse().unwrap()), None);
+        assert_eq!(db.lookup("0.255.255.255".parse().unwrap()), None);
+        assert_eq!(db.lookup("8.8.8.8".parse().unwrap()), None);
+        assert!(GeoIpDb::parse("1,2").is_err());
+    }
+}
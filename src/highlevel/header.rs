@@ -0,0 +1,232 @@
+//! Header items of a consensus document that are not covered by tordoc, so
+//! they can be carried over to generated consensus documents.
+
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Default consensus method, if none is known from the input consensus
+pub const DEFAULT_CONSENSUS_METHOD: u32 = 31;
+
+/// Header items of a consensus document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusHeader {
+    pub consensus_method: u32,
+    /// Time between valid-after and fresh-until (in seconds)
+    pub fresh_interval: i64,
+    /// Time between valid-after and valid-until (in seconds)
+    pub valid_interval: i64,
+    /// Seconds for voting and for fetching signatures
+    pub voting_delay: (u32, u32),
+    pub client_versions: Option<String>,
+    pub server_versions: Option<String>,
+    pub recommended_client_protocols: Option<String>,
+    pub recommended_relay_protocols: Option<String>,
+    pub required_client_protocols: Option<String>,
+    pub required_relay_protocols: Option<String>,
+    pub params: Option<String>,
+    /// Number of reveals and value of the previous shared random value
+    pub shared_rand_previous_value: Option<String>,
+    /// Number of reveals and value of the current shared random value
+    pub shared_rand_current_value: Option<String>,
+}
+
+impl Default for ConsensusHeader {
+    fn default() -> Self {
+        ConsensusHeader {
+            consensus_method: DEFAULT_CONSENSUS_METHOD,
+            fresh_interval: 60 * 60,
+            valid_interval: 3 * 60 * 60,
+            voting_delay: (300, 300),
+            client_versions: None,
+            server_versions: None,
+            recommended_client_protocols: None,
+            recommended_relay_protocols: None,
+            required_client_protocols: None,
+            required_relay_protocols: None,
+            params: None,
+            shared_rand_previous_value: None,
+            shared_rand_current_value: None,
+        }
+    }
+}
+
+impl ConsensusHeader {
+    /// Extract the header items from a raw consensus document. Items that
+    /// are missing or malformed keep their default values.
+    pub fn from_raw(raw: &str) -> ConsensusHeader {
+        let mut header = ConsensusHeader::default();
+        let mut valid_after = None;
+        let mut fresh_until = None;
+        let mut valid_until = None;
+
+        let parse_time = |value: &str| {
+            DateTime::parse_from_str(&format!("{} +0000", value), "%Y-%m-%d %H:%M:%S %z")
+                .ok()
+                .map(|t| t.with_timezone(&Utc))
+        };
+
+        for line in raw.lines() {
+            let (keyword, value) = match line.split_once(' ') {
+                Some((keyword, value)) => (keyword, value.trim().to_string()),
+                None => (line, String::new()),
+            };
+            match keyword {
+                // the header ends with the authority section or the relays
+                "dir-source" | "r" | "directory-footer" => break,
+                "consensus-method" => {
+                    if let Ok(method) = value.parse() {
+                        header.consensus_method = method;
+                    }
+                }
+                "valid-after" => valid_after = parse_time(&value),
+                "fresh-until" => fresh_until = parse_time(&value),
+                "valid-until" => valid_until = parse_time(&value),
+                "voting-delay" => {
+                    let delays: Vec<u32> =
+                        value.split(' ').filter_map(|x| x.parse().ok()).collect();
+                    if let [vote, dist] = delays[..] {
+                        header.voting_delay = (vote, dist);
+                    }
+                }
+                "client-versions" => header.client_versions = Some(value),
+                "server-versions" => header.server_versions = Some(value),
+                "recommended-client-protocols" => header.recommended_client_protocols = Some(value),
+                "recommended-relay-protocols" => header.recommended_relay_protocols = Some(value),
+                "required-client-protocols" => header.required_client_protocols = Some(value),
+                "required-relay-protocols" => header.required_relay_protocols = Some(value),
+                "params" => header.params = Some(value),
+                "shared-rand-previous-value" => header.shared_rand_previous_value = Some(value),
+                "shared-rand-current-value" => header.shared_rand_current_value = Some(value),
+                _ => {}
+            }
+        }
+
+        if let Some(valid_after) = valid_after {
+            if let Some(fresh_until) = fresh_until {
+                header.fresh_interval = (fresh_until - valid_after).num_seconds();
+            }
+            if let Some(valid_until) = valid_until {
+                header.valid_interval = (valid_until - valid_after).num_seconds();
+            }
+        }
+
+        header
+    }
+
+    /// Write the header items from "consensus-method" up to (and including)
+    /// "shared-rand-current-value", with timestamps based on `valid_after`.
+    /// `known_flags` is inserted at its position in the header.
+    pub fn write_items(
+        &self,
+        w: &mut impl fmt::Write,
+        valid_after: DateTime<Utc>,
+        known_flags: &str,
+    ) -> fmt::Result {
+        let format_time = |t: DateTime<Utc>| t.format("%Y-%m-%d %H:%M:%S");
+
+        writeln!(w, "consensus-method {}", self.consensus_method)?;
+        writeln!(w, "valid-after {}", format_time(valid_after))?;
+        writeln!(
+            w,
+            "fresh-until {}",
+            format_time(valid_after + Duration::seconds(self.fresh_interval))
+        )?;
+        writeln!(
+            w,
+            "valid-until {}",
+            format_time(valid_after + Duration::seconds(self.valid_interval))
+        )?;
+        writeln!(
+            w,
+            "voting-delay {} {}",
+            self.voting_delay.0, self.voting_delay.1
+        )?;
+
+        let optional_items = [
+            ("client-versions", &self.client_versions),
+            ("server-versions", &self.server_versions),
+        ];
+        for (keyword, value) in optional_items {
+            if let Some(value) = value {
+                writeln!(w, "{} {}", keyword, value)?;
+            }
+        }
+
+        writeln!(w, "known-flags {}", known_flags)?;
+
+        let optional_items = [
+            (
+                "recommended-client-protocols",
+                &self.recommended_client_protocols,
+            ),
+            (
+                "recommended-relay-protocols",
+                &self.recommended_relay_protocols,
+            ),
+            ("required-client-protocols", &self.required_client_protocols),
+            ("required-relay-protocols", &self.required_relay_protocols),
+            ("params", &self.params),
+            (
+                "shared-rand-previous-value",
+                &self.shared_rand_previous_value,
+            ),
+            ("shared-rand-current-value", &self.shared_rand_current_value),
+        ];
+        for (keyword, value) in optional_items {
+            if let Some(value) = value {
+                writeln!(w, "{} {}", keyword, value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW_HEADER: &str = "network-status-version 3
+vote-status consensus
+consensus-method 32
+valid-after 2023-03-01 12:00:00
+fresh-until 2023-03-01 13:00:00
+valid-until 2023-03-01 15:00:00
+voting-delay 300 300
+client-versions 0.4.7.13,0.4.8.1-alpha
+known-flags Authority BadExit Exit Fast Guard HSDir Running Stable V2Dir Valid
+params CircuitPriorityHalflifeMsec=30000 DoSCircuitCreationEnabled=1
+shared-rand-current-value 9 dGVzdA==
+dir-source moria1 D586D18309DED4CD6D57C18FDB97EFA96D330566 128.31.0.34 128.31.0.34 9131 9101
+params ignored=1
+";
+
+    #[test]
+    fn header_round_trip() {
+        let header = ConsensusHeader::from_raw(RAW_HEADER);
+        assert_eq!(header.consensus_method, 32);
+        assert_eq!(header.fresh_interval, 3600);
+        assert_eq!(header.valid_interval, 3 * 3600);
+        assert_eq!(
+            header.params.as_deref(),
+            Some("CircuitPriorityHalflifeMsec=30000 DoSCircuitCreationEnabled=1")
+        );
+        assert_eq!(header.server_versions, None);
+
+        let valid_after = DateTime::parse_from_rfc3339("2023-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut written = String::from("network-status-version 3\nvote-status consensus\n");
+        header
+            .write_items(
+                &mut written,
+                valid_after,
+                "Authority BadExit Exit Fast Guard HSDir Running Stable V2Dir Valid",
+            )
+            .unwrap();
+        assert_eq!(ConsensusHeader::from_raw(&written), header);
+        assert!(written.contains("valid-until 2023-03-01 15:00:00\n"));
+    }
+}
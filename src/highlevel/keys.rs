@@ -0,0 +1,645 @@
+//! Generation of key material and signatures in the formats used by Tor.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use curve25519_dalek::{EdwardsPoint, Scalar};
+use ed25519_dalek::hazmat::{raw_sign, ExpandedSecretKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rsa::pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+use thiserror;
+
+use super::Consensus;
+use seeded_rand::{get_rng, RHashMap};
+use tordoc::Fingerprint;
+
+/// Size of relay RSA keys (identity and onion key) in bits
+const RELAY_RSA_KEY_BITS: usize = 1024;
+/// Lifetime of the generated ed25519 certificates
+const ED25519_CERT_LIFETIME_DAYS: i64 = 30;
+
+// Ed25519 certificate types and extensions (see cert-spec.txt)
+const CERT_TYPE_SIGNING_KEY: u8 = 0x04;
+const CERT_TYPE_NTOR_CROSSCERT: u8 = 0x0A;
+const CERT_EXT_SIGNED_WITH_KEY: u8 = 0x04;
+const CERT_KEY_TYPE_ED25519: u8 = 0x01;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("RSA error")]
+    RsaError(#[from] rsa::Error),
+    #[error("PKCS#1 encoding error")]
+    Pkcs1Error(#[from] rsa::pkcs1::Error),
+    #[error("General I/O error")]
+    IoError(#[from] io::Error),
+}
+
+/// Get a cryptographically secure RNG that is seeded from the global seeded
+/// RNG, so that generated keys are reproducible
+pub fn key_rng() -> ChaCha20Rng {
+    ChaCha20Rng::from_rng(get_rng()).expect("seeding the key RNG failed")
+}
+
+/// Generate an RSA key with public exponent 65537
+pub fn generate_rsa_key(rng: &mut ChaCha20Rng, bits: usize) -> Result<RsaPrivateKey, KeyError> {
+    Ok(RsaPrivateKey::new(rng, bits)?)
+}
+
+/// The DER encoding (PKCS#1) of an RSA public key
+pub fn rsa_public_der(key: &RsaPrivateKey) -> Result<Vec<u8>, KeyError> {
+    Ok(RsaPublicKey::from(key).to_pkcs1_der()?.as_bytes().to_vec())
+}
+
+/// The PEM encoding of an RSA public key, as used in Tor documents
+pub fn rsa_public_pem(key: &RsaPrivateKey) -> Result<String, KeyError> {
+    Ok(RsaPublicKey::from(key).to_pkcs1_pem(LineEnding::LF)?)
+}
+
+/// The fingerprint of an RSA key, i.e. the SHA-1 digest of its DER encoding
+pub fn rsa_fingerprint(key: &RsaPrivateKey) -> Result<Fingerprint, KeyError> {
+    let digest = Sha1::digest(rsa_public_der(key)?);
+    Ok(Fingerprint::from_u8(&digest))
+}
+
+/// Sign data with PKCS#1 v1.5 padding but without a DigestInfo prefix, as
+/// done by Tor
+pub fn rsa_sign(key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    Ok(key.sign(Pkcs1v15Sign::new_unprefixed(), data)?)
+}
+
+/// Encode binary data as a PEM-style object with the given label
+pub fn pem_object(label: &str, data: &[u8]) -> String {
+    let encoded = base64::encode(data);
+    let mut result = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        // base64 is ASCII, so this cannot fail
+        result.push_str(std::str::from_utf8(line).unwrap());
+        result.push('\n');
+    }
+    result.push_str(&format!("-----END {}-----\n", label));
+    result
+}
+
+/// Encode binary data as unpadded base64, as used for ed25519 items
+fn base64_nopad(data: &[u8]) -> String {
+    base64::encode_config(data, base64::STANDARD_NO_PAD)
+}
+
+/// Create an ed25519 certificate (see cert-spec.txt), signed by the given
+/// function. If `signed_with` is given, it is included as an extension.
+fn ed25519_cert<F: FnOnce(&[u8]) -> Signature>(
+    cert_type: u8,
+    certified_key: &[u8; 32],
+    expires: DateTime<Utc>,
+    signed_with: Option<&VerifyingKey>,
+    sign: F,
+) -> Vec<u8> {
+    let mut cert = vec![1u8, cert_type];
+    cert.extend(((expires.timestamp() / 3600) as u32).to_be_bytes());
+    cert.push(CERT_KEY_TYPE_ED25519);
+    cert.extend(certified_key);
+    match signed_with {
+        Some(key) => {
+            cert.push(1); // number of extensions
+            cert.extend(32u16.to_be_bytes()); // extension length
+            cert.push(CERT_EXT_SIGNED_WITH_KEY);
+            cert.push(0); // extension flags
+            cert.extend(key.as_bytes());
+        }
+        None => {
+            cert.push(0); // number of extensions
+        }
+    }
+    let signature = sign(&cert);
+    cert.extend(signature.to_bytes());
+    cert
+}
+
+/// Content of a key file as written by Tor, i.e. prefixed with a 32-byte
+/// header containing the type and tag
+fn tagged_file_contents(typestring: &str, tag: &str, data: &[u8]) -> Vec<u8> {
+    let mut contents = format!("== {}: {} ==", typestring, tag).into_bytes();
+    contents.resize(32, 0);
+    contents.extend(data);
+    contents
+}
+
+/// The expanded secret key of an ed25519 key as stored by Tor
+fn ed25519_expanded_secret(key: &SigningKey) -> Vec<u8> {
+    let mut expanded = Sha512::digest(key.to_bytes());
+    expanded[0] &= 248;
+    expanded[31] &= 63;
+    expanded[31] |= 64;
+    expanded.to_vec()
+}
+
+/// A curve25519 key pair, used as ntor onion key
+struct NtorKey {
+    /// clamped secret key
+    secret: [u8; 32],
+}
+
+impl NtorKey {
+    fn generate(rng: &mut ChaCha20Rng) -> NtorKey {
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        secret[0] &= 248;
+        secret[31] &= 127;
+        secret[31] |= 64;
+        NtorKey { secret }
+    }
+
+    /// The corresponding point on the ed25519 curve
+    fn edwards_point(&self) -> EdwardsPoint {
+        EdwardsPoint::mul_base(&Scalar::from_bytes_mod_order(self.secret))
+    }
+
+    fn public(&self) -> [u8; 32] {
+        self.edwards_point().to_montgomery().to_bytes()
+    }
+
+    /// Derive an ed25519 key from this key, the same way Tor does for the
+    /// ntor cross-certificate. Also return the sign bit of the public key.
+    fn to_ed25519(&self) -> (ExpandedSecretKey, VerifyingKey, u8) {
+        let mut hasher = Sha512::new();
+        hasher.update(self.secret);
+        hasher.update(b"Derive high part of ed25519 key from curve25519 key\0");
+        let mut hash_prefix = [0u8; 32];
+        hash_prefix.copy_from_slice(&hasher.finalize()[..32]);
+
+        let expanded = ExpandedSecretKey {
+            scalar: Scalar::from_bytes_mod_order(self.secret),
+            hash_prefix,
+        };
+        let public = VerifyingKey::from(&expanded);
+        let sign_bit = public.as_bytes()[31] >> 7;
+        (expanded, public, sign_bit)
+    }
+}
+
+/// The key material of a relay
+pub struct RelayKeys {
+    identity_key: RsaPrivateKey,
+    onion_key: RsaPrivateKey,
+    ntor_key: NtorKey,
+    ed_identity: SigningKey,
+    ed_signing: SigningKey,
+    /// Certificate of the signing key, signed by the identity key
+    signing_cert: Vec<u8>,
+    /// Expiration of the generated certificates
+    expires: DateTime<Utc>,
+}
+
+impl RelayKeys {
+    /// Generate new keys. The certificates are valid for some time after
+    /// `published`.
+    pub fn generate(
+        rng: &mut ChaCha20Rng,
+        published: DateTime<Utc>,
+    ) -> Result<RelayKeys, KeyError> {
+        let identity_key = generate_rsa_key(rng, RELAY_RSA_KEY_BITS)?;
+        let onion_key = generate_rsa_key(rng, RELAY_RSA_KEY_BITS)?;
+        let ntor_key = NtorKey::generate(rng);
+        let ed_identity = SigningKey::generate(rng);
+        let ed_signing = SigningKey::generate(rng);
+
+        let expires = published + Duration::days(ED25519_CERT_LIFETIME_DAYS);
+        let signing_cert = ed25519_cert(
+            CERT_TYPE_SIGNING_KEY,
+            ed_signing.verifying_key().as_bytes(),
+            expires,
+            Some(&ed_identity.verifying_key()),
+            |data| ed_identity.sign(data),
+        );
+
+        Ok(RelayKeys {
+            identity_key,
+            onion_key,
+            ntor_key,
+            ed_identity,
+            ed_signing,
+            signing_cert,
+            expires,
+        })
+    }
+
+    /// The relay fingerprint, derived from the RSA identity key
+    pub fn fingerprint(&self) -> Result<Fingerprint, KeyError> {
+        rsa_fingerprint(&self.identity_key)
+    }
+
+    /// Descriptor items that have to directly follow the "router" line
+    pub fn descriptor_identity_items(&self) -> String {
+        format!(
+            "identity-ed25519\n{}master-key-ed25519 {}\n",
+            pem_object("ED25519 CERT", &self.signing_cert),
+            base64_nopad(self.ed_identity.verifying_key().as_bytes())
+        )
+    }
+
+    /// Descriptor items containing the onion keys, signing key and the
+    /// respective cross-certificates
+    pub fn descriptor_key_items(&self) -> Result<String, KeyError> {
+        // onion key cross-certificate: RSA identity digest and ed25519
+        // identity, signed (without hashing) by the onion key
+        let mut crosscert_data = Sha1::digest(rsa_public_der(&self.identity_key)?).to_vec();
+        crosscert_data.extend(self.ed_identity.verifying_key().as_bytes());
+        let onion_crosscert = rsa_sign(&self.onion_key, &crosscert_data)?;
+
+        // ntor cross-certificate: the ed25519 identity, certified by the
+        // ed25519 key derived from the ntor key
+        let (ntor_ed_secret, ntor_ed_public, sign_bit) = self.ntor_key.to_ed25519();
+        let ntor_crosscert = ed25519_cert(
+            CERT_TYPE_NTOR_CROSSCERT,
+            self.ed_identity.verifying_key().as_bytes(),
+            self.expires,
+            None,
+            |data| raw_sign::<Sha512>(&ntor_ed_secret, data, &ntor_ed_public),
+        );
+
+        Ok(format!(
+            "onion-key\n{}signing-key\n{}onion-key-crosscert\n{}\
+             ntor-onion-key {}\nntor-onion-key-crosscert {}\n{}",
+            rsa_public_pem(&self.onion_key)?,
+            rsa_public_pem(&self.identity_key)?,
+            pem_object("CROSSCERT", &onion_crosscert),
+            base64::encode(self.ntor_key.public()),
+            sign_bit,
+            pem_object("ED25519 CERT", &ntor_crosscert),
+        ))
+    }
+
+    /// Microdescriptor items containing the onion keys
+    pub fn microdescriptor_key_items(&self) -> Result<String, KeyError> {
+        Ok(format!(
+            "onion-key\n{}ntor-onion-key {}\n",
+            rsa_public_pem(&self.onion_key)?,
+            base64::encode(self.ntor_key.public()),
+        ))
+    }
+
+    /// Microdescriptor item containing the ed25519 identity
+    pub fn microdescriptor_id_item(&self) -> String {
+        format!(
+            "id ed25519 {}\n",
+            base64_nopad(self.ed_identity.verifying_key().as_bytes())
+        )
+    }
+
+    /// Sign a descriptor. `descriptor` has to contain the whole descriptor up
+    /// to (and excluding) the "router-sig-ed25519" item. The signature items
+    /// are appended.
+    pub fn sign_descriptor(&self, descriptor: &mut String) -> Result<(), KeyError> {
+        let start = descriptor.find("router ").unwrap_or(0);
+
+        // ed25519 signature, covering everything up to "router-sig-ed25519 "
+        descriptor.push_str("router-sig-ed25519 ");
+        let mut hasher = Sha256::new();
+        hasher.update(b"Tor router descriptor signature v1");
+        hasher.update(&descriptor.as_bytes()[start..]);
+        let ed_signature = self.ed_signing.sign(&hasher.finalize());
+        descriptor.push_str(&base64_nopad(&ed_signature.to_bytes()));
+        descriptor.push('\n');
+
+        // RSA signature, covering everything up to "router-signature\n"
+        descriptor.push_str("router-signature\n");
+        let digest = Sha1::digest(&descriptor.as_bytes()[start..]);
+        descriptor.push_str(&pem_object(
+            "SIGNATURE",
+            &rsa_sign(&self.identity_key, &digest)?,
+        ));
+        Ok(())
+    }
+
+    /// Save the keys in the format of a Tor data directory
+    pub fn save_to_tor_data_dir(
+        &self,
+        dir: impl AsRef<Path>,
+        nickname: &str,
+    ) -> Result<(), KeyError> {
+        let dir = dir.as_ref();
+        let keys_dir = dir.join("keys");
+        fs::create_dir_all(&keys_dir)?;
+
+        fs::write(
+            keys_dir.join("secret_id_key"),
+            self.identity_key.to_pkcs1_pem(LineEnding::LF)?.as_bytes(),
+        )?;
+        fs::write(
+            keys_dir.join("secret_onion_key"),
+            self.onion_key.to_pkcs1_pem(LineEnding::LF)?.as_bytes(),
+        )?;
+        let mut ntor_keypair = self.ntor_key.secret.to_vec();
+        ntor_keypair.extend(self.ntor_key.public());
+        fs::write(
+            keys_dir.join("secret_onion_key_ntor"),
+            tagged_file_contents("c25519v1", "onion", &ntor_keypair),
+        )?;
+        fs::write(
+            keys_dir.join("ed25519_master_id_secret_key"),
+            tagged_file_contents(
+                "ed25519v1-secret",
+                "type0",
+                &ed25519_expanded_secret(&self.ed_identity),
+            ),
+        )?;
+        fs::write(
+            keys_dir.join("ed25519_master_id_public_key"),
+            tagged_file_contents(
+                "ed25519v1-public",
+                "type0",
+                self.ed_identity.verifying_key().as_bytes(),
+            ),
+        )?;
+        fs::write(
+            keys_dir.join("ed25519_signing_secret_key"),
+            tagged_file_contents(
+                "ed25519v1-secret",
+                "type0",
+                &ed25519_expanded_secret(&self.ed_signing),
+            ),
+        )?;
+        fs::write(
+            keys_dir.join("ed25519_signing_cert"),
+            tagged_file_contents("ed25519v1-cert", "type4", &self.signing_cert),
+        )?;
+
+        fs::write(
+            dir.join("fingerprint"),
+            format!(
+                "{} {}\n",
+                nickname,
+                self.fingerprint()?.to_string_hex_blocks()
+            ),
+        )?;
+        fs::write(
+            dir.join("fingerprint-ed25519"),
+            format!(
+                "{} {}\n",
+                nickname,
+                base64_nopad(self.ed_identity.verifying_key().as_bytes())
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// Key material for all relays of a consensus, indexed by their fingerprint
+pub struct RelayKeyStore {
+    keys: RHashMap<Fingerprint, RelayKeys>,
+}
+
+impl RelayKeyStore {
+    /// Generate keys for all relays in the consensus. As the relay
+    /// fingerprints are derived from the identity keys, all relays are
+    /// assigned new fingerprints.
+    pub fn generate_for(consensus: &mut Consensus) -> Result<RelayKeyStore, KeyError> {
+        let mut rng = key_rng();
+
+        // generate in a deterministic order
+        let mut fingerprints: Vec<Fingerprint> = consensus.relays.keys().cloned().collect();
+        fingerprints.sort_by_cached_key(|fp| fp.to_string_hex());
+
+        let mut keys = RHashMap::default();
+        let mut new_fingerprints = RHashMap::default();
+        for old_fingerprint in fingerprints {
+            let relay_keys = RelayKeys::generate(&mut rng, consensus.valid_after)?;
+            let new_fingerprint = relay_keys.fingerprint()?;
+            keys.insert(new_fingerprint.clone(), relay_keys);
+            new_fingerprints.insert(old_fingerprint, new_fingerprint);
+        }
+
+        consensus.change_fingerprints(&new_fingerprints);
+
+        Ok(RelayKeyStore { keys })
+    }
+
+    pub fn get(&self, fingerprint: &Fingerprint) -> Option<&RelayKeys> {
+        self.keys.get(fingerprint)
+    }
+
+    /// Save the keys of all relays as Tor data directories, one
+    /// subdirectory per relay (named by its fingerprint)
+    pub fn save_to_tor_data_dirs(
+        &self,
+        consensus: &Consensus,
+        dir: impl AsRef<Path>,
+    ) -> Result<(), KeyError> {
+        let dir = dir.as_ref();
+        for (fingerprint, relay_keys) in self.keys.iter() {
+            let nickname = consensus
+                .relays
+                .get(fingerprint)
+                .map(|r| r.nickname.as_str())
+                .unwrap_or("Unnamed");
+            relay_keys.save_to_tor_data_dir(dir.join(fingerprint.to_string_hex()), nickname)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use curve25519_dalek::montgomery::MontgomeryPoint;
+    use ed25519_dalek::Verifier;
+    use rsa::pkcs1::DecodeRsaPublicKey;
+
+    use crate::highlevel::{FamilyDecision, Lineage, Relay};
+
+    fn keys(seed: u64) -> RelayKeys {
+        let published = DateTime::parse_from_rfc3339("2023-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        RelayKeys::generate(&mut ChaCha20Rng::seed_from_u64(seed), published).unwrap()
+    }
+
+    /// A signed descriptor with the items generated from the keys
+    fn descriptor(keys: &RelayKeys) -> String {
+        let mut desc = format!(
+            "router test 10.0.0.1 9001 0 0\n{}published 2023-03-01 11:00:00\n\
+             fingerprint {}\nbandwidth 1000 2000 1000\n{}reject *:*\n",
+            keys.descriptor_identity_items(),
+            keys.fingerprint().unwrap().to_string_hex_blocks(),
+            keys.descriptor_key_items().unwrap()
+        );
+        keys.sign_descriptor(&mut desc).unwrap();
+        desc
+    }
+
+    /// The arguments of the item with the given keyword
+    fn item<'a>(doc: &'a str, keyword: &str) -> &'a str {
+        doc.lines()
+            .find_map(|line| line.strip_prefix(&format!("{} ", keyword)))
+            .unwrap()
+    }
+
+    /// The decoded object following the item with the given keyword
+    fn object(doc: &str, keyword: &str) -> Vec<u8> {
+        let encoded: String = doc
+            .lines()
+            .skip_while(|line| line.split(' ').next() != Some(keyword))
+            .skip(2)
+            .take_while(|line| !line.starts_with("-----END"))
+            .collect();
+        base64::decode(encoded).unwrap()
+    }
+
+    fn pem(doc: &str, keyword: &str) -> String {
+        doc.lines()
+            .skip_while(|line| *line != keyword)
+            .skip(1)
+            .take_while(|line| !line.starts_with("-----END"))
+            .chain(["-----END RSA PUBLIC KEY-----"])
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    fn ed25519_key(encoded: &str) -> VerifyingKey {
+        let bytes = base64::decode_config(encoded, base64::STANDARD_NO_PAD).unwrap();
+        VerifyingKey::from_bytes(&bytes.try_into().unwrap()).unwrap()
+    }
+
+    /// Check the signature of an ed25519 certificate and return the
+    /// certified key
+    fn check_cert(cert: &[u8], cert_type: u8, signing_key: &VerifyingKey) -> [u8; 32] {
+        assert_eq!(cert[1], cert_type);
+        let (body, signature) = cert.split_at(cert.len() - 64);
+        signing_key
+            .verify(body, &Signature::from_slice(signature).unwrap())
+            .unwrap();
+        cert[7..39].try_into().unwrap()
+    }
+
+    #[test]
+    fn descriptor_signatures() {
+        let keys = keys(1);
+        let desc = descriptor(&keys);
+
+        // the signing key is certified by the master key
+        let master_key = ed25519_key(item(&desc, "master-key-ed25519"));
+        let signing_key = check_cert(
+            &object(&desc, "identity-ed25519"),
+            CERT_TYPE_SIGNING_KEY,
+            &master_key,
+        );
+        let signing_key = VerifyingKey::from_bytes(&signing_key).unwrap();
+
+        let end = desc.find("router-sig-ed25519 ").unwrap() + "router-sig-ed25519 ".len();
+        let mut hasher = Sha256::new();
+        hasher.update(b"Tor router descriptor signature v1");
+        hasher.update(&desc.as_bytes()[..end]);
+        let signature =
+            base64::decode_config(item(&desc, "router-sig-ed25519"), base64::STANDARD_NO_PAD)
+                .unwrap();
+        signing_key
+            .verify(
+                &hasher.finalize(),
+                &Signature::from_slice(&signature).unwrap(),
+            )
+            .unwrap();
+
+        // RSA signature with the identity key
+        let identity_key = RsaPublicKey::from_pkcs1_pem(&pem(&desc, "signing-key")).unwrap();
+        let end = desc.find("router-signature\n").unwrap() + "router-signature\n".len();
+        identity_key
+            .verify(
+                Pkcs1v15Sign::new_unprefixed(),
+                &Sha1::digest(&desc.as_bytes()[..end]),
+                &object(&desc, "router-signature"),
+            )
+            .unwrap();
+        let der = identity_key.to_pkcs1_der().unwrap();
+        assert_eq!(
+            Fingerprint::from_u8(&Sha1::digest(der.as_bytes())),
+            keys.fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn crosscerts() {
+        let keys = keys(2);
+        let desc = descriptor(&keys);
+        let master_key = item(&desc, "master-key-ed25519");
+
+        // the onion key signs the identity digest and the master key
+        let onion_key = RsaPublicKey::from_pkcs1_pem(&pem(&desc, "onion-key")).unwrap();
+        let identity_key = RsaPublicKey::from_pkcs1_pem(&pem(&desc, "signing-key")).unwrap();
+        let mut signed = Sha1::digest(identity_key.to_pkcs1_der().unwrap().as_bytes()).to_vec();
+        signed.extend(ed25519_key(master_key).as_bytes());
+        onion_key
+            .verify(
+                Pkcs1v15Sign::new_unprefixed(),
+                &signed,
+                &object(&desc, "onion-key-crosscert"),
+            )
+            .unwrap();
+
+        // the ed25519 key derived from the ntor key certifies the master key
+        let ntor_key: [u8; 32] = base64::decode(item(&desc, "ntor-onion-key"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let sign_bit: u8 = item(&desc, "ntor-onion-key-crosscert").parse().unwrap();
+        let ntor_ed_key = MontgomeryPoint(ntor_key).to_edwards(sign_bit).unwrap();
+        let ntor_ed_key = VerifyingKey::from_bytes(&ntor_ed_key.compress().to_bytes()).unwrap();
+        let certified = check_cert(
+            &object(&desc, "ntor-onion-key-crosscert"),
+            CERT_TYPE_NTOR_CROSSCERT,
+            &ntor_ed_key,
+        );
+        assert_eq!(&certified, ed25519_key(master_key).as_bytes());
+    }
+
+    #[test]
+    fn reproducible() {
+        let (a, b) = (keys(3), keys(3));
+        assert_eq!(a.fingerprint().unwrap(), b.fingerprint().unwrap());
+        assert_eq!(
+            a.descriptor_key_items().unwrap(),
+            b.descriptor_key_items().unwrap()
+        );
+        assert_eq!(a.descriptor_identity_items(), b.descriptor_identity_items());
+        assert_ne!(a.fingerprint().unwrap(), keys(4).fingerprint().unwrap());
+    }
+
+    #[test]
+    fn fingerprint_changes_keep_lineage() {
+        seeded_rand::set_seed(1);
+        let original = Relay::for_test(1, "10.0.0.1", "Fast Running Valid", 100, "reject 1-65535");
+        let mut synthetic =
+            Relay::for_test(2, "10.1.0.1", "Fast Running Valid", 100, "reject 1-65535");
+        synthetic.lineage = Some(Lineage {
+            base_fingerprint: original.fingerprint.clone(),
+            family: FamilyDecision::NoFamily,
+            same_as_family: None,
+        });
+        let original_fingerprint = original.fingerprint.clone();
+        let mut consensus = Consensus::for_test(vec![original, synthetic], &[], None);
+
+        let store = RelayKeyStore::generate_for(&mut consensus).unwrap();
+        assert_eq!(consensus.relays.len(), 2);
+        for (fingerprint, relay) in consensus.relays.iter() {
+            assert!(store.get(fingerprint).is_some());
+            assert!(relay.original_fingerprint.is_some());
+            assert_ne!(relay.original_fingerprint.as_ref(), Some(fingerprint));
+        }
+        let synthetic = consensus
+            .relays
+            .values()
+            .find(|r| r.is_synthetic())
+            .unwrap();
+        assert_eq!(
+            synthetic.lineage.as_ref().unwrap().base_fingerprint,
+            original_fingerprint
+        );
+    }
+}
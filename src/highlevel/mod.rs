@@ -1,16 +1,45 @@
 mod bwweights;
+pub use bwweights::{
+    BandwidthTotals, BwwError, WeightCase, WeightComputation, WeightDifference, WeightVerification,
+};
 mod containers;
 
-pub use containers::{lookup_descriptors, Consensus, Relay, UnpackedConsensus};
+pub use containers::{
+    descriptor_paths, lookup_descriptors, lookup_descriptors_lenient, Consensus, Relay,
+    UnpackedConsensus,
+};
+
+mod diversity;
+pub use diversity::{DiversityMetrics, PositionDiversity};
 
 mod families;
+mod header;
+pub use header::{ConsensusHeader, DEFAULT_CONSENSUS_METHOD};
+
+mod series;
+pub use series::{dynamic_flags, ChurnModel, ChurnModelError, SeriesGenerator};
+
+mod selection;
+pub use selection::{PositionClass, PositionProbabilities, PositionWeights, WEIGHT_SCALE};
 
 mod scale;
 pub use scale::{
-    cutoff_lower_and_redistribute, scale_flag_groups_vertically, scale_horizontally,
-    scale_vertically_by_bandwidth_rank,
+    cutoff_lower_and_redistribute, downsample, scale_capacity, scale_flag_groups_vertically,
+    scale_horizontally, scale_vertically_by_bandwidth_rank, DownsampleOptions, FamilyDecision,
+    Lineage, ScaleError,
 };
 
+pub mod adversary;
 pub mod asn;
 
+pub mod authority;
+pub mod collector;
+pub mod compare;
+pub mod estimate;
+pub mod geoip;
+pub mod keys;
+
 pub mod output;
+pub mod paths;
+pub mod roundtrip;
+pub mod stats;
@@ -1,11 +1,12 @@
 //! Dump a highlevel consensus to Tor descriptor files
 
-use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
 
 use chrono::Duration;
@@ -13,9 +14,13 @@ use serde::Serialize;
 use serde_json;
 use thiserror;
 
-use super::Consensus;
+use super::authority::{AuthorityError, AuthoritySet, DigestAlgorithm};
+use super::geoip::GeoIpDb;
+use super::keys::{KeyError, RelayKeyStore, RelayKeys};
+use super::{Consensus, DiversityMetrics, FamilyDecision, PositionClass, Relay};
 
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use tordoc::{consensus::Flag, Fingerprint};
 
 #[derive(thiserror::Error, Debug)]
@@ -32,11 +37,31 @@ pub enum OutputError {
     FmtError(#[from] fmt::Error),
     #[error("JSON serialization error")]
     JsonError(#[from] serde_json::Error),
+    #[error("CSV serialization error")]
+    CsvError(#[from] csv::Error),
+    #[error("Directory authority error")]
+    AuthorityError(#[from] AuthorityError),
+    #[error("Relay key error")]
+    KeyError(#[from] KeyError),
+    #[error("Keys are required for all relays")]
+    MissingRelayKeys,
+}
+
+/// Options for generating the consensus and descriptor documents
+#[derive(Default)]
+pub struct OutputOptions {
+    /// Directory authorities to sign the consensus with. If not given, the
+    /// consensus is not signed.
+    pub authorities: Option<AuthoritySet>,
+    /// Keys of the relays to sign their descriptors with. If not given, the
+    /// descriptors contain no keys and a dummy signature.
+    pub relay_keys: Option<RelayKeyStore>,
 }
 
 #[derive(Serialize)]
 struct JsonConsensus {
     relays: Vec<JsonRelay>,
+    diversity: DiversityMetrics,
 }
 
 #[derive(Serialize)]
@@ -47,9 +72,14 @@ struct JsonRelay {
     is_guard: bool,
     is_exit: bool,
     asn: u32,
+    prob_guard: f64,
+    prob_middle: f64,
+    prob_exit: f64,
+    is_synthetic: bool,
 }
 
 fn save_consensus_json<P: AsRef<Path>>(consensus: &Consensus, fpath: P) -> Result<(), OutputError> {
+    let probabilities = consensus.selection_probabilities();
     let relays: Vec<JsonRelay> = consensus
         .relays
         .iter()
@@ -60,9 +90,16 @@ fn save_consensus_json<P: AsRef<Path>>(consensus: &Consensus, fpath: P) -> Resul
             is_guard: r.has_flag(Flag::Guard),
             is_exit: r.has_flag(Flag::Exit),
             asn: r.asn.as_ref().map(|x| x.number).unwrap_or(0),
+            prob_guard: probabilities[fp].guard,
+            prob_middle: probabilities[fp].middle,
+            prob_exit: probabilities[fp].exit,
+            is_synthetic: r.is_synthetic(),
         })
         .collect();
-    let result = JsonConsensus { relays };
+    let result = JsonConsensus {
+        relays,
+        diversity: consensus.diversity_metrics(),
+    };
 
     let mut f = File::create(fpath.as_ref())?;
     write!(&mut f, "{}", serde_json::to_string_pretty(&result)?)?;
@@ -70,10 +107,68 @@ fn save_consensus_json<P: AsRef<Path>>(consensus: &Consensus, fpath: P) -> Resul
     Ok(())
 }
 
-pub fn save_to_dir(consensus: &Consensus, dir: impl AsRef<Path>) -> Result<(), OutputError> {
-    let dir: &Path = dir.as_ref();
+#[derive(Serialize)]
+struct LineageRecord {
+    fingerprint: String,
+    nickname: String,
+    /// Fingerprint of the relay before generating keys for it
+    original_fingerprint: Option<String>,
+    base_fingerprint: Option<String>,
+    family_decision: Option<FamilyDecision>,
+    same_as_family: Option<bool>,
+    asn: Option<u32>,
+}
+
+/// Save the lineage of all synthetic relays to `<name>.csv` and
+/// `<name>.json` in the given directory. Relays of the original consensus
+/// are included if their fingerprint was changed, to map them to their
+/// original fingerprint.
+pub fn save_lineage(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    name: &str,
+) -> Result<(), OutputError> {
+    let dir = dir.as_ref();
+    let mut records: Vec<LineageRecord> = consensus
+        .relays
+        .values()
+        .filter(|r| r.lineage.is_some() || r.original_fingerprint.is_some())
+        .map(|r| LineageRecord {
+            fingerprint: r.fingerprint.to_string_hex(),
+            nickname: r.nickname.clone(),
+            original_fingerprint: r.original_fingerprint.as_ref().map(|x| x.to_string_hex()),
+            base_fingerprint: r
+                .lineage
+                .as_ref()
+                .map(|x| x.base_fingerprint.to_string_hex()),
+            family_decision: r.lineage.as_ref().map(|x| x.family),
+            same_as_family: r.lineage.as_ref().and_then(|x| x.same_as_family),
+            asn: r.asn.as_ref().map(|x| x.number),
+        })
+        .collect();
+    records.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+
+    let mut writer = csv::Writer::from_path(dir.join(format!("{}.csv", name)))?;
+    for record in records.iter() {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
 
-    // check output dir
+    let mut f = File::create(dir.join(format!("{}.json", name)))?;
+    write!(&mut f, "{}", serde_json::to_string_pretty(&records)?)?;
+
+    Ok(())
+}
+
+/// Paths of the documents written by [save_to_dir] and [save_to_tordata_dir]
+#[derive(Debug, Clone)]
+pub struct WrittenDocuments {
+    pub consensus_path: PathBuf,
+    pub descriptor_paths: Vec<PathBuf>,
+}
+
+/// Make sure the output directory exists and is empty
+fn check_output_dir(dir: &Path) -> Result<(), OutputError> {
     if !fs::metadata(dir)
         .map_err(|_| OutputError::DirAccess)?
         .is_dir()
@@ -86,6 +181,17 @@ pub fn save_to_dir(consensus: &Consensus, dir: impl AsRef<Path>) -> Result<(), O
     {
         return Err(OutputError::DirNotEmpty);
     }
+    Ok(())
+}
+
+pub fn save_to_dir(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    options: &OutputOptions,
+) -> Result<WrittenDocuments, OutputError> {
+    let dir: &Path = dir.as_ref();
+
+    check_output_dir(dir)?;
 
     // create output dir tree
     let consensus_dir = dir.join("consensus");
@@ -99,44 +205,49 @@ pub fn save_to_dir(consensus: &Consensus, dir: impl AsRef<Path>) -> Result<(), O
         Ok(descriptor_dir.join(fp.to_string_hex()))
     };
 
-    save_to(
+    let written = save_to(
         consensus,
         consensus_path,
         Some(consensus_json_path),
         descriptor_path,
-    )
+        options,
+    )?;
+    save_lineage(consensus, &consensus_dir, "lineage")?;
+    save_authority_files(&consensus_dir, options)?;
+    Ok(written)
 }
 
 pub fn save_to_tordata_dir(
     consensus: &Consensus,
     dir: impl AsRef<Path>,
-) -> Result<(), OutputError> {
+    options: &OutputOptions,
+) -> Result<WrittenDocuments, OutputError> {
     let dir: &Path = dir.as_ref();
 
-    // check output dir
-    if !fs::metadata(dir)
-        .map_err(|_| OutputError::DirAccess)?
-        .is_dir()
-    {
-        return Err(OutputError::NotADir);
-    }
-    if let Some(_) = fs::read_dir(dir)
-        .map_err(|_| OutputError::DirAccess)?
-        .next()
-    {
-        return Err(OutputError::DirNotEmpty);
-    }
+    check_output_dir(dir)?;
+
+    save_to_collector_tree(consensus, dir, options)
+}
+
+/// Add the consensus and descriptors to a (possibly non-empty) folder
+/// hierarchy in the format from CollecTor
+pub fn save_to_collector_tree(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    options: &OutputOptions,
+) -> Result<WrittenDocuments, OutputError> {
+    let dir: &Path = dir.as_ref();
 
     // consensus file
-    let consensus_dir = dir.join(
-        consensus
-            .valid_after
-            .format("consensuses-%Y-%m")
-            .to_string(),
-    );
-    fs::create_dir(&consensus_dir)?;
-    let consensus_dir = consensus_dir.join(consensus.valid_after.format("%m").to_string());
-    fs::create_dir(&consensus_dir)?;
+    let consensus_dir = dir
+        .join(
+            consensus
+                .valid_after
+                .format("consensuses-%Y-%m")
+                .to_string(),
+        )
+        .join(consensus.valid_after.format("%d").to_string());
+    fs::create_dir_all(&consensus_dir)?;
 
     let consensus_path = consensus_dir.join(
         consensus
@@ -152,7 +263,7 @@ pub fn save_to_tordata_dir(
             .format("server-descriptors-%Y-%m")
             .to_string(),
     );
-    fs::create_dir(&descriptor_dir)?;
+    fs::create_dir_all(&descriptor_dir)?;
 
     let descriptor_path = |fp: &Fingerprint| -> Result<PathBuf, OutputError> {
         let digest = fp.to_string_hex();
@@ -165,130 +276,240 @@ pub fn save_to_tordata_dir(
         Ok(desc_subdir.join(fp.to_string_hex()))
     };
 
-    save_to(
+    let written = save_to(
         consensus,
         consensus_path,
         None as Option<PathBuf>,
         descriptor_path,
-    )
+        options,
+    )?;
+    // several consensuses may share the directory, so name the lineage
+    // files like the consensus
+    save_lineage(
+        consensus,
+        &consensus_dir,
+        &consensus
+            .valid_after
+            .format("%Y-%m-%d-%H-%M-%S-lineage")
+            .to_string(),
+    )?;
+    save_authority_files(dir, options)?;
+    Ok(written)
 }
 
-fn save_to(
-    consensus: &Consensus,
-    consensus_path: impl AsRef<Path>,
-    consensus_json_path: Option<impl AsRef<Path>>,
-    descriptor_path: impl FnMut(&Fingerprint) -> Result<PathBuf, OutputError>,
-) -> Result<(), OutputError> {
-    let consensus_path = consensus_path.as_ref();
-    let mut descriptor_path = descriptor_path;
+/// Flavors of the consensus document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConsensusFlavor {
+    /// The full consensus, referencing server descriptors
+    Ns,
+    /// The microdescriptor consensus
+    Microdesc,
+}
 
-    // output meta info
-    let mut f_consensus = File::create(consensus_path)?;
+/// A relay together with the digest of its descriptor or microdescriptor,
+/// as referenced from the consensus
+struct ConsensusEntry<'a> {
+    relay: &'a Relay,
+    digest: String,
+}
+
+/// The relays of the consensus, in the order required for consensus documents
+fn sorted_relays(consensus: &Consensus) -> Vec<&Relay> {
+    let mut relays: Vec<&Relay> = consensus.relays.values().collect();
+    relays.sort_by_cached_key(|r| r.fingerprint.to_string_hex());
+    relays
+}
 
-    writeln!(&mut f_consensus, "@type network-status-consensus-3 1.0")?;
-    writeln!(&mut f_consensus, "network-status-version 3")?;
-    writeln!(&mut f_consensus, "vote-status consensus")?;
-    writeln!(&mut f_consensus, "consensus-method 31")?;
+/// Generate the server descriptor of a relay (without annotations). Returns
+/// the descriptor and its digest.
+fn render_descriptor(
+    relay: &Relay,
+    options: &OutputOptions,
+) -> Result<(String, Fingerprint), OutputError> {
+    use std::fmt::Write;
+
+    let mut desc = String::new();
     writeln!(
-        &mut f_consensus,
-        "valid-after {}",
-        (consensus.valid_after.date().and_hms(0, 0, 0) - Duration::hours(1))
-            .format("%Y-%m-%d %H:%M:%S")
+        &mut desc,
+        "router {} {} {} {} {}",
+        relay.nickname,
+        relay.address,
+        relay.or_port,
+        0,
+        relay.dir_port.unwrap_or(0)
     )?;
+    let relay_keys = options
+        .relay_keys
+        .as_ref()
+        .and_then(|keys| keys.get(&relay.fingerprint));
+    if let Some(relay_keys) = relay_keys {
+        write!(&mut desc, "{}", relay_keys.descriptor_identity_items())?;
+    }
+    if let Some(ref version_line) = relay.version_line {
+        writeln!(&mut desc, "platform {}", version_line)?;
+    }
+    if let Some(protocols) = protocols_string(relay) {
+        writeln!(&mut desc, "proto {}", protocols)?;
+    }
     writeln!(
-        &mut f_consensus,
-        "known-flags {}",
-        Flag::known_flags_string()
+        &mut desc,
+        "published {}",
+        relay.published.format("%Y-%m-%d %H:%M:%S")
     )?;
+    writeln!(
+        &mut desc,
+        "fingerprint {}",
+        relay.fingerprint.to_string_hex_blocks(),
+    )?;
+    writeln!(
+        &mut desc,
+        "bandwidth {} {} {}",
+        relay.bandwidth_avg(),
+        relay.bandwidth_burst(),
+        relay.bandwidth_observed(),
+    )?;
+    if let Some(relay_keys) = relay_keys {
+        write!(&mut desc, "{}", relay_keys.descriptor_key_items()?)?;
+    }
+    if let Some(family_line) = family_line(relay) {
+        writeln!(&mut desc, "{}", family_line)?;
+    }
 
-    // output relays
-    for relay in consensus.relays.values() {
-        // First, generate the server descriptor
-        let mut desc = String::new();
-        {
-            use std::fmt::Write;
-            writeln!(&mut desc, "@type server-descriptor 1.0")?;
-            writeln!(
-                &mut desc,
-                "router {} {} {} {} {}",
-                relay.nickname,
-                relay.address,
-                9001, // TODO or_port
-                0,
-                0 // TODO dir_port
-            )?;
-            writeln!(
-                &mut desc,
-                "published {}",
-                (consensus.valid_after.date().and_hms(0, 0, 0) - Duration::hours(1))
-                    .format("%Y-%m-%d %H:%M:%S")
-            )?;
-            writeln!(
-                &mut desc,
-                "fingerprint {}",
-                relay.fingerprint.to_string_hex_blocks(),
-            )?;
-            writeln!(
-                &mut desc,
-                "bandwidth {} {} {}",
-                min(
-                    (relay.bandwidth_weight as f32 * relay.bw_ratio_avg as f32) as u64,
-                    2147483500
-                ),
-                min(
-                    (relay.bandwidth_weight as f32 * relay.bw_ratio_burst as f32) as u64,
-                    2147483500
-                ),
-                min(
-                    (relay.bandwidth_weight as f32 * relay.bw_ratio_observed as f32) as u64,
-                    2147483500
-                ),
-            )?;
-            if let Some(ref fam) = relay.family {
-                writeln!(
-                    &mut desc,
-                    "family {}",
-                    fam.members
-                        .iter()
-                        .map(|fp| format!("${}", fp.to_string_hex()))
-                        .collect::<Vec<_>>()
-                        .join(" "),
-                )?;
-            }
-
-            for line in relay.exit_policy.to_descriptor_lines() {
-                writeln!(&mut desc, "{}", line)?;
-            }
+    for line in relay.exit_policy.to_descriptor_lines() {
+        writeln!(&mut desc, "{}", line)?;
+    }
 
+    match relay_keys {
+        Some(relay_keys) => relay_keys.sign_descriptor(&mut desc)?,
+        None => {
             writeln!(&mut desc, "router-signature")?;
             writeln!(&mut desc, "-----BEGIN SIGNATURE-----")?;
             writeln!(&mut desc, "AAAA")?;
             writeln!(&mut desc, "-----END SIGNATURE-----")?;
         }
-        let desc_digest = {
-            let from = "router";
-            let to = "\nrouter-signature\n";
-            let from_idx = desc.find(from).unwrap();
-            let to_idx = desc.find(to).unwrap() + to.len();
-            digest_from_raw(&desc[from_idx..to_idx])
-        };
-        fs::write(descriptor_path(&desc_digest)?, &desc)?;
+    }
 
-        // Now print the consensus entry
-        writeln!(
-            &mut f_consensus,
-            "r {} {} {} {} {} {} {}",
-            relay.nickname,
-            relay.fingerprint.to_string_b64(),
-            desc_digest.to_string_b64(),
-            (consensus.valid_after.date().and_hms(0, 0, 0) - Duration::hours(1))
-                .format("%Y-%m-%d %H:%M:%S"),
-            relay.address,
-            9001, // TODO or_port
-            0,    // TODO dir_port
+    let desc_digest = {
+        let from = "router";
+        let to = "\nrouter-signature\n";
+        let from_idx = desc.find(from).unwrap();
+        let to_idx = desc.find(to).unwrap() + to.len();
+        digest_from_raw(&desc[from_idx..to_idx])
+    };
+    Ok((desc, desc_digest))
+}
+
+/// Generate the microdescriptor of a relay (without annotations). Returns the
+/// microdescriptor and its digest as used in the microdesc consensus.
+fn render_microdescriptor(
+    relay: &Relay,
+    relay_keys: &RelayKeys,
+) -> Result<(String, String), OutputError> {
+    use std::fmt::Write;
+
+    let mut md = relay_keys.microdescriptor_key_items()?;
+    if let Some(family_line) = family_line(relay) {
+        writeln!(&mut md, "{}", family_line)?;
+    }
+    writeln!(&mut md, "p {}", relay.exit_policy)?;
+    md.push_str(&relay_keys.microdescriptor_id_item());
+
+    let digest = base64::encode_config(Sha256::digest(md.as_bytes()), base64::STANDARD_NO_PAD);
+    Ok((md, digest))
+}
+
+/// The supported protocol versions of a relay, as used in "pr" and "proto"
+/// lines
+fn protocols_string(relay: &Relay) -> Option<String> {
+    relay.protocols.as_ref().map(|protocols| {
+        protocols
+            .iter()
+            .map(|(protocol, version)| format!("{}={}", <&'static str>::from(protocol), version))
+            .collect::<Vec<_>>()
+            .join(" ")
+    })
+}
+
+/// The "family" line of a relay's (micro)descriptor, if it has a family
+fn family_line(relay: &Relay) -> Option<String> {
+    relay.family.as_ref().map(|fam| {
+        format!(
+            "family {}",
+            fam.members
+                .iter()
+                .map(|fp| format!("${}", fp.to_string_hex()))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    })
+}
+
+/// Generate a consensus document of the given flavor (without annotations),
+/// signed by the authorities if any are given
+fn render_consensus(
+    consensus: &Consensus,
+    entries: &[ConsensusEntry],
+    flavor: ConsensusFlavor,
+    options: &OutputOptions,
+) -> Result<String, OutputError> {
+    use std::fmt::Write;
+
+    // output meta info
+    let mut consensus_doc = String::new();
+
+    match flavor {
+        ConsensusFlavor::Ns => writeln!(&mut consensus_doc, "network-status-version 3")?,
+        ConsensusFlavor::Microdesc => {
+            writeln!(&mut consensus_doc, "network-status-version 3 microdesc")?
+        }
+    }
+    writeln!(&mut consensus_doc, "vote-status consensus")?;
+    consensus.header.write_items(
+        &mut consensus_doc,
+        consensus.valid_after,
+        &Flag::known_flags_string(),
+    )?;
+
+    // output authorities
+    if let Some(ref authorities) = options.authorities {
+        write!(
+            &mut consensus_doc,
+            "{}",
+            authorities.authority_section(consensus.valid_after)?
         )?;
+    }
+
+    // output relays
+    for entry in entries {
+        let relay = entry.relay;
+        match flavor {
+            ConsensusFlavor::Ns => writeln!(
+                &mut consensus_doc,
+                "r {} {} {} {} {} {} {}",
+                relay.nickname,
+                relay.fingerprint.to_string_b64(),
+                entry.digest,
+                relay.published.format("%Y-%m-%d %H:%M:%S"),
+                relay.address,
+                relay.or_port,
+                relay.dir_port.unwrap_or(0),
+            )?,
+            ConsensusFlavor::Microdesc => {
+                writeln!(
+                    &mut consensus_doc,
+                    "r {} {} {} {} {} {}",
+                    relay.nickname,
+                    relay.fingerprint.to_string_b64(),
+                    relay.published.format("%Y-%m-%d %H:%M:%S"),
+                    relay.address,
+                    relay.or_port,
+                    relay.dir_port.unwrap_or(0),
+                )?;
+                writeln!(&mut consensus_doc, "m {}", entry.digest)?;
+            }
+        }
         writeln!(
-            &mut f_consensus,
+            &mut consensus_doc,
             "s {}",
             relay
                 .flags
@@ -298,32 +519,25 @@ fn save_to(
                 .join(" ")
                 .to_string()
         )?;
-        writeln!(&mut f_consensus, "v Tor 0.4.6.10")?; // TODO version
+        if let Some(ref version_line) = relay.version_line {
+            writeln!(&mut consensus_doc, "v {}", version_line)?;
+        }
 
         writeln!(
-            &mut f_consensus,
+            &mut consensus_doc,
             "pr {}",
-            match relay.protocols {
-                Some(ref protocols) => {
-                    protocols
-                        .iter()
-                        .map(|(protocol, version)| {
-                            format!("{}={}", <&'static str>::from(protocol), version.to_string())
-                        })
-                        .collect::<Vec<_>>()
-                        .join(" ")
-                }
-                None => "".to_string(),
-            }
+            protocols_string(relay).unwrap_or_default()
         )?;
 
-        writeln!(&mut f_consensus, "w Bandwidth={}", relay.bandwidth_weight)?;
-        writeln!(&mut f_consensus, "p {}", relay.exit_policy)?;
+        writeln!(&mut consensus_doc, "w Bandwidth={}", relay.bandwidth_weight)?;
+        if flavor == ConsensusFlavor::Ns {
+            writeln!(&mut consensus_doc, "p {}", relay.exit_policy)?;
+        }
     }
 
-    writeln!(&mut f_consensus, "directory-footer")?;
+    writeln!(&mut consensus_doc, "directory-footer")?;
     writeln!(
-        &mut f_consensus,
+        &mut consensus_doc,
         "bandwidth-weights {}",
         consensus
             .weights
@@ -333,11 +547,517 @@ fn save_to(
             .join(" ")
     )?;
 
+    // sign the consensus
+    if let Some(ref authorities) = options.authorities {
+        let algorithm = match flavor {
+            ConsensusFlavor::Ns => DigestAlgorithm::Sha1,
+            ConsensusFlavor::Microdesc => DigestAlgorithm::Sha256,
+        };
+        let signatures = authorities.sign_consensus(&consensus_doc, algorithm)?;
+        consensus_doc.push_str(&signatures);
+    }
+
+    Ok(consensus_doc)
+}
+
+fn save_to(
+    consensus: &Consensus,
+    consensus_path: impl AsRef<Path>,
+    consensus_json_path: Option<impl AsRef<Path>>,
+    descriptor_path: impl FnMut(&Fingerprint) -> Result<PathBuf, OutputError>,
+    options: &OutputOptions,
+) -> Result<WrittenDocuments, OutputError> {
+    let consensus_path = consensus_path.as_ref();
+    let mut descriptor_path = descriptor_path;
+
+    // output descriptors
+    let mut entries = Vec::new();
+    let mut descriptor_paths = Vec::new();
+    for relay in sorted_relays(consensus) {
+        let (desc, desc_digest) = render_descriptor(relay, options)?;
+        let path = descriptor_path(&desc_digest)?;
+        fs::write(&path, format!("@type server-descriptor 1.0\n{}", desc))?;
+        descriptor_paths.push(path);
+        entries.push(ConsensusEntry {
+            relay,
+            digest: desc_digest.to_string_b64(),
+        });
+    }
+
+    // output consensus
+    let consensus_doc = render_consensus(consensus, &entries, ConsensusFlavor::Ns, options)?;
+    fs::write(
+        consensus_path,
+        format!("@type network-status-consensus-3 1.0\n{}", consensus_doc),
+    )?;
+
     // save consensus JSON
     if let Some(consensus_json_path) = consensus_json_path {
         save_consensus_json(consensus, consensus_json_path)?;
     }
 
+    Ok(WrittenDocuments {
+        consensus_path: consensus_path.to_path_buf(),
+        descriptor_paths,
+    })
+}
+
+/// Save the consensus and descriptors in the layout of a Tor DataDirectory
+/// (`cached-consensus`, `cached-microdesc-consensus`, `cached-descriptors`
+/// and `cached-microdescs`, plus `cached-certs` if the consensus is signed).
+/// As microdescriptors consist mainly of the relays' onion keys, this requires
+/// relay keys in the options.
+pub fn save_to_tor_cache_dir(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    options: &OutputOptions,
+) -> Result<(), OutputError> {
+    let dir: &Path = dir.as_ref();
+    check_output_dir(dir)?;
+    let relay_keys = options
+        .relay_keys
+        .as_ref()
+        .ok_or(OutputError::MissingRelayKeys)?;
+
+    let downloaded_at = consensus.valid_after.format("%Y-%m-%d %H:%M:%S");
+
+    let mut descriptors = String::new();
+    let mut microdescriptors = String::new();
+    let mut ns_entries = Vec::new();
+    let mut md_entries = Vec::new();
+    for relay in sorted_relays(consensus) {
+        let (desc, desc_digest) = render_descriptor(relay, options)?;
+        descriptors.push_str(&format!(
+            "@downloaded-at {}\n@source \"127.0.0.1\"\n{}",
+            downloaded_at, desc
+        ));
+        ns_entries.push(ConsensusEntry {
+            relay,
+            digest: desc_digest.to_string_b64(),
+        });
+
+        let keys = relay_keys
+            .get(&relay.fingerprint)
+            .ok_or(OutputError::MissingRelayKeys)?;
+        let (md, md_digest) = render_microdescriptor(relay, keys)?;
+        microdescriptors.push_str(&format!("@last-listed {}\n{}", downloaded_at, md));
+        md_entries.push(ConsensusEntry {
+            relay,
+            digest: md_digest,
+        });
+    }
+
+    fs::write(
+        dir.join("cached-consensus"),
+        render_consensus(consensus, &ns_entries, ConsensusFlavor::Ns, options)?,
+    )?;
+    fs::write(
+        dir.join("cached-microdesc-consensus"),
+        render_consensus(consensus, &md_entries, ConsensusFlavor::Microdesc, options)?,
+    )?;
+    fs::write(dir.join("cached-descriptors"), descriptors)?;
+    fs::write(dir.join("cached-microdescs"), microdescriptors)?;
+
+    save_lineage(consensus, dir, "lineage")?;
+    save_authority_files(dir, options)
+}
+
+#[derive(Serialize)]
+struct StagingRelayInfo {
+    min_unix_time: i64,
+    max_unix_time: i64,
+    network_stats: StagingNetworkStats,
+    relays: BTreeMap<String, StagingRelay>,
+}
+
+#[derive(Serialize, Default)]
+struct StagingNetworkStats {
+    med_count_exitguard: usize,
+    med_count_guard: usize,
+    med_count_exit: usize,
+    med_count_middle: usize,
+    med_count_total: usize,
+    med_bwfrac_exitguard: f64,
+    med_bwfrac_guard: f64,
+    med_bwfrac_exit: f64,
+    med_bwfrac_middle: f64,
+}
+
+#[derive(Serialize)]
+struct StagingRelay {
+    fingerprint: String,
+    nickname: String,
+    address: Ipv4Addr,
+    asn: Option<u32>,
+    country_code: Option<String>,
+    flags: Vec<&'static str>,
+    running_frequency: f64,
+    guard_frequency: f64,
+    exit_frequency: f64,
+    weight: f64,
+    bandwidth_capacity: u64,
+    bandwidth_rate: u64,
+    bandwidth_burst: u64,
+    prob_guard: f64,
+    prob_middle: f64,
+    prob_exit: f64,
+}
+
+/// Save the relays in the format of the relay info staging file produced by
+/// tornettools' `stage` command, so the consensus can directly be used with
+/// `tornettools generate`. As the consensus is a single snapshot, all
+/// frequencies are either 0 or 1 and the network statistics are those of
+/// this consensus. The relays' countries are looked up in `geoip`, if given.
+/// Returns the path of the written file.
+pub fn save_tornettools_staging(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    geoip: Option<&GeoIpDb>,
+) -> Result<PathBuf, OutputError> {
+    let total_weight: u64 = consensus.relays.values().map(|r| r.bandwidth_weight).sum();
+    let weight_fraction = |weight: u64| {
+        if total_weight > 0 {
+            weight as f64 / total_weight as f64
+        } else {
+            0.0
+        }
+    };
+    let frequency = |x: bool| if x { 1.0 } else { 0.0 };
+
+    let probabilities = consensus.selection_probabilities();
+    let mut stats = StagingNetworkStats::default();
+    let mut relays = BTreeMap::new();
+    for (fp, relay) in consensus.relays.iter() {
+        let class = relay.position_class();
+        let weight = weight_fraction(relay.bandwidth_weight);
+        match class {
+            PositionClass::GuardExit => {
+                stats.med_count_exitguard += 1;
+                stats.med_bwfrac_exitguard += weight;
+            }
+            PositionClass::Guard => {
+                stats.med_count_guard += 1;
+                stats.med_bwfrac_guard += weight;
+            }
+            PositionClass::Exit => {
+                stats.med_count_exit += 1;
+                stats.med_bwfrac_exit += weight;
+            }
+            PositionClass::Middle => {
+                stats.med_count_middle += 1;
+                stats.med_bwfrac_middle += weight;
+            }
+        }
+        stats.med_count_total += 1;
+
+        relays.insert(
+            fp.to_string_hex(),
+            StagingRelay {
+                fingerprint: fp.to_string_hex(),
+                nickname: relay.nickname.clone(),
+                address: relay.address,
+                asn: relay.asn.as_ref().map(|x| x.number),
+                country_code: geoip.and_then(|db| db.lookup(relay.address)),
+                flags: relay.flags.iter().map(|f| f.clone().into()).collect(),
+                running_frequency: frequency(relay.has_flag(Flag::Running)),
+                guard_frequency: frequency(relay.has_flag(Flag::Guard)),
+                exit_frequency: frequency(matches!(
+                    class,
+                    PositionClass::Exit | PositionClass::GuardExit
+                )),
+                weight,
+                bandwidth_capacity: relay.bandwidth_observed(),
+                bandwidth_rate: relay.bandwidth_avg(),
+                bandwidth_burst: relay.bandwidth_burst(),
+                prob_guard: probabilities[fp].guard,
+                prob_middle: probabilities[fp].middle,
+                prob_exit: probabilities[fp].exit,
+            },
+        );
+    }
+
+    let result = StagingRelayInfo {
+        min_unix_time: consensus.valid_after.timestamp(),
+        max_unix_time: (consensus.valid_after + Duration::hours(1)).timestamp(),
+        network_stats: stats,
+        relays,
+    };
+
+    let day = consensus.valid_after.format("%Y-%m-%d");
+    let path = dir
+        .as_ref()
+        .join(format!("relayinfo_staging_{}--{}.json", day, day));
+    let mut f = File::create(&path)?;
+    write!(&mut f, "{}", serde_json::to_string_pretty(&result)?)?;
+
+    Ok(path)
+}
+
+/// Minimum bandwidth rate that Tor accepts for relays (in bytes/s)
+const CHUTNEY_MIN_BANDWIDTH: u64 = 76800;
+
+/// Options for the generated chutney network
+pub struct ChutneyOptions {
+    /// Number of relays to include. If smaller than the number of relays in
+    /// the consensus, the network is downscaled, keeping the share of each
+    /// position class and the bandwidth distribution within the classes.
+    pub num_relays: Option<usize>,
+    /// Number of directory authorities
+    pub num_authorities: usize,
+    /// Number of clients
+    pub num_clients: usize,
+    /// Total bandwidth (in bytes/s) to distribute among the relays,
+    /// proportional to their bandwidth in the consensus
+    pub total_bandwidth: u64,
+}
+
+impl Default for ChutneyOptions {
+    fn default() -> Self {
+        ChutneyOptions {
+            num_relays: None,
+            num_authorities: 3,
+            num_clients: 1,
+            total_bandwidth: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// A group of chutney nodes that share the same configuration
+struct ChutneyNodeGroup {
+    class: PositionClass,
+    bandwidth_rate: u64,
+    bandwidth_burst: u64,
+    exit_policy: Option<String>,
+    count: usize,
+}
+
+impl ChutneyNodeGroup {
+    /// The chutney tag of the nodes, which is part of their nickname
+    fn tag(&self) -> &'static str {
+        match self.class {
+            PositionClass::GuardExit => "ge",
+            PositionClass::Guard => "g",
+            PositionClass::Exit => "e",
+            PositionClass::Middle => "m",
+        }
+    }
+
+    fn template(&self) -> &'static str {
+        match self.exit_policy {
+            Some(_) => "torscaler-exit",
+            None => "torscaler-relay",
+        }
+    }
+}
+
+/// Select `num` relays from the given relays, evenly spaced in the order of
+/// their bandwidth
+fn select_by_bandwidth_rank<'a>(mut relays: Vec<&'a Relay>, num: usize) -> Vec<&'a Relay> {
+    if num >= relays.len() {
+        return relays;
+    }
+    relays.sort_by_key(|r| (r.bandwidth_weight, r.fingerprint.to_string_hex()));
+    (0..num)
+        .map(|i| relays[(2 * i + 1) * relays.len() / (2 * num)])
+        .collect()
+}
+
+/// Save a chutney network file and the torrc templates it uses to `dir`
+/// (as `networks/torscaler` and `torrc_templates/torscaler-*.tmpl`), so they
+/// can be copied into a chutney checkout.
+///
+/// Relays with identical role, bandwidth limits and exit policy are grouped
+/// into a single node definition. As chutney networks run on the loopback
+/// interface, exits do not reject private addresses, but otherwise keep the
+/// exit policy of the relay they represent.
+pub fn save_chutney_network(
+    consensus: &Consensus,
+    dir: impl AsRef<Path>,
+    options: &ChutneyOptions,
+) -> Result<(), OutputError> {
+    let dir = dir.as_ref();
+    let classes = [
+        PositionClass::GuardExit,
+        PositionClass::Guard,
+        PositionClass::Exit,
+        PositionClass::Middle,
+    ];
+
+    // select the relays to include, separately for each class
+    let num_total = consensus.relays.len();
+    let mut selected: Vec<&Relay> = Vec::new();
+    for class in classes {
+        let relays: Vec<&Relay> = consensus
+            .relays
+            .values()
+            .filter(|r| r.position_class() == class)
+            .collect();
+        let num = match options.num_relays {
+            Some(n) if n < num_total && !relays.is_empty() => ((relays.len() * n) as f64
+                / num_total as f64)
+                .round()
+                .max(1.0) as usize,
+            _ => relays.len(),
+        };
+        selected.extend(select_by_bandwidth_rank(relays, num));
+    }
+
+    // distribute the available bandwidth and group identical nodes
+    let total_weight: u64 = selected.iter().map(|r| r.bandwidth_weight).sum();
+    let mut groups: Vec<ChutneyNodeGroup> = Vec::new();
+    for relay in selected {
+        let share = if total_weight > 0 {
+            relay.bandwidth_weight as f64 / total_weight as f64
+        } else {
+            0.0
+        };
+        let bandwidth_rate =
+            ((options.total_bandwidth as f64 * share) as u64).max(CHUTNEY_MIN_BANDWIDTH);
+        // round to KBytes so that similar relays end up in the same group
+        let bandwidth_rate = bandwidth_rate / 1024 * 1024;
+        let burst_ratio = relay.bw_ratio_burst as f64 / relay.bw_ratio_avg.max(f32::EPSILON) as f64;
+        let bandwidth_burst = ((bandwidth_rate as f64 * burst_ratio.max(1.0)) as u64) / 1024 * 1024;
+
+        let class = relay.position_class();
+        let exit_policy = match class {
+            PositionClass::Exit | PositionClass::GuardExit => {
+                Some(relay.exit_policy.to_descriptor_lines().join(","))
+            }
+            PositionClass::Guard | PositionClass::Middle => None,
+        };
+
+        match groups.iter_mut().find(|g| {
+            g.class == class
+                && g.bandwidth_rate == bandwidth_rate
+                && g.bandwidth_burst == bandwidth_burst
+                && g.exit_policy == exit_policy
+        }) {
+            Some(group) => group.count += 1,
+            None => groups.push(ChutneyNodeGroup {
+                class,
+                bandwidth_rate,
+                bandwidth_burst,
+                exit_policy,
+                count: 1,
+            }),
+        }
+    }
+
+    // chutney assigns nicknames by node number and tag, so the guards can be
+    // determined in advance
+    let mut guard_nicknames = Vec::new();
+    let mut node_num = options.num_authorities;
+    for group in groups.iter() {
+        for _ in 0..group.count {
+            if matches!(group.class, PositionClass::Guard | PositionClass::GuardExit) {
+                guard_nicknames.push(format!("test{:03}{}", node_num, group.tag()));
+            }
+            node_num += 1;
+        }
+    }
+
+    use std::fmt::Write;
+
+    // network file
+    let mut network = String::new();
+    writeln!(
+        &mut network,
+        "# Generated by torscaler from the consensus valid after {}",
+        consensus.valid_after.format("%Y-%m-%d %H:%M:%S")
+    )?;
+    writeln!(&mut network, "# vim: ft=python")?;
+    writeln!(&mut network)?;
+    writeln!(
+        &mut network,
+        "Authority = Node(tag=\"a\", authority=1, relay=1, torrc=\"torscaler-authority.tmpl\")"
+    )?;
+    writeln!(
+        &mut network,
+        "Client = Node(tag=\"c\", client=1, torrc=\"client.tmpl\")"
+    )?;
+    writeln!(&mut network)?;
+    writeln!(
+        &mut network,
+        "NODES = Authority.getN({})",
+        options.num_authorities
+    )?;
+    for group in groups.iter() {
+        write!(
+            &mut network,
+            "NODES += Node(tag=\"{}\", relay=1, torrc=\"{}.tmpl\", \
+             torscaler_bandwidth_rate={}, torscaler_bandwidth_burst={}",
+            group.tag(),
+            group.template(),
+            group.bandwidth_rate,
+            group.bandwidth_burst,
+        )?;
+        if let Some(ref exit_policy) = group.exit_policy {
+            write!(
+                &mut network,
+                ", exit=1, torscaler_exit_policy=\"{}\"",
+                exit_policy
+            )?;
+        }
+        writeln!(&mut network, ").getN({})", group.count)?;
+    }
+    writeln!(
+        &mut network,
+        "NODES += Client.getN({})",
+        options.num_clients
+    )?;
+    writeln!(&mut network)?;
+    writeln!(&mut network, "ConfigureNodes(NODES)")?;
+
+    let networks_dir = dir.join("networks");
+    fs::create_dir_all(&networks_dir)?;
+    fs::write(networks_dir.join("torscaler"), network)?;
+
+    // torrc templates
+    let templates_dir = dir.join("torrc_templates");
+    fs::create_dir_all(&templates_dir)?;
+    let mut authority = String::from("${include:authority.tmpl}\n");
+    if !guard_nicknames.is_empty() {
+        writeln!(
+            &mut authority,
+            "TestingDirAuthVoteGuard {}",
+            guard_nicknames.join(",")
+        )?;
+        writeln!(&mut authority, "TestingDirAuthVoteGuardIsStrict 1")?;
+    }
+    fs::write(templates_dir.join("torscaler-authority.tmpl"), authority)?;
+
+    // Tor uses the first matching exit policy line, so exits must not include
+    // the non-exit template with its "ExitPolicy reject *:*"
+    let bandwidth = "BandwidthRate ${torscaler_bandwidth_rate}\n\
+                     BandwidthBurst ${torscaler_bandwidth_burst}\n";
+    let relay = format!("${{include:relay-non-exit.tmpl}}\n{}", bandwidth);
+    fs::write(templates_dir.join("torscaler-relay.tmpl"), relay)?;
+    let exit = format!(
+        "${{include:relay.tmpl}}\n{}ExitRelay 1\nExitPolicyRejectPrivate 0\n\
+         ExitPolicy ${{torscaler_exit_policy}}\n",
+        bandwidth
+    );
+    fs::write(templates_dir.join("torscaler-exit.tmpl"), exit)?;
+
+    Ok(())
+}
+
+/// Save the files necessary for Tor to trust the authorities that signed the
+/// consensus (key certificates and torrc lines), if any
+fn save_authority_files(dir: impl AsRef<Path>, options: &OutputOptions) -> Result<(), OutputError> {
+    let dir = dir.as_ref();
+    if let Some(ref authorities) = options.authorities {
+        let certificates: String = authorities
+            .authorities()
+            .iter()
+            .map(|a| a.certificate())
+            .collect();
+        fs::write(dir.join("cached-certs"), certificates)?;
+
+        let mut torrc = authorities.torrc_lines()?.join("\n");
+        torrc.push('\n');
+        fs::write(dir.join("torrc.authorities"), torrc)?;
+    }
     Ok(())
 }
 
@@ -349,3 +1069,54 @@ pub fn digest_from_raw<R: AsRef<[u8]>>(raw: R) -> Fingerprint {
     let result = hasher.finalize();
     Fingerprint::from_u8(&result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chutney_exit_template() {
+        let consensus = Consensus::for_test(
+            vec![
+                Relay::for_test(
+                    1,
+                    "10.0.0.1",
+                    "Fast Guard Running Valid",
+                    100,
+                    "reject 1-65535",
+                ),
+                Relay::for_test(2, "10.1.0.1", "Fast Running Valid", 100, "reject 1-65535"),
+                Relay::for_test(
+                    3,
+                    "10.2.0.1",
+                    "Exit Fast Running Valid",
+                    100,
+                    "accept 80,443",
+                ),
+            ],
+            &[],
+            None,
+        );
+        let dir = std::env::temp_dir().join(format!("torscaler-chutney-{}", std::process::id()));
+        save_chutney_network(&consensus, &dir, &ChutneyOptions::default()).unwrap();
+
+        let network = fs::read_to_string(dir.join("networks/torscaler")).unwrap();
+        let exit_node = network.lines().find(|l| l.contains("tag=\"e\"")).unwrap();
+        assert!(exit_node.contains("torrc=\"torscaler-exit.tmpl\""));
+
+        let relay = fs::read_to_string(dir.join("torrc_templates/torscaler-relay.tmpl")).unwrap();
+        assert!(relay.contains("${include:relay-non-exit.tmpl}"));
+
+        // no reject-all policy may come before the policy of the relay
+        let exit = fs::read_to_string(dir.join("torrc_templates/torscaler-exit.tmpl")).unwrap();
+        assert!(exit.contains("${include:relay.tmpl}"));
+        assert!(!exit.contains("non-exit"));
+        let policies: Vec<&str> = exit
+            .lines()
+            .filter(|l| l.starts_with("ExitPolicy "))
+            .collect();
+        assert_eq!(policies, vec!["ExitPolicy ${torscaler_exit_policy}"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
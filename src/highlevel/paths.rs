@@ -0,0 +1,432 @@
+//! Monte Carlo simulation of circuit path selection, to estimate how often
+//! an adversary controls positions of a circuit.
+
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use serde::Serialize;
+use thiserror;
+
+use seeded_rand::{get_rng, RHashSet};
+use tordoc::{consensus::Flag, Fingerprint};
+
+use super::families::Family;
+use super::stats::wilson_interval;
+use super::{Consensus, PositionWeights, Relay};
+
+/// Standard normal quantile for 95% confidence intervals
+const Z_95: f64 = 1.96;
+
+/// Attempts to pick a relay for a position that is compatible with the
+/// relays chosen so far, before giving up on the circuit
+const MAX_ATTEMPTS: usize = 1000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PathError {
+    #[error("No relay can be chosen for the {0} position")]
+    NoCandidates(&'static str),
+}
+
+/// A set of relays controlled by an adversary
+#[derive(Debug, Clone)]
+pub struct AdversarySet {
+    pub name: String,
+    pub relays: RHashSet<Fingerprint>,
+}
+
+impl AdversarySet {
+    /// All relays in the AS with the given number
+    pub fn from_as(consensus: &Consensus, asn: u32) -> AdversarySet {
+        AdversarySet {
+            name: format!("AS{}", asn),
+            relays: consensus
+                .relays
+                .values()
+                .filter(|r| r.asn.as_ref().is_some_and(|a| a.number == asn))
+                .map(|r| r.fingerprint.clone())
+                .collect(),
+        }
+    }
+
+    /// The relay with the given fingerprint (in hex) and all members of its
+    /// family. Returns `None` if the relay is not in the consensus.
+    pub fn from_family(consensus: &Consensus, fingerprint: &str) -> Option<AdversarySet> {
+        let relay = find_relay(consensus, fingerprint)?;
+        let relays = match relay.family {
+            Some(ref family) => family.members.iter().cloned().collect(),
+            None => [relay.fingerprint.clone()].into_iter().collect(),
+        };
+        Some(AdversarySet {
+            name: format!("family of {}", relay.nickname),
+            relays,
+        })
+    }
+
+    /// The relays with the given fingerprints (in hex). Returns the set and
+    /// the fingerprints that are not in the consensus.
+    pub fn from_relays<S: AsRef<str>>(
+        consensus: &Consensus,
+        name: &str,
+        fingerprints: &[S],
+    ) -> (AdversarySet, Vec<String>) {
+        let mut relays = RHashSet::default();
+        let mut unknown = Vec::new();
+        for fingerprint in fingerprints {
+            match find_relay(consensus, fingerprint.as_ref()) {
+                Some(relay) => {
+                    relays.insert(relay.fingerprint.clone());
+                }
+                None => unknown.push(fingerprint.as_ref().to_string()),
+            }
+        }
+        let set = AdversarySet {
+            name: name.to_string(),
+            relays,
+        };
+        (set, unknown)
+    }
+}
+
+/// Find a relay by its fingerprint in hex, ignoring case and a leading `$`
+fn find_relay<'c>(consensus: &'c Consensus, fingerprint: &str) -> Option<&'c Relay> {
+    let fingerprint = fingerprint.trim().trim_start_matches('$').to_uppercase();
+    consensus
+        .relays
+        .values()
+        .find(|r| r.fingerprint.to_string_hex().to_uppercase() == fingerprint)
+}
+
+/// How often an adversary controlled the positions of the simulated circuits
+#[derive(Debug, Clone, Serialize)]
+pub struct CompromiseRate {
+    pub adversary: String,
+    pub relays: usize,
+    pub guard: f64,
+    pub middle: f64,
+    pub exit: f64,
+    /// Share of circuits with both the guard and the exit controlled by the
+    /// adversary
+    pub end_to_end: f64,
+    /// 95% confidence interval of the end-to-end rate
+    pub end_to_end_ci: Option<(f64, f64)>,
+    /// Share of circuits with any position controlled by the adversary
+    pub any: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PathSimulation {
+    /// Destination port that the exits had to allow
+    pub port: Option<u16>,
+    /// Number of circuits that were built
+    pub circuits: usize,
+    /// Number of circuits that could not be built because no compatible
+    /// relay was found for a position
+    pub failed: usize,
+    pub adversaries: Vec<CompromiseRate>,
+}
+
+/// Whether the exit policy summary of a relay (e.g. `accept 80,443` or
+/// `reject 1-1024`) allows connecting to `port`. Returns `None` if the
+/// summary cannot be parsed.
+pub(crate) fn policy_summary_allows(summary: &str, port: u16) -> Option<bool> {
+    let (action, ports) = summary.trim().split_once(' ')?;
+    let accept = match action {
+        "accept" => true,
+        "reject" => false,
+        _ => return None,
+    };
+    let mut listed = false;
+    for range in ports.split(',') {
+        let (low, high) = match range.split_once('-') {
+            Some((low, high)) => (low.parse::<u16>().ok()?, high.parse::<u16>().ok()?),
+            None => {
+                let single = range.parse::<u16>().ok()?;
+                (single, single)
+            }
+        };
+        listed |= low <= port && port <= high;
+    }
+    Some(listed == accept)
+}
+
+/// What the simulation needs to know about a relay
+#[derive(Debug, Clone)]
+struct PathRelay<'r> {
+    fingerprint: &'r Fingerprint,
+    family: Option<&'r Rc<Family>>,
+    address: Ipv4Addr,
+    /// Summary of the exit policy, e.g. `accept 80,443`
+    exit_policy: String,
+    guard_weight: f64,
+    middle_weight: f64,
+    /// Zero for relays with the BadExit flag
+    exit_weight: f64,
+}
+
+impl<'r> PathRelay<'r> {
+    fn new(relay: &'r Relay, weights: &PositionWeights) -> PathRelay<'r> {
+        PathRelay {
+            fingerprint: &relay.fingerprint,
+            family: relay.family.as_ref(),
+            address: relay.address,
+            exit_policy: relay.exit_policy.to_string(),
+            guard_weight: weights.guard_weight(relay),
+            middle_weight: weights.middle_weight(relay),
+            exit_weight: if relay.has_flag(Flag::BadExit) {
+                0.0
+            } else {
+                weights.exit_weight(relay)
+            },
+        }
+    }
+
+    /// Whether the relay can be used as exit for `port`. Returns `None` if
+    /// the exit policy cannot be parsed.
+    fn allows_port(&self, port: u16) -> Option<bool> {
+        policy_summary_allows(&self.exit_policy, port)
+    }
+}
+
+fn same_family(a: &PathRelay, b: &PathRelay) -> bool {
+    match (a.family, b.family) {
+        (Some(x), Some(y)) => Rc::ptr_eq(x, y),
+        _ => false,
+    }
+}
+
+fn same_subnet(a: Ipv4Addr, b: Ipv4Addr) -> bool {
+    a.octets()[..2] == b.octets()[..2]
+}
+
+/// Whether two relays may be used in the same circuit
+fn compatible(a: &PathRelay, b: &PathRelay) -> bool {
+    a.fingerprint != b.fingerprint && !same_family(a, b) && !same_subnet(a.address, b.address)
+}
+
+/// Pick a relay from the distribution that is compatible with the relays
+/// chosen so far
+fn pick<'a, 'r, R: Rng>(
+    relays: &'a [PathRelay<'r>],
+    distribution: &WeightedIndex<f64>,
+    chosen: &[&PathRelay],
+    rng: &mut R,
+) -> Option<&'a PathRelay<'r>> {
+    (0..MAX_ATTEMPTS)
+        .map(|_| &relays[distribution.sample(rng)])
+        .find(|candidate| chosen.iter().all(|r| compatible(candidate, r)))
+}
+
+/// Simulate building `num_circuits` circuits, and count how often each
+/// adversary controls their positions.
+///
+/// As in Tor, the exit is chosen first, then the guard and the middle relay,
+/// each weighted by its bandwidth weight for the position. No two relays of
+/// a circuit may be in the same family or /16 network. If a `port` is given,
+/// only exits whose exit policy allows it are chosen. Every circuit chooses
+/// a new guard, so the rates correspond to many clients building one
+/// circuit each.
+pub fn simulate_paths(
+    consensus: &Consensus,
+    num_circuits: usize,
+    port: Option<u16>,
+    adversaries: &[AdversarySet],
+) -> Result<PathSimulation, PathError> {
+    let weights = PositionWeights::from_consensus(consensus);
+    let relays: Vec<PathRelay> = consensus
+        .relays
+        .values()
+        .map(|relay| PathRelay::new(relay, &weights))
+        .collect();
+    simulate(&relays, num_circuits, port, adversaries, &mut get_rng())
+}
+
+fn simulate<R: Rng>(
+    relays: &[PathRelay],
+    num_circuits: usize,
+    port: Option<u16>,
+    adversaries: &[AdversarySet],
+    rng: &mut R,
+) -> Result<PathSimulation, PathError> {
+    if let Some(port) = port {
+        let unparsable = relays
+            .iter()
+            .filter(|r| r.exit_weight > 0.0 && r.allows_port(port).is_none())
+            .count();
+        if unparsable > 0 {
+            eprintln!(
+                "[Warning] Cannot parse the exit policy of {} exits, not using them as exits",
+                unparsable
+            );
+        }
+    }
+
+    let distribution = |position: &'static str, weight: &dyn Fn(&PathRelay) -> f64| {
+        WeightedIndex::new(relays.iter().map(|r| weight(r)))
+            .map_err(|_| PathError::NoCandidates(position))
+    };
+    let exits = distribution("exit", &|r| {
+        if port.is_some_and(|port| r.allows_port(port) != Some(true)) {
+            0.0
+        } else {
+            r.exit_weight
+        }
+    })?;
+    let guards = distribution("guard", &|r| r.guard_weight)?;
+    let middles = distribution("middle", &|r| r.middle_weight)?;
+
+    // number of circuits with the guard, middle, exit, both ends, or any
+    // position controlled, per adversary
+    let mut counts = vec![[0usize; 5]; adversaries.len()];
+    let mut circuits = 0;
+    let mut failed = 0;
+    for _ in 0..num_circuits {
+        let exit = &relays[exits.sample(rng)];
+        let guard = match pick(relays, &guards, &[exit], rng) {
+            Some(guard) => guard,
+            None => {
+                failed += 1;
+                continue;
+            }
+        };
+        let middle = match pick(relays, &middles, &[exit, guard], rng) {
+            Some(middle) => middle,
+            None => {
+                failed += 1;
+                continue;
+            }
+        };
+        circuits += 1;
+
+        for (adversary, counts) in std::iter::zip(adversaries, counts.iter_mut()) {
+            let [g, m, e] = [guard, middle, exit].map(|r| adversary.relays.contains(r.fingerprint));
+            for (i, controlled) in [g, m, e, g && e, g || m || e].into_iter().enumerate() {
+                counts[i] += controlled as usize;
+            }
+        }
+    }
+
+    let rate = |count: usize| {
+        if circuits == 0 {
+            0.0
+        } else {
+            count as f64 / circuits as f64
+        }
+    };
+    let adversaries = std::iter::zip(adversaries, counts)
+        .map(|(adversary, counts)| CompromiseRate {
+            adversary: adversary.name.clone(),
+            relays: adversary.relays.len(),
+            guard: rate(counts[0]),
+            middle: rate(counts[1]),
+            exit: rate(counts[2]),
+            end_to_end: rate(counts[3]),
+            end_to_end_ci: wilson_interval(counts[3], circuits, Z_95),
+            any: rate(counts[4]),
+        })
+        .collect();
+
+    Ok(PathSimulation {
+        port,
+        circuits,
+        failed,
+        adversaries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn policy_summaries() {
+        assert_eq!(policy_summary_allows("accept 80,443", 443), Some(true));
+        assert_eq!(policy_summary_allows("accept 80,443", 22), Some(false));
+        assert_eq!(policy_summary_allows("accept 20-23,80", 22), Some(true));
+        assert_eq!(policy_summary_allows("reject 1-65535", 80), Some(false));
+        assert_eq!(policy_summary_allows("reject 25,119", 80), Some(true));
+        assert_eq!(policy_summary_allows("reject 25,119", 25), Some(false));
+        assert_eq!(policy_summary_allows("allow 80", 80), None);
+    }
+
+    fn fp(id: u8) -> Fingerprint {
+        Fingerprint::from_u8(&[id; 20])
+    }
+
+    fn adversary(name: &str, ids: &[u8]) -> AdversarySet {
+        AdversarySet {
+            name: name.to_string(),
+            relays: ids.iter().map(|id| fp(*id)).collect(),
+        }
+    }
+
+    #[test]
+    fn simulation() {
+        let fingerprints: Vec<Fingerprint> = (0..7).map(fp).collect();
+        let family = Rc::new(Family {
+            members: vec![fp(0), fp(1)],
+        });
+        let relay =
+            |id: usize,
+             in_family: bool,
+             address: &str,
+             exit_policy: &str,
+             [guard_weight, middle_weight, exit_weight]: [f64; 3]| PathRelay {
+                fingerprint: &fingerprints[id],
+                family: if in_family { Some(&family) } else { None },
+                address: address.parse().unwrap(),
+                exit_policy: exit_policy.to_string(),
+                guard_weight,
+                middle_weight,
+                exit_weight,
+            };
+        let relays = vec![
+            // guard in the same family as the only usable exit
+            relay(0, true, "10.0.0.1", "reject 1-65535", [10.0, 0.0, 0.0]),
+            // the only exit that allows port 443
+            relay(1, true, "20.0.0.1", "accept 80,443", [0.0, 0.0, 1.0]),
+            // exits that do not allow port 443 or have an invalid policy
+            relay(2, false, "30.0.0.1", "reject 443", [0.0, 0.0, 10.0]),
+            relay(3, false, "40.0.0.1", "invalid", [0.0, 0.0, 10.0]),
+            // the only guard that can be combined with the exit
+            relay(4, false, "50.0.0.1", "reject 1-65535", [1.0, 0.0, 0.0]),
+            // middle in the same /16 as the exit
+            relay(5, false, "20.0.1.1", "reject 1-65535", [0.0, 10.0, 0.0]),
+            relay(6, false, "60.0.0.1", "reject 1-65535", [0.0, 1.0, 0.0]),
+        ];
+        let adversaries = [
+            adversary("family", &[0, 1]),
+            adversary("guard", &[4]),
+            adversary("unusable", &[2, 3, 5]),
+        ];
+
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let result = simulate(&relays, 100, Some(443), &adversaries, &mut rng).unwrap();
+        assert_eq!(result.circuits, 100);
+        assert_eq!(result.failed, 0);
+
+        let family_rates = &result.adversaries[0];
+        assert_eq!(family_rates.exit, 1.0);
+        assert_eq!(family_rates.guard, 0.0);
+        assert_eq!(family_rates.end_to_end, 0.0);
+        let guard = &result.adversaries[1];
+        assert_eq!(guard.guard, 1.0);
+        assert_eq!(guard.any, 1.0);
+        let unusable = &result.adversaries[2];
+        assert_eq!(unusable.any, 0.0);
+
+        // without a port, the other exits are used as well
+        let result = simulate(&relays, 100, None, &adversaries, &mut rng).unwrap();
+        assert!(result.adversaries[2].exit > 0.0);
+
+        // no exit allows port 22
+        assert!(matches!(
+            simulate(&relays, 100, Some(22), &adversaries, &mut rng),
+            Err(PathError::NoCandidates("exit"))
+        ));
+    }
+}
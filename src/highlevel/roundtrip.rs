@@ -0,0 +1,250 @@
+//! Validation of written documents by parsing them again and comparing the
+//! result to the consensus they were generated from.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use serde::Serialize;
+use thiserror;
+
+use super::asn::AsnDb;
+use super::output::WrittenDocuments;
+use super::{Consensus, Relay, UnpackedConsensus};
+
+/// Maximum relative deviation of bandwidth values, caused by storing them as
+/// ratios to the bandwidth weight
+const BANDWIDTH_TOLERANCE: f64 = 0.001;
+
+/// A difference between the written documents and the original consensus
+#[derive(Debug, Clone, Serialize)]
+pub enum Discrepancy {
+    /// A relay of the consensus is missing from the written documents
+    MissingRelay { fingerprint: String },
+    /// The written documents contain a relay not in the consensus
+    UnexpectedRelay { fingerprint: String },
+    /// A property of a relay differs
+    RelayField {
+        fingerprint: String,
+        field: &'static str,
+        expected: String,
+        found: String,
+    },
+    /// A bandwidth weight differs
+    Weight {
+        key: String,
+        expected: Option<u64>,
+        found: Option<u64>,
+    },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::MissingRelay { fingerprint } => {
+                write!(f, "relay {} is missing", fingerprint)
+            }
+            Discrepancy::UnexpectedRelay { fingerprint } => {
+                write!(f, "relay {} is unexpected", fingerprint)
+            }
+            Discrepancy::RelayField {
+                fingerprint,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "relay {}: {} is {} instead of {}",
+                fingerprint, field, found, expected
+            ),
+            Discrepancy::Weight {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "bandwidth weight {} is {:?} instead of {:?}",
+                key, found, expected
+            ),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RoundTripError {
+    #[error("General I/O error")]
+    IoError(#[from] io::Error),
+    #[error("The written documents could not be parsed or combined")]
+    DocumentError(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("The written documents differ from the consensus in {} places", .0.len())]
+    Discrepancies(Vec<Discrepancy>),
+}
+
+/// Parse the written consensus and descriptors again, combine them and check
+/// that the result matches the consensus they were generated from.
+pub fn validate_written_documents(
+    original: &Consensus,
+    written: &WrittenDocuments,
+    asn_db: &AsnDb,
+) -> Result<(), RoundTripError> {
+    let raw = fs::read_to_string(&written.consensus_path)?;
+    let unpacked = raw
+        .parse::<UnpackedConsensus>()
+        .map_err(RoundTripError::DocumentError)?;
+
+    let mut descriptors = Vec::with_capacity(written.descriptor_paths.len());
+    for path in written.descriptor_paths.iter() {
+        let raw = fs::read_to_string(path)?;
+        descriptors.push(
+            tordoc::Descriptor::from_str(&raw)
+                .map_err(|e| RoundTripError::DocumentError(Box::new(e)))?,
+        );
+    }
+
+    let reparsed = Consensus::combine_documents(unpacked, descriptors, asn_db)
+        .map_err(RoundTripError::DocumentError)?;
+
+    let discrepancies = compare(original, &reparsed);
+    if discrepancies.is_empty() {
+        Ok(())
+    } else {
+        Err(RoundTripError::Discrepancies(discrepancies))
+    }
+}
+
+/// Find all discrepancies between the original and the re-parsed consensus
+fn compare(original: &Consensus, reparsed: &Consensus) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+
+    for (fp, relay) in original.relays.iter() {
+        match reparsed.relays.get(fp) {
+            Some(other) => compare_relays(relay, other, &mut discrepancies),
+            None => discrepancies.push(Discrepancy::MissingRelay {
+                fingerprint: fp.to_string_hex(),
+            }),
+        }
+    }
+    for fp in reparsed.relays.keys() {
+        if !original.relays.contains_key(fp) {
+            discrepancies.push(Discrepancy::UnexpectedRelay {
+                fingerprint: fp.to_string_hex(),
+            });
+        }
+    }
+
+    let keys: BTreeSet<&String> = original
+        .weights
+        .keys()
+        .chain(reparsed.weights.keys())
+        .collect();
+    for key in keys {
+        let expected = original.weights.get(key).copied();
+        let found = reparsed.weights.get(key).copied();
+        if expected != found {
+            discrepancies.push(Discrepancy::Weight {
+                key: key.clone(),
+                expected,
+                found,
+            });
+        }
+    }
+
+    discrepancies
+}
+
+fn compare_relays(expected: &Relay, found: &Relay, discrepancies: &mut Vec<Discrepancy>) {
+    let mut check = |field: &'static str, expected_value: String, found_value: String| {
+        if expected_value != found_value {
+            discrepancies.push(Discrepancy::RelayField {
+                fingerprint: expected.fingerprint.to_string_hex(),
+                field,
+                expected: expected_value,
+                found: found_value,
+            });
+        }
+    };
+
+    check(
+        "nickname",
+        expected.nickname.clone(),
+        found.nickname.clone(),
+    );
+    check(
+        "address",
+        expected.address.to_string(),
+        found.address.to_string(),
+    );
+    check(
+        "ORPort",
+        expected.or_port.to_string(),
+        found.or_port.to_string(),
+    );
+    check(
+        "DirPort",
+        expected.dir_port.unwrap_or(0).to_string(),
+        found.dir_port.unwrap_or(0).to_string(),
+    );
+    check(
+        "version",
+        format!("{:?}", expected.version_line),
+        format!("{:?}", found.version_line),
+    );
+    check(
+        "published",
+        expected.published.to_string(),
+        found.published.to_string(),
+    );
+    check("flags", flags_string(expected), flags_string(found));
+    check(
+        "bandwidth weight",
+        expected.bandwidth_weight.to_string(),
+        found.bandwidth_weight.to_string(),
+    );
+    check(
+        "exit policy",
+        expected.exit_policy.to_string(),
+        found.exit_policy.to_string(),
+    );
+    check("family", family_string(expected), family_string(found));
+
+    let bandwidths = [
+        (
+            "average bandwidth",
+            expected.bandwidth_avg(),
+            found.bandwidth_avg(),
+        ),
+        (
+            "burst bandwidth",
+            expected.bandwidth_burst(),
+            found.bandwidth_burst(),
+        ),
+        (
+            "observed bandwidth",
+            expected.bandwidth_observed(),
+            found.bandwidth_observed(),
+        ),
+    ];
+    for (field, expected_value, found_value) in bandwidths {
+        let difference = (expected_value as f64 - found_value as f64).abs();
+        if difference > 1.0 && difference > BANDWIDTH_TOLERANCE * expected_value as f64 {
+            check(field, expected_value.to_string(), found_value.to_string());
+        }
+    }
+}
+
+fn flags_string(relay: &Relay) -> String {
+    let flags: BTreeSet<&'static str> = relay.flags.iter().map(<&'static str>::from).collect();
+    flags.into_iter().collect::<Vec<_>>().join(" ")
+}
+
+fn family_string(relay: &Relay) -> String {
+    match relay.family {
+        Some(ref family) => {
+            let members: BTreeSet<String> =
+                family.members.iter().map(|fp| fp.to_string_hex()).collect();
+            members.into_iter().collect::<Vec<_>>().join(" ")
+        }
+        None => String::new(),
+    }
+}
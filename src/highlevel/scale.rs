@@ -1,5 +1,6 @@
 //! Algorithms for scaling Tor consensuses.
 
+use std::collections::BTreeMap;
 use std::rc::Rc;
 
 use rand::distributions::weighted::WeightedError;
@@ -8,11 +9,54 @@ use rand::Rng;
 
 use super::asn::{Asn, AsnDb};
 use super::families::{self, Family};
-use super::{Consensus, Relay};
+use super::{Consensus, PositionClass, Relay};
+
+use serde::Serialize;
 
 use seeded_rand::{get_rng, RHashMap, RHashSet};
 use tordoc::{consensus::Flag, Fingerprint};
 
+/// How a synthetic relay was created by horizontal scaling
+#[derive(Debug, Clone)]
+pub struct Lineage {
+    /// Fingerprint of the relay this relay was cloned from. It is not changed
+    /// when the relays get new fingerprints from generated keys, so it keeps
+    /// referring to the input consensus.
+    pub base_fingerprint: Fingerprint,
+    /// How the relay got its family
+    pub family: FamilyDecision,
+    /// Whether the relay was placed in the same AS as the relay it was
+    /// related to when joining or founding a family. `None` if there was no
+    /// such decision, or if it could not be satisfied.
+    pub same_as_family: Option<bool>,
+}
+
+/// The family decision taken for a synthetic relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FamilyDecision {
+    /// The relay was not put into a family
+    NoFamily,
+    /// The relay joined the family of an existing relay
+    JoinedFamily,
+    /// The relay is a member of a newly created family
+    NewFamily,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ScaleError {
+    #[error("scale must be positive, not {0}")]
+    InvalidScale(f32),
+    #[error("{name} cannot be negative, not {value}")]
+    NegativeFactor { name: &'static str, value: f32 },
+    #[error("probability for new families must be between 0 and 1, not {0}")]
+    InvalidProbability(f32),
+    #[error("cannot choose a relay to clone")]
+    Sampling(#[from] WeightedError),
+}
+
+/// Add relays to the consensus (or remove relays from it, for a scale below
+/// 1) so that it has `scale` times as many relays. Fails on invalid
+/// parameters instead of changing the consensus.
 pub fn scale_horizontally(
     consensus: &mut Consensus,
     scale: f32,
@@ -20,23 +64,33 @@ pub fn scale_horizontally(
     guard_factor: Option<f32>,
     asn_db: &AsnDb,
     prob_family_new: f32,
-) {
-    if scale < 1.0 {
-        unimplemented!("For now, horizontal scaling can only scale _up_");
+) -> Result<(), ScaleError> {
+    if !(scale > 0.0) {
+        return Err(ScaleError::InvalidScale(scale));
     }
 
     let mut rng = get_rng();
     let exit_factor = exit_factor.unwrap_or(1.0);
     let guard_factor = guard_factor.unwrap_or(1.0);
 
-    if exit_factor < 0.0 {
-        panic!("exit factor cannot be negative.");
+    if !(exit_factor >= 0.0) {
+        return Err(ScaleError::NegativeFactor {
+            name: "exit factor",
+            value: exit_factor,
+        });
     }
-    if guard_factor < 0.0 {
-        panic!("exit factor cannot be negative.");
+    if !(guard_factor >= 0.0) {
+        return Err(ScaleError::NegativeFactor {
+            name: "guard factor",
+            value: guard_factor,
+        });
     }
-    if prob_family_new < 0.0 || prob_family_new > 1.0 {
-        panic!("probability for new families must be between 0 and 1.");
+    if !(0.0..=1.0).contains(&prob_family_new) {
+        return Err(ScaleError::InvalidProbability(prob_family_new));
+    }
+    if scale < 1.0 {
+        shrink_horizontally(consensus, scale, exit_factor, guard_factor);
+        return Ok(());
     }
     // number of relays
     let num_relays_before = consensus.relays.len() as u32;
@@ -78,13 +132,20 @@ pub fn scale_horizontally(
         let same_as = rng.gen_bool(prob_family_sameas as f64);
 
         // choose a base relay
-        let chosen_relay = RelaySampler::with_flag_weights(&old_relays, &flag_weights).sample();
+        let chosen_relay =
+            RelaySampler::with_flag_weights(&old_relays, &flag_weights).sample_checked()?;
 
         if in_family {
             // this relay shall belong to a family
             if new_family {
                 // create a family for it later
-                new_relays_needing_family.push(chosen_relay.clone());
+                let mut new_relay = chosen_relay.clone();
+                new_relay.lineage = Some(Lineage {
+                    base_fingerprint: chosen_relay.fingerprint.clone(),
+                    family: FamilyDecision::NewFamily,
+                    same_as_family: None,
+                });
+                new_relays_needing_family.push(new_relay);
             } else {
                 // The relay should join an existing family. For this, we need a
                 // reference relay that has a family and does match our previously
@@ -110,25 +171,43 @@ pub fn scale_horizontally(
                             }
                         }
                     } else {
+                        // Likewise, there may be no relay with a family in
+                        // any other AS
                         sampler.set_not_from_as(chosen_relay.asn.clone());
-                        sampler.sample()
+                        match sampler.sample_checked() {
+                            Ok(r) => r,
+                            Err(e) => {
+                                assert_eq!(e, WeightedError::AllWeightsZero);
+                                continue;
+                            }
+                        }
                     }
                 };
                 let mut new_relay = chosen_relay.clone();
                 new_relay.family = family_ref_relay.family.clone();
+                new_relay.lineage = Some(Lineage {
+                    base_fingerprint: chosen_relay.fingerprint.clone(),
+                    family: FamilyDecision::JoinedFamily,
+                    same_as_family: Some(same_as),
+                });
                 new_relays_with_family.push(new_relay);
             }
         } else {
             // this relay shall not belong to a family
             let mut new_relay = chosen_relay.clone();
             new_relay.family = None;
+            new_relay.lineage = Some(Lineage {
+                base_fingerprint: chosen_relay.fingerprint.clone(),
+                family: FamilyDecision::NoFamily,
+                same_as_family: None,
+            });
             new_relays_with_family.push(new_relay.clone());
         }
         created_relays += 1;
     }
     // Customize the relays. We need to do this here because they need to have
     // their final fingerprints for constructing families later.
-    let mut customizer = Customizer::new(asn_db);
+    let mut customizer = Customizer::new(asn_db, &consensus.relays);
     for haystack in [&mut new_relays_with_family, &mut new_relays_needing_family] {
         for relay in haystack.iter_mut() {
             customizer.customize_relay(relay);
@@ -184,7 +263,7 @@ pub fn scale_horizontally(
 
             // get a new family member that satisfies the AS relation if possible
             let new_relays_needing_family_ref: Vec<_> = new_relays_needing_family.iter().collect();
-            let new_member = {
+            let (new_member, same_as_satisfied) = {
                 let mut sampler = RelaySampler::unbiased(&new_relays_needing_family_ref);
 
                 if same_as {
@@ -193,12 +272,15 @@ pub fn scale_horizontally(
                     sampler.set_not_from_as(ref_member.asn.clone());
                 }
                 match sampler.sample_checked() {
-                    Ok(r) => r,
+                    Ok(r) => (r, true),
                     Err(e) => {
                         assert_eq!(e, WeightedError::AllWeightsZero);
                         // sample again without AS restriction
                         // println!("have to ignore AS restriction once (same AS: {})", same_as);
-                        RelaySampler::unbiased(&new_relays_needing_family_ref).sample()
+                        (
+                            RelaySampler::unbiased(&new_relays_needing_family_ref).sample(),
+                            false,
+                        )
                     }
                 }
             };
@@ -206,7 +288,14 @@ pub fn scale_horizontally(
                 .iter()
                 .position(|x| x as *const Relay == new_member as *const Relay)
                 .unwrap();
-            let new_member = new_relays_needing_family.swap_remove(position);
+            let mut new_member = new_relays_needing_family.swap_remove(position);
+            if let Some(ref mut lineage) = new_member.lineage {
+                lineage.same_as_family = if same_as_satisfied {
+                    Some(same_as)
+                } else {
+                    None
+                };
+            }
             current_members.push(new_member);
         }
         let family = Rc::new(Family {
@@ -240,33 +329,74 @@ pub fn scale_horizontally(
     //     "New relay: {} {:?}",
     //     &new_relay.fingerprint, &new_relay.flags
     // );
+    Ok(())
+}
+
+/// Add `num` relays that are clones of randomly chosen existing relays (with
+/// customized fingerprint, nickname and address), without family. Bandwidth
+/// weights and statistics are not recomputed. Returns the fingerprints of the
+/// new relays.
+pub(super) fn add_cloned_relays(
+    consensus: &mut Consensus,
+    num: usize,
+    asn_db: &AsnDb,
+) -> Vec<Fingerprint> {
+    let new_relays: Vec<Relay> = {
+        let old_relays: Vec<&Relay> = consensus.relays.values().collect();
+        if old_relays.is_empty() {
+            return Vec::new();
+        }
+        let mut customizer = Customizer::new(asn_db, &consensus.relays);
+        (0..num)
+            .map(|_| {
+                let chosen_relay = RelaySampler::unbiased(&old_relays).sample();
+                let mut new_relay = chosen_relay.clone();
+                new_relay.family = None;
+                new_relay.lineage = Some(Lineage {
+                    base_fingerprint: chosen_relay.fingerprint.clone(),
+                    family: FamilyDecision::NoFamily,
+                    same_as_family: None,
+                });
+                customizer.customize_relay(&mut new_relay);
+                new_relay
+            })
+            .collect()
+    };
+
+    let fingerprints = new_relays.iter().map(|r| r.fingerprint.clone()).collect();
+    for relay in new_relays {
+        consensus.relays.insert(relay.fingerprint.clone(), relay);
+    }
+    fingerprints
 }
 
 struct Customizer<'a> {
     fingerprint_generator: FingerprintGenerator,
     nickname_generator: NicknameGenerator,
     asn_db: &'a AsnDb,
+    /// Fingerprints that must not be assigned to new relays
+    taken_fingerprints: RHashSet<Fingerprint>,
 }
 
 impl<'a> Customizer<'a> {
-    fn new(asn_db: &'a AsnDb) -> Customizer<'a> {
+    fn new(asn_db: &'a AsnDb, relays: &RHashMap<Fingerprint, Relay>) -> Customizer<'a> {
         Customizer {
             fingerprint_generator: FingerprintGenerator::new(),
             nickname_generator: NicknameGenerator::new(),
             asn_db,
+            taken_fingerprints: relays.keys().cloned().collect(),
         }
     }
 
     fn customize_relay(&mut self, relay: &mut Relay) {
-        // customize the new relay
-        let fingerprint = Fingerprint::from_u8(self.fingerprint_generator.get_fingerprint());
-        //  loop {
-        //     let x = Fingerprint::from_u8(self.fingerprint_generator.get_fingerprint());
-        //     if consensus.relays.contains_key(&x) {
-        //         continue;
-        //     }
-        //     break x;
-        // };
+        // customize the new relay (ports, version, protocols and the
+        // published time are kept from the base relay)
+        let fingerprint = loop {
+            let x = Fingerprint::from_u8(self.fingerprint_generator.get_fingerprint());
+            if self.taken_fingerprints.insert(x.clone()) {
+                break x;
+            }
+        };
         relay.fingerprint = fingerprint;
         relay.nickname = self.nickname_generator.get_nickname();
         relay.address = match &relay.asn {
@@ -276,6 +406,62 @@ impl<'a> Customizer<'a> {
     }
 }
 
+/// Remove relays to scale the consensus down horizontally. The exit and guard
+/// factors keep their meaning from scaling up: a factor above 1 lets the
+/// share of exits or guards grow, so these relays are removed less often.
+fn shrink_horizontally(consensus: &mut Consensus, scale: f32, exit_factor: f32, guard_factor: f32) {
+    let mut rng = get_rng();
+
+    let num_relays_before = consensus.relays.len();
+    let num_relays_after = (num_relays_before as f32 * scale).round() as usize;
+    let num_removed_relays = num_relays_before - num_relays_after;
+
+    println!("Current relays: {:7}", num_relays_before);
+    println!("Scale:          {:7.3}", scale);
+    println!("Removed relays: {:7}", num_removed_relays);
+
+    // never remove directory authorities
+    let relays: Vec<&Relay> = consensus
+        .relays
+        .values()
+        .filter(|r| !r.has_flag(Flag::Authority))
+        .collect();
+    // Relays are removed with the inverse of the factors relative to the
+    // middle relays. A factor of 0 gives the relays such a large weight that
+    // they are removed first.
+    let removal_factor = |factor: f32| 1.0 / factor.max(f32::EPSILON);
+    let flag_weights = FlagWeights::from_flag_factors_by_number(
+        &relays,
+        1.0,
+        removal_factor(exit_factor),
+        removal_factor(guard_factor),
+    );
+
+    // weighted sampling without replacement (Efraimidis and Spirakis): each
+    // relay gets a random key depending on its weight, and the relays with
+    // the largest keys are removed
+    let mut keys: Vec<(f64, &Fingerprint)> = relays
+        .iter()
+        .map(|r| {
+            let weight = flag_weights.get_relay_weight(r) as f64;
+            let key = if weight > 0.0 {
+                rng.gen::<f64>().powf(1.0 / weight)
+            } else {
+                0.0
+            };
+            (key, &r.fingerprint)
+        })
+        .collect();
+    keys.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    let removed: RHashSet<Fingerprint> = keys
+        .into_iter()
+        .take(num_removed_relays)
+        .map(|(_, fp)| fp.clone())
+        .collect();
+
+    consensus.remove_relays_by(|r| removed.contains(&r.fingerprint));
+}
+
 struct RelaySampler<'r> {
     relays: &'r Vec<&'r Relay>,
     flag_weights: FlagWeights,
@@ -648,6 +834,149 @@ pub fn cutoff_lower_and_redistribute(consensus: &mut Consensus, cutoff: f32) {
     scale_vertically_by_bandwidth_rank(consensus, vec![grow_factor]);
 }
 
+/// Options for [downsample]
+#[derive(Debug, Clone)]
+pub struct DownsampleOptions {
+    /// Number of strata by bandwidth rank within each position class
+    pub bandwidth_strata: usize,
+    /// Trim every family to the given fraction of its members instead of
+    /// keeping or removing families as a whole
+    pub trim_families: bool,
+}
+
+impl Default for DownsampleOptions {
+    fn default() -> Self {
+        DownsampleOptions {
+            bandwidth_strata: 10,
+            trim_families: false,
+        }
+    }
+}
+
+/// Reduce the consensus to `fraction` of its (non-authority) relays by
+/// stratified sampling, preserving the shape of the bandwidth distribution,
+/// the shares of the position classes and the family sizes.
+///
+/// Relays are stratified by position class and bandwidth rank. Within each
+/// stratum, the sampling units are ordered by size and bandwidth and chosen
+/// by systematic sampling, so that every unit is kept with probability
+/// `fraction`. Families are either sampled as one unit (in the stratum of
+/// their member with the highest bandwidth), or trimmed to `fraction` of
+/// their members.
+///
+/// The bandwidth of the kept relays is not changed. Use [scale_capacity]
+/// afterwards to restore the total capacity.
+///
+/// Panics if the fraction is not in the range of (0.0 ; 1.0].
+pub fn downsample(consensus: &mut Consensus, fraction: f32, options: &DownsampleOptions) {
+    if fraction <= 0.0 || fraction > 1.0 {
+        panic!("downsampling fraction must be between 0.0 (exclusive) and 1.0");
+    }
+    let fraction = fraction as f64;
+    let mut rng = get_rng();
+
+    let num_relays_before = consensus.relays.len();
+
+    // stratum of each relay by its bandwidth rank
+    let mut relays: Vec<&Relay> = consensus
+        .relays
+        .values()
+        .filter(|r| !r.has_flag(Flag::Authority))
+        .collect();
+    relays.sort_by_key(|r| r.bandwidth_weight);
+    let num_strata = options.bandwidth_strata.max(1);
+    let bandwidth_stratum: RHashMap<&Fingerprint, usize> = relays
+        .iter()
+        .enumerate()
+        .map(|(rank, r)| (&r.fingerprint, rank * num_strata / relays.len()))
+        .collect();
+    let stratum_of = |relay: &Relay| -> (PositionClass, usize) {
+        (
+            relay.position_class(),
+            bandwidth_stratum[&relay.fingerprint],
+        )
+    };
+
+    // sampling units: whole families (if not trimmed) or single relays, by
+    // (is family, position class, bandwidth stratum)
+    let mut strata: BTreeMap<(bool, PositionClass, usize), Vec<Vec<&Relay>>> = BTreeMap::new();
+    let mut kept: RHashSet<Fingerprint> = RHashSet::default();
+    let mut in_family: RHashSet<&Fingerprint> = RHashSet::default();
+    for family in consensus.families.iter() {
+        let mut members: Vec<&Relay> = family
+            .members
+            .iter()
+            .filter_map(|fp| consensus.relays.get(fp))
+            .filter(|r| !r.has_flag(Flag::Authority))
+            .collect();
+        if members.len() < 2 {
+            continue;
+        }
+        members.sort_by_key(|r| r.bandwidth_weight);
+        in_family.extend(members.iter().map(|r| &r.fingerprint));
+
+        if options.trim_families {
+            for i in systematic_sample(members.len(), fraction, &mut rng) {
+                kept.insert(members[i].fingerprint.clone());
+            }
+        } else {
+            let (class, stratum) = stratum_of(members.last().unwrap());
+            strata
+                .entry((true, class, stratum))
+                .or_default()
+                .push(members);
+        }
+    }
+    for relay in relays.iter() {
+        if !in_family.contains(&relay.fingerprint) {
+            let (class, stratum) = stratum_of(relay);
+            strata
+                .entry((false, class, stratum))
+                .or_default()
+                .push(vec![relay]);
+        }
+    }
+
+    for units in strata.values_mut() {
+        units.sort_by_key(|unit| {
+            (
+                unit.len(),
+                unit.iter().map(|r| r.bandwidth_weight).sum::<u64>(),
+            )
+        });
+        for i in systematic_sample(units.len(), fraction, &mut rng) {
+            kept.extend(units[i].iter().map(|r| r.fingerprint.clone()));
+        }
+    }
+
+    consensus.remove_relays_by(|r| !r.has_flag(Flag::Authority) && !kept.contains(&r.fingerprint));
+
+    println!("Current relays: {:7}", num_relays_before);
+    println!("Fraction:       {:7.3}", fraction);
+    println!("Kept relays:    {:7}", consensus.relays.len());
+}
+
+/// Scale the bandwidth of all relays by the same factor so that their total
+/// is `total_bandwidth`. Does nothing if the consensus has no bandwidth.
+pub fn scale_capacity(consensus: &mut Consensus, total_bandwidth: u64) {
+    let current: u64 = consensus.relays.values().map(|r| r.bandwidth_weight).sum();
+    if current > 0 {
+        let scale = total_bandwidth as f32 / current as f32;
+        scale_vertically_by(consensus, |_| scale);
+    }
+}
+
+/// Indices of the units (out of `num_units`) chosen by systematic sampling
+/// with a random start, each with probability `fraction`
+fn systematic_sample<R: Rng>(num_units: usize, fraction: f64, rng: &mut R) -> Vec<usize> {
+    let start: f64 = rng.gen();
+    (0..num_units)
+        .filter(|&i| {
+            (start + (i + 1) as f64 * fraction).floor() > (start + i as f64 * fraction).floor()
+        })
+        .collect()
+}
+
 /// Scale a consensus given a function that defines each relay's scale factor
 fn scale_vertically_by<F: Fn(&Relay) -> f32>(consensus: &mut Consensus, relay_scale: F) {
     for relay in consensus.relays.values_mut() {
@@ -658,3 +987,109 @@ fn scale_vertically_by<F: Fn(&Relay) -> f32>(consensus: &mut Consensus, relay_sc
     consensus.recompute_bw_weights();
     consensus.recompute_stats();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    /// A consensus with 25 relays of each position class
+    fn consensus_with_classes() -> Consensus {
+        let relays = (1..=100u8)
+            .map(|id| {
+                let (flags, exit_policy) = match id % 4 {
+                    0 => ("Fast Running Valid", "reject 1-65535"),
+                    1 => ("Fast Guard Running Valid", "reject 1-65535"),
+                    2 => ("Exit Fast Running Valid", "accept 80,443"),
+                    _ => ("Exit Fast Guard Running Valid", "accept 80,443"),
+                };
+                Relay::for_test(id, &format!("10.{}.0.1", id), flags, 100, exit_policy)
+            })
+            .collect();
+        Consensus::for_test(relays, &[], None)
+    }
+
+    fn exit_share(consensus: &Consensus) -> f64 {
+        let exits = consensus
+            .relays
+            .values()
+            .filter(|r| r.has_flag(Flag::Exit))
+            .count();
+        exits as f64 / consensus.relays.len() as f64
+    }
+
+    #[test]
+    fn shrink_with_exit_factor() {
+        seeded_rand::set_seed(1);
+        let asn_db = AsnDb::from_reader("network,asn,name\n".as_bytes()).unwrap();
+
+        // exits grow faster than the other relays, so they are removed less
+        let mut consensus = consensus_with_classes();
+        scale_horizontally(&mut consensus, 0.5, Some(4.0), None, &asn_db, 0.5).unwrap();
+        assert_eq!(consensus.relays.len(), 50);
+        assert!(exit_share(&consensus) > 0.6, "{}", exit_share(&consensus));
+
+        let mut consensus = consensus_with_classes();
+        scale_horizontally(&mut consensus, 0.5, Some(0.25), None, &asn_db, 0.5).unwrap();
+        assert_eq!(consensus.relays.len(), 50);
+        assert!(exit_share(&consensus) < 0.5, "{}", exit_share(&consensus));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let asn_db = AsnDb::from_reader("network,asn,name\n".as_bytes()).unwrap();
+        let mut consensus = consensus_with_classes();
+        for (scale, exit_factor, prob_family_new) in [
+            (0.0, 1.0, 0.5),
+            (f32::NAN, 1.0, 0.5),
+            (2.0, -1.0, 0.5),
+            (2.0, 1.0, 1.5),
+        ] {
+            assert!(scale_horizontally(
+                &mut consensus,
+                scale,
+                Some(exit_factor),
+                None,
+                &asn_db,
+                prob_family_new
+            )
+            .is_err());
+        }
+        assert_eq!(consensus.relays.len(), 100);
+    }
+
+    #[test]
+    fn systematic_sample_count() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for (num_units, fraction) in [(10, 0.5), (7, 0.3), (100, 0.01), (3, 1.0)] {
+            let expected = num_units as f64 * fraction;
+            for _ in 0..100 {
+                let sample = systematic_sample(num_units, fraction, &mut rng);
+                assert!(sample.len() as f64 >= expected.floor());
+                assert!(sample.len() as f64 <= expected.ceil());
+                assert!(sample.windows(2).all(|x| x[0] < x[1]));
+                assert!(sample.iter().all(|&i| i < num_units));
+            }
+        }
+    }
+
+    #[test]
+    fn systematic_sample_inclusion_probability() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let num_units = 7;
+        let fraction = 0.3;
+        let trials = 10000;
+        let mut kept = vec![0usize; num_units];
+        for _ in 0..trials {
+            for i in systematic_sample(num_units, fraction, &mut rng) {
+                kept[i] += 1;
+            }
+        }
+        for count in kept {
+            let share = count as f64 / trials as f64;
+            assert!((share - fraction).abs() < 0.02, "{}", share);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Selection probabilities of relays for the positions of a circuit, following
+//! Tor's bandwidth-weighted path selection.
+
+use serde::Serialize;
+
+use super::{Consensus, Relay};
+
+use seeded_rand::RHashMap;
+use tordoc::{consensus::Flag, Fingerprint};
+
+/// The scale of the bandwidth weights given in the consensus
+pub const WEIGHT_SCALE: u64 = 10000;
+
+/// Classes of relays as distinguished by the bandwidth weights
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Serialize)]
+pub enum PositionClass {
+    /// Exit relays (without a guard flag)
+    Exit,
+    /// Guard relays (without a usable exit flag)
+    Guard,
+    /// Relays that are both guard and exit
+    GuardExit,
+    /// Relays that are neither guard nor exit
+    Middle,
+}
+
+impl PositionClass {
+    /// The class of a relay with the given flags. Exits with the BadExit flag
+    /// are not considered to be exits.
+    pub fn from_flags(flags: &[Flag]) -> PositionClass {
+        let is_exit = flags.contains(&Flag::Exit) && !flags.contains(&Flag::BadExit);
+        let is_guard = flags.contains(&Flag::Guard);
+        match (is_guard, is_exit) {
+            (true, true) => PositionClass::GuardExit,
+            (false, true) => PositionClass::Exit,
+            (true, false) => PositionClass::Guard,
+            (false, false) => PositionClass::Middle,
+        }
+    }
+}
+
+/// Probabilities of a relay to be selected for the positions of a circuit
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PositionProbabilities {
+    pub guard: f64,
+    pub middle: f64,
+    pub exit: f64,
+}
+
+/// The bandwidth weights that apply to the positions of a circuit, scaled to
+/// the range [0 ; 1]
+#[derive(Debug, Clone)]
+pub struct PositionWeights {
+    wgg: f64,
+    wgd: f64,
+    wmg: f64,
+    wmm: f64,
+    wme: f64,
+    wmd: f64,
+    wee: f64,
+    wed: f64,
+}
+
+impl PositionWeights {
+    /// Get the weights from the consensus. Missing weights default to the
+    /// full weight. For Wmm, this is always correct, since Tor never weights
+    /// pure middle relays down. For the other weights, a warning is printed.
+    pub fn from_consensus(consensus: &Consensus) -> PositionWeights {
+        let weight = |key: &str| -> f64 {
+            let value = match consensus.weights.get(key) {
+                Some(value) => *value,
+                None => {
+                    if key != "Wmm" {
+                        eprintln!(
+                            "[Warning] The consensus has no {} weight, using {}",
+                            key, WEIGHT_SCALE
+                        );
+                    }
+                    WEIGHT_SCALE
+                }
+            };
+            value as f64 / WEIGHT_SCALE as f64
+        };
+        PositionWeights {
+            wgg: weight("Wgg"),
+            wgd: weight("Wgd"),
+            wmg: weight("Wmg"),
+            wmm: weight("Wmm"),
+            wme: weight("Wme"),
+            wmd: weight("Wmd"),
+            wee: weight("Wee"),
+            wed: weight("Wed"),
+        }
+    }
+
+    /// Weighted bandwidth of the relay for the guard position. Relays without
+    /// the Guard flag are not considered.
+    pub fn guard_weight(&self, relay: &Relay) -> f64 {
+        let weight = match relay.position_class() {
+            PositionClass::GuardExit => self.wgd,
+            PositionClass::Guard => self.wgg,
+            PositionClass::Exit | PositionClass::Middle => 0.0,
+        };
+        relay.bandwidth_weight as f64 * weight
+    }
+
+    /// Weighted bandwidth of the relay for the middle position
+    pub fn middle_weight(&self, relay: &Relay) -> f64 {
+        let weight = match relay.position_class() {
+            PositionClass::GuardExit => self.wmd,
+            PositionClass::Guard => self.wmg,
+            PositionClass::Exit => self.wme,
+            PositionClass::Middle => self.wmm,
+        };
+        relay.bandwidth_weight as f64 * weight
+    }
+
+    /// Weighted bandwidth of the relay for the exit position. Relays without
+    /// the Exit flag or with the BadExit flag are not considered.
+    pub fn exit_weight(&self, relay: &Relay) -> f64 {
+        let weight = match relay.position_class() {
+            PositionClass::GuardExit => self.wed,
+            PositionClass::Exit => self.wee,
+            PositionClass::Guard | PositionClass::Middle => 0.0,
+        };
+        relay.bandwidth_weight as f64 * weight
+    }
+}
+
+/// Compute the probabilities of each relay to be chosen as guard, middle and
+/// exit.
+///
+/// The exit probabilities refer to a generic exit position and do not take
+/// into account the exit policies for a specific destination port.
+pub fn selection_probabilities(
+    consensus: &Consensus,
+) -> RHashMap<Fingerprint, PositionProbabilities> {
+    let weights = PositionWeights::from_consensus(consensus);
+
+    let weighted: Vec<(&Fingerprint, PositionProbabilities)> = consensus
+        .relays
+        .iter()
+        .map(|(fp, relay)| {
+            (
+                fp,
+                PositionProbabilities {
+                    guard: weights.guard_weight(relay),
+                    middle: weights.middle_weight(relay),
+                    exit: weights.exit_weight(relay),
+                },
+            )
+        })
+        .collect();
+
+    let total_guard: f64 = weighted.iter().map(|(_, p)| p.guard).sum();
+    let total_middle: f64 = weighted.iter().map(|(_, p)| p.middle).sum();
+    let total_exit: f64 = weighted.iter().map(|(_, p)| p.exit).sum();
+
+    let normalize = |x: f64, total: f64| if total > 0.0 { x / total } else { 0.0 };
+
+    weighted
+        .into_iter()
+        .map(|(fp, p)| {
+            (
+                fp.clone(),
+                PositionProbabilities {
+                    guard: normalize(p.guard, total_guard),
+                    middle: normalize(p.middle, total_middle),
+                    exit: normalize(p.exit, total_exit),
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fp(id: u8) -> Fingerprint {
+        Fingerprint::from_u8(&[id; 20])
+    }
+
+    /// A relay with the given flags and bandwidth, in a /16 of its own
+    fn relay(id: u8, flags: &str, bandwidth: u64) -> Relay {
+        let address = format!("10.{}.0.1", id);
+        Relay::for_test(id, &address, flags, bandwidth, "accept 80,443")
+    }
+
+    fn consensus() -> Consensus {
+        let mut consensus = Consensus::for_test(
+            vec![
+                relay(1, "Fast Guard Running Valid", 100),
+                relay(2, "Exit Fast Guard Running Valid", 200),
+                relay(3, "Exit Fast Running Valid", 100),
+                relay(4, "BadExit Exit Fast Running Valid", 300),
+                relay(5, "Fast Running Valid", 100),
+            ],
+            &[],
+            None,
+        );
+        consensus.weights = [
+            ("Wgg", 6000),
+            ("Wgd", 3000),
+            ("Wmg", 4000),
+            ("Wmm", 10000),
+            ("Wme", 2000),
+            ("Wmd", 4000),
+            ("Wee", 8000),
+            ("Wed", 3000),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        consensus
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn position_classes() {
+        let consensus = consensus();
+        let class = |id| consensus.relays[&fp(id)].position_class();
+        assert_eq!(class(1), PositionClass::Guard);
+        assert_eq!(class(2), PositionClass::GuardExit);
+        assert_eq!(class(3), PositionClass::Exit);
+        // BadExit relays are not used as exits
+        assert_eq!(class(4), PositionClass::Middle);
+        assert_eq!(class(5), PositionClass::Middle);
+    }
+
+    #[test]
+    fn probabilities() {
+        let probabilities = selection_probabilities(&consensus());
+        let p = |id| probabilities[&fp(id)];
+
+        // guard: 100 * 0.6 and 200 * 0.3
+        assert_close(p(1).guard, 0.5);
+        assert_close(p(2).guard, 0.5);
+        assert_close(p(3).guard, 0.0);
+
+        // middle: 40, 80, 20, 300, 100
+        assert_close(p(1).middle, 40.0 / 540.0);
+        assert_close(p(2).middle, 80.0 / 540.0);
+        assert_close(p(3).middle, 20.0 / 540.0);
+        assert_close(p(4).middle, 300.0 / 540.0);
+        assert_close(p(5).middle, 100.0 / 540.0);
+
+        // exit: 200 * 0.3 and 100 * 0.8, nothing for the BadExit relay
+        assert_close(p(2).exit, 60.0 / 140.0);
+        assert_close(p(3).exit, 80.0 / 140.0);
+        assert_close(p(4).exit, 0.0);
+        assert_close(p(1).exit, 0.0);
+
+        let positions: [fn(&PositionProbabilities) -> f64; 3] =
+            [|p| p.guard, |p| p.middle, |p| p.exit];
+        for position in positions {
+            assert_close(probabilities.values().map(position).sum(), 1.0);
+        }
+    }
+
+    #[test]
+    fn missing_wmm() {
+        let mut consensus = consensus();
+        consensus.weights.remove("Wmm");
+        let weights = PositionWeights::from_consensus(&consensus);
+        assert_eq!(weights.middle_weight(&consensus.relays[&fp(5)]), 100.0);
+    }
+}
@@ -0,0 +1,280 @@
+//! Generation of consecutive consensuses with relay churn, flag changes and
+//! bandwidth fluctuations.
+
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use chrono::Duration;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::asn::AsnDb;
+use super::paths::policy_summary_allows;
+use super::scale;
+use super::{Consensus, Relay};
+
+use seeded_rand::{get_rng, RHashSet};
+use tordoc::{consensus::Flag, Fingerprint};
+
+/// Flags that relays gain and lose over time
+pub fn dynamic_flags() -> [Flag; 5] {
+    [
+        Flag::Guard,
+        Flag::Stable,
+        Flag::Fast,
+        Flag::HSDir,
+        Flag::Exit,
+    ]
+}
+
+/// Ports of which an exit policy has to allow at least two for the relay to
+/// get the Exit flag (as in Tor's directory authorities)
+const EXIT_FLAG_PORTS: [u16; 3] = [80, 443, 6667];
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ChurnModelError {
+    #[error("{name} has to be a probability between 0 and 1, not {value}")]
+    InvalidProbability { name: String, value: f64 },
+    #[error("{name} has to be a finite number of at least 0, not {value}")]
+    InvalidRate { name: String, value: f64 },
+}
+
+/// Hourly rates describing the dynamics of relays
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChurnModel {
+    /// Share of relays joining the network per hour, relative to the current
+    /// number of relays
+    pub join_rate: f64,
+    /// Probability of a relay to leave the network per hour
+    pub leave_rate: f64,
+    /// Probability of a relay without the flag to gain it per hour, by flag
+    pub flag_gain_rates: BTreeMap<String, f64>,
+    /// Probability of a relay with the flag to lose it per hour, by flag
+    pub flag_loss_rates: BTreeMap<String, f64>,
+    /// Standard deviation of the hourly change of the logarithm of a relay's
+    /// bandwidth
+    pub bandwidth_noise: f64,
+}
+
+impl Default for ChurnModel {
+    /// Rough hourly rates as observed in the public Tor network. Use the
+    /// history subcommand to estimate them for a specific period.
+    fn default() -> Self {
+        let rates = |rates: &[(&str, f64)]| {
+            rates
+                .iter()
+                .map(|(flag, rate)| (flag.to_string(), *rate))
+                .collect()
+        };
+        ChurnModel {
+            join_rate: 0.005,
+            leave_rate: 0.005,
+            flag_gain_rates: rates(&[
+                ("Guard", 0.002),
+                ("Stable", 0.004),
+                ("Fast", 0.003),
+                ("HSDir", 0.004),
+                ("Exit", 0.0002),
+            ]),
+            flag_loss_rates: rates(&[
+                ("Guard", 0.002),
+                ("Stable", 0.002),
+                ("Fast", 0.002),
+                ("HSDir", 0.002),
+                ("Exit", 0.0005),
+            ]),
+            bandwidth_noise: 0.05,
+        }
+    }
+}
+
+impl ChurnModel {
+    /// Check that the rates are within their valid ranges: the join rate and
+    /// the bandwidth noise have to be non-negative, all other rates have to
+    /// be probabilities.
+    pub fn validate(&self) -> Result<(), ChurnModelError> {
+        let non_negative = |name: &str, value: f64| {
+            if value.is_finite() && value >= 0.0 {
+                Ok(())
+            } else {
+                Err(ChurnModelError::InvalidRate {
+                    name: name.to_string(),
+                    value,
+                })
+            }
+        };
+        let probability = |name: &str, value: f64| {
+            if (0.0..=1.0).contains(&value) {
+                Ok(())
+            } else {
+                Err(ChurnModelError::InvalidProbability {
+                    name: name.to_string(),
+                    value,
+                })
+            }
+        };
+
+        non_negative("join_rate", self.join_rate)?;
+        probability("leave_rate", self.leave_rate)?;
+        for (flag, rate) in self.flag_gain_rates.iter() {
+            probability(&format!("flag_gain_rates.{}", flag), *rate)?;
+        }
+        for (flag, rate) in self.flag_loss_rates.iter() {
+            probability(&format!("flag_loss_rates.{}", flag), *rate)?;
+        }
+        non_negative("bandwidth_noise", self.bandwidth_noise)?;
+        Ok(())
+    }
+
+    /// Turn an hourly probability into the probability for a step of the
+    /// given length
+    fn per_step(rate: f64, hours: f64) -> f64 {
+        (1.0 - (1.0 - rate.clamp(0.0, 1.0)).powf(hours)).clamp(0.0, 1.0)
+    }
+}
+
+/// Whether the exit policy of a relay qualifies it for the Exit flag
+fn may_be_exit(relay: &Relay) -> bool {
+    let summary = relay.exit_policy.to_string();
+    EXIT_FLAG_PORTS
+        .iter()
+        .filter(|port| policy_summary_allows(&summary, **port) == Some(true))
+        .count()
+        >= 2
+}
+
+/// Generates consecutive consensuses by repeatedly applying a churn model
+pub struct SeriesGenerator<'a> {
+    consensus: Consensus,
+    model: ChurnModel,
+    interval: Duration,
+    asn_db: &'a AsnDb,
+}
+
+impl<'a> SeriesGenerator<'a> {
+    /// Start a series with the given consensus as the first one. Fails if
+    /// the churn model has rates outside their valid ranges.
+    pub fn new(
+        base: Consensus,
+        model: ChurnModel,
+        interval: Duration,
+        asn_db: &'a AsnDb,
+    ) -> Result<SeriesGenerator<'a>, ChurnModelError> {
+        model.validate()?;
+        Ok(SeriesGenerator {
+            consensus: base,
+            model,
+            interval,
+            asn_db,
+        })
+    }
+
+    /// The current consensus of the series
+    pub fn current(&self) -> &Consensus {
+        &self.consensus
+    }
+
+    /// Advance the series by one interval and return the new consensus
+    pub fn step(&mut self) -> &Consensus {
+        let mut rng = get_rng();
+        let hours = self.interval.num_seconds() as f64 / 3600.0;
+
+        self.consensus.valid_after = self.consensus.valid_after + self.interval;
+        let valid_after = self.consensus.valid_after;
+
+        // relays leaving
+        let leave_prob = ChurnModel::per_step(self.model.leave_rate, hours);
+        let num_before = self.consensus.relays.len();
+        self.consensus
+            .remove_relays_by(|_| rng.gen_bool(leave_prob));
+
+        // relays joining
+        let expected_joins = self.model.join_rate * hours * num_before as f64;
+        let num_joins =
+            expected_joins.floor() as usize + rng.gen_bool(expected_joins.fract()) as usize;
+        let joined: RHashSet<Fingerprint> =
+            scale::add_cloned_relays(&mut self.consensus, num_joins, self.asn_db)
+                .into_iter()
+                .collect();
+
+        let noise = self.model.bandwidth_noise;
+        for (fp, relay) in self.consensus.relays.iter_mut() {
+            // flag changes
+            for flag in dynamic_flags() {
+                let name: &'static str = (&flag).into();
+                if relay.has_flag(flag.clone()) {
+                    let rate = self.model.flag_loss_rates.get(name).copied().unwrap_or(0.0);
+                    if rng.gen_bool(ChurnModel::per_step(rate, hours)) {
+                        relay.flags.retain(|f| *f != flag);
+                    }
+                } else if flag != Flag::Exit || may_be_exit(relay) {
+                    let rate = self.model.flag_gain_rates.get(name).copied().unwrap_or(0.0);
+                    if rng.gen_bool(ChurnModel::per_step(rate, hours)) {
+                        relay.flags.push(flag);
+                    }
+                }
+            }
+
+            // bandwidth fluctuations (log-normal, using the Box-Muller
+            // transform)
+            if noise > 0.0 && relay.bandwidth_weight > 0 {
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                let factor = (noise * hours.sqrt() * z).exp();
+                relay.bandwidth_weight =
+                    ((relay.bandwidth_weight as f64 * factor).round() as u64).max(1);
+            }
+
+            // relays publish new descriptors regularly, and new relays just
+            // published their first one
+            if joined.contains(fp) || valid_after - relay.published > Duration::hours(18) {
+                relay.published = valid_after - Duration::minutes(rng.gen_range(1..60));
+            }
+        }
+
+        self.consensus.recompute_bw_weights();
+        self.consensus.recompute_stats();
+        &self.consensus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rates() {
+        assert_eq!(ChurnModel::default().validate(), Ok(()));
+
+        let model = ChurnModel {
+            join_rate: -0.1,
+            ..ChurnModel::default()
+        };
+        assert!(matches!(
+            model.validate(),
+            Err(ChurnModelError::InvalidRate { .. })
+        ));
+
+        let model = ChurnModel {
+            leave_rate: 1.5,
+            ..ChurnModel::default()
+        };
+        assert!(matches!(
+            model.validate(),
+            Err(ChurnModelError::InvalidProbability { .. })
+        ));
+
+        let mut model = ChurnModel::default();
+        model.flag_loss_rates.insert("Guard".to_string(), f64::NAN);
+        assert!(model.validate().is_err());
+
+        // join rates above 1 are fine, the network may more than double
+        let model = ChurnModel {
+            join_rate: 2.0,
+            ..ChurnModel::default()
+        };
+        assert_eq!(model.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,266 @@
+//! Descriptive statistics used when analyzing consensuses and series of them.
+
+/// Arithmetic mean of the values, or `None` if there are none
+pub fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+/// Quantile `q` (in [0, 1]) of ascendingly sorted values, interpolating
+/// linearly between the closest ranks. Returns `None` if there are no values.
+pub fn quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let pos = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lower = pos.floor() as usize;
+    let upper = pos.ceil() as usize;
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (pos - lower as f64))
+}
+
+/// Gini coefficient of non-negative values: 0 if all values are equal,
+/// approaching 1 if a single value makes up the total. Returns `None` if there
+/// are no values or they sum up to zero.
+pub fn gini(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let total: f64 = sorted.iter().sum();
+    if sorted.is_empty() || total <= 0.0 {
+        return None;
+    }
+    let n = sorted.len() as f64;
+    let weighted: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (i + 1) as f64 * x)
+        .sum();
+    Some(2.0 * weighted / (n * total) - (n + 1.0) / n)
+}
+
+/// Share of the total held by the `k` largest values. Returns `None` if the
+/// values sum up to zero.
+pub fn top_share(values: &[f64], k: usize) -> Option<f64> {
+    let total: f64 = values.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    Some(sorted.iter().take(k).sum::<f64>() / total)
+}
+
+/// Shannon entropy (in bits) of the distribution given by non-negative
+/// weights. Returns `None` if the weights sum up to zero.
+pub fn entropy(weights: &[f64]) -> Option<f64> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    Some(
+        -weights
+            .iter()
+            .filter(|w| **w > 0.0)
+            .map(|w| {
+                let p = w / total;
+                p * p.log2()
+            })
+            .sum::<f64>(),
+    )
+}
+
+/// Entropy of the weights relative to the maximum entropy of a uniform
+/// distribution over the non-zero weights: 1 if they are all equal, and
+/// lower the more the weight is concentrated. Returns `None` if there are
+/// less than two non-zero weights.
+pub fn normalized_entropy(weights: &[f64]) -> Option<f64> {
+    let nonzero = weights.iter().filter(|w| **w > 0.0).count();
+    if nonzero < 2 {
+        return None;
+    }
+    Some(entropy(weights)? / (nonzero as f64).log2())
+}
+
+/// Smallest number of the largest values that together make up at least
+/// `share` of the total. Returns `None` if the values sum up to zero.
+pub fn count_to_reach(values: &[f64], share: f64) -> Option<usize> {
+    let total: f64 = values.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    let mut sum = 0.0;
+    for (i, x) in sorted.iter().enumerate() {
+        sum += x;
+        if sum >= share * total {
+            return Some(i + 1);
+        }
+    }
+    Some(sorted.len())
+}
+
+/// Wilson score interval for a binomial proportion with `successes` out of
+/// `trials`, for the standard normal quantile `z` (e.g. 1.96 for 95%).
+/// Returns `None` if there are no trials.
+pub fn wilson_interval(successes: usize, trials: usize, z: f64) -> Option<(f64, f64)> {
+    if trials == 0 {
+        return None;
+    }
+    let n = trials as f64;
+    let p = successes as f64 / n;
+    let denominator = 1.0 + z * z / n;
+    let center = (p + z * z / (2.0 * n)) / denominator;
+    let half_width = z / denominator * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+    Some((
+        (center - half_width).max(0.0),
+        (center + half_width).min(1.0),
+    ))
+}
+
+/// Walk through the union of two ascendingly sorted samples, calling `f`
+/// with each distinct value, the next distinct value (if any) and the
+/// empirical CDFs of both samples at the value.
+fn sweep_cdfs(a: &[f64], b: &[f64], mut f: impl FnMut(f64, Option<f64>, f64, f64)) {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let x = match (a.get(i), b.get(j)) {
+            (Some(&x), Some(&y)) => x.min(y),
+            (Some(&x), None) | (None, Some(&x)) => x,
+            (None, None) => unreachable!(),
+        };
+        while i < a.len() && a[i] <= x {
+            i += 1;
+        }
+        while j < b.len() && b[j] <= x {
+            j += 1;
+        }
+        let next = match (a.get(i), b.get(j)) {
+            (Some(&x), Some(&y)) => Some(x.min(y)),
+            (Some(&x), None) | (None, Some(&x)) => Some(x),
+            (None, None) => None,
+        };
+        f(
+            x,
+            next,
+            i as f64 / a.len() as f64,
+            j as f64 / b.len() as f64,
+        );
+    }
+}
+
+/// Two-sample Kolmogorov-Smirnov statistic (the largest difference of the
+/// empirical CDFs) of two ascendingly sorted samples. Returns `None` if one
+/// of them is empty.
+pub fn ks_statistic(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let mut max: f64 = 0.0;
+    sweep_cdfs(a, b, |_, _, cdf_a, cdf_b| {
+        max = max.max((cdf_a - cdf_b).abs())
+    });
+    Some(max)
+}
+
+/// Earth mover's distance (1-Wasserstein distance) between the empirical
+/// distributions of two ascendingly sorted samples. Returns `None` if one of
+/// them is empty.
+pub fn earth_movers_distance(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let mut distance = 0.0;
+    sweep_cdfs(a, b, |x, next, cdf_a, cdf_b| {
+        if let Some(next) = next {
+            distance += (cdf_a - cdf_b).abs() * (next - x);
+        }
+    });
+    Some(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_interpolate() {
+        let values = [1.0, 2.0, 4.0, 8.0];
+        assert_eq!(quantile(&values, 0.0), Some(1.0));
+        assert_eq!(quantile(&values, 1.0), Some(8.0));
+        assert_eq!(quantile(&values, 0.5), Some(3.0));
+        assert_eq!(quantile(&[], 0.5), None);
+        assert_eq!(mean(&values), Some(3.75));
+    }
+
+    #[test]
+    fn gini_extremes() {
+        assert_eq!(gini(&[5.0, 5.0, 5.0, 5.0]), Some(0.0));
+        assert_eq!(gini(&[0.0, 0.0, 0.0, 4.0]), Some(0.75));
+        assert_eq!(gini(&[0.0, 0.0]), None);
+        assert_eq!(gini(&[]), None);
+    }
+
+    #[test]
+    fn top_shares() {
+        let values = [1.0, 5.0, 2.0, 2.0];
+        assert_eq!(top_share(&values, 1), Some(0.5));
+        assert_eq!(top_share(&values, 2), Some(0.7));
+        assert_eq!(top_share(&values, 10), Some(1.0));
+        assert_eq!(top_share(&[0.0], 1), None);
+    }
+
+    #[test]
+    fn entropies() {
+        assert_eq!(entropy(&[1.0, 1.0, 1.0, 1.0]), Some(2.0));
+        assert_eq!(entropy(&[0.0, 3.0]), Some(0.0));
+        assert_eq!(entropy(&[0.0]), None);
+        assert_eq!(normalized_entropy(&[2.0, 2.0, 0.0]), Some(1.0));
+        let skewed = normalized_entropy(&[1.0, 9.0]).unwrap();
+        assert!(skewed > 0.0 && skewed < 1.0);
+        assert_eq!(normalized_entropy(&[0.0, 3.0]), None);
+    }
+
+    #[test]
+    fn counts_to_reach_share() {
+        let values = [1.0, 5.0, 2.0, 2.0];
+        assert_eq!(count_to_reach(&values, 0.5), Some(1));
+        assert_eq!(count_to_reach(&values, 0.6), Some(2));
+        assert_eq!(count_to_reach(&values, 1.0), Some(4));
+        assert_eq!(count_to_reach(&[0.0, 0.0], 0.5), None);
+    }
+
+    #[test]
+    fn distribution_distances() {
+        let a = [1.0, 2.0, 3.0];
+        assert_eq!(ks_statistic(&a, &a), Some(0.0));
+        assert_eq!(earth_movers_distance(&a, &a), Some(0.0));
+
+        // shifting a sample moves all of its mass
+        let shifted = [3.0, 4.0, 5.0];
+        assert_eq!(earth_movers_distance(&a, &shifted), Some(2.0));
+        assert!((ks_statistic(&a, &shifted).unwrap() - 2.0 / 3.0).abs() < 1e-12);
+
+        // different sample sizes
+        assert_eq!(
+            earth_movers_distance(&[0.0, 0.0], &[0.0, 0.0, 3.0]),
+            Some(1.0)
+        );
+        assert_eq!(ks_statistic(&[1.0], &[]), None);
+    }
+
+    #[test]
+    fn wilson_interval_contains_estimate() {
+        let (lower, upper) = wilson_interval(30, 100, 1.96).unwrap();
+        assert!((lower - 0.2189).abs() < 1e-4);
+        assert!((upper - 0.3958).abs() < 1e-4);
+
+        // the interval stays within [0, 1] for extreme proportions
+        let (lower, upper) = wilson_interval(0, 10, 1.96).unwrap();
+        assert_eq!(lower, 0.0);
+        assert!(upper > 0.0 && upper < 1.0);
+        assert_eq!(wilson_interval(0, 0, 1.96), None);
+    }
+}